@@ -0,0 +1,12 @@
+-- Add migration script here
+-- Deliveries that ran out of attempts (or failed permanently) end up here
+-- until an admin requeues them.
+CREATE TABLE issue_delivery_dead_letters (
+	newsletter_issue_id uuid NOT NULL
+		REFERENCES newsletter_issues (newsletter_issue_id),
+	subscriber_email TEXT NOT NULL,
+	n_attempts SMALLINT NOT NULL,
+	last_error TEXT NOT NULL,
+	failed_at timestamptz NOT NULL,
+	PRIMARY KEY(newsletter_issue_id, subscriber_email)
+);
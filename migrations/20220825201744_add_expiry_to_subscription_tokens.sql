@@ -0,0 +1,8 @@
+-- Add migration script here
+-- Tokens issued before this migration get a fresh validity window.
+ALTER TABLE subscription_tokens
+	ADD COLUMN issued_at timestamptz NOT NULL DEFAULT now(),
+	ADD COLUMN expires_at timestamptz NOT NULL DEFAULT now() + interval '48 hours';
+ALTER TABLE subscription_tokens
+	ALTER COLUMN issued_at DROP DEFAULT,
+	ALTER COLUMN expires_at DROP DEFAULT;
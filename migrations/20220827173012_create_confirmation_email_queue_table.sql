@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE confirmation_email_queue (
+	subscription_token TEXT NOT NULL
+		REFERENCES subscription_tokens (subscription_token) ON DELETE CASCADE,
+	enqueued_at timestamptz NOT NULL,
+	PRIMARY KEY(subscription_token)
+);
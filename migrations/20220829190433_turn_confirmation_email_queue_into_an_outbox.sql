@@ -0,0 +1,6 @@
+-- Add migration script here
+ALTER TABLE confirmation_email_queue RENAME TO confirmation_email_outbox;
+ALTER TABLE confirmation_email_outbox
+	ADD COLUMN n_retries SMALLINT NOT NULL DEFAULT 0,
+	ADD COLUMN execute_after timestamptz NOT NULL DEFAULT now(),
+	ADD COLUMN last_error TEXT NULL;
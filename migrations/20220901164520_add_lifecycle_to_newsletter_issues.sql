@@ -0,0 +1,12 @@
+-- Add migration script here
+ALTER TABLE newsletter_issues
+	ADD COLUMN status TEXT NOT NULL DEFAULT 'sending'
+		CHECK (status IN ('draft', 'scheduled', 'sending', 'sent')),
+	ADD COLUMN scheduled_for timestamptz NULL,
+	ADD COLUMN updated_at timestamptz NOT NULL DEFAULT now(),
+	ALTER COLUMN published_at DROP NOT NULL;
+-- Issues published so far are marked as sent by the scheduler once their
+-- deliveries are done.
+ALTER TABLE newsletter_issues
+	ALTER COLUMN status DROP DEFAULT,
+	ALTER COLUMN updated_at DROP DEFAULT;
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- Where test copies of newsletter issues go by default
+ALTER TABLE users ADD COLUMN email TEXT NULL;
@@ -0,0 +1,23 @@
+-- Add migration script here
+CREATE TABLE lists(
+	list_id uuid NOT NULL,
+	name TEXT NOT NULL UNIQUE,
+	created_at timestamptz NOT NULL,
+	PRIMARY KEY (list_id)
+);
+CREATE TABLE subscription_lists(
+	subscriber_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+	list_id uuid NOT NULL REFERENCES lists (list_id) ON DELETE CASCADE,
+	PRIMARY KEY (subscriber_id, list_id)
+);
+CREATE TABLE subscriber_tags(
+	subscriber_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+	tag TEXT NOT NULL,
+	PRIMARY KEY (subscriber_id, tag)
+);
+-- Who an issue goes to: empty `include_*` arrays mean every confirmed subscriber.
+ALTER TABLE newsletter_issues
+	ADD COLUMN include_lists TEXT[] NOT NULL DEFAULT '{}',
+	ADD COLUMN include_tags TEXT[] NOT NULL DEFAULT '{}',
+	ADD COLUMN exclude_lists TEXT[] NOT NULL DEFAULT '{}',
+	ADD COLUMN exclude_tags TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,48 @@
+-- Add migration script here
+ALTER TABLE subscriptions
+	ADD COLUMN digest_frequency TEXT NOT NULL DEFAULT 'every_issue'
+		CHECK (digest_frequency IN ('every_issue', 'weekly', 'monthly')),
+	-- Digests cover the issues published since then. NULL for `every_issue`.
+	ADD COLUMN last_digest_at timestamptz NULL;
+-- A pending change of address, applied once the new address is confirmed with
+-- the subscription token sent to it.
+CREATE TABLE email_change_requests(
+	subscription_token TEXT NOT NULL
+		REFERENCES subscription_tokens (subscription_token) ON DELETE CASCADE,
+	new_email TEXT NOT NULL,
+	PRIMARY KEY (subscription_token)
+);
+-- Whether a subscriber is part of the audience of an issue: shared by the delivery
+-- of single issues and of digests.
+CREATE FUNCTION in_audience(
+	subscriber_id uuid,
+	include_lists TEXT[],
+	include_tags TEXT[],
+	exclude_lists TEXT[],
+	exclude_tags TEXT[]
+) RETURNS boolean LANGUAGE sql STABLE AS $$
+	SELECT
+		(
+			(cardinality(include_lists) = 0 AND cardinality(include_tags) = 0) OR
+			EXISTS (
+				SELECT 1 FROM subscription_lists sl JOIN lists l USING (list_id)
+				WHERE sl.subscriber_id = in_audience.subscriber_id
+					AND l.name = ANY(include_lists)
+			) OR
+			EXISTS (
+				SELECT 1 FROM subscriber_tags t
+				WHERE t.subscriber_id = in_audience.subscriber_id
+					AND t.tag = ANY(include_tags)
+			)
+		) AND
+		NOT EXISTS (
+			SELECT 1 FROM subscription_lists sl JOIN lists l USING (list_id)
+			WHERE sl.subscriber_id = in_audience.subscriber_id
+				AND l.name = ANY(exclude_lists)
+		) AND
+		NOT EXISTS (
+			SELECT 1 FROM subscriber_tags t
+			WHERE t.subscriber_id = in_audience.subscriber_id
+				AND t.tag = ANY(exclude_tags)
+		)
+$$;
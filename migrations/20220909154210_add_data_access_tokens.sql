@@ -0,0 +1,14 @@
+-- Add migration script here
+-- Erasing a subscriber takes their tokens with them.
+ALTER TABLE subscription_tokens
+	DROP CONSTRAINT subscription_tokens_subscriber_id_fkey,
+	ADD CONSTRAINT subscription_tokens_subscriber_id_fkey
+		FOREIGN KEY (subscriber_id) REFERENCES subscriptions (id) ON DELETE CASCADE;
+-- Mailed to a subscriber who asks for their data: following the link proves they
+-- own the address.
+CREATE TABLE data_access_tokens(
+	data_access_token TEXT NOT NULL,
+	subscriber_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+	expires_at timestamptz NOT NULL,
+	PRIMARY KEY (data_access_token)
+);
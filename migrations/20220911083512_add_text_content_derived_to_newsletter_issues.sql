@@ -0,0 +1,5 @@
+-- Add migration script here
+-- Whether the plain-text body was generated from the HTML one, in which case the
+-- draft form leaves it empty so that it is generated again on every save.
+ALTER TABLE newsletter_issues
+	ADD COLUMN text_content_derived BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- A digest that failed with a transient error is tried again after a backoff, like
+-- the deliveries of single issues.
+ALTER TABLE subscriptions
+	ADD COLUMN digest_n_retries SMALLINT NOT NULL DEFAULT 0,
+	ADD COLUMN digest_execute_after timestamptz NULL;
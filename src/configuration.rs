@@ -1,4 +1,5 @@
 use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, FileOutbox, PostmarkClient, SmtpClient};
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
@@ -11,6 +12,9 @@ pub struct Settings {
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
     pub redis_uri: Secret<String>,
+    pub issue_delivery: IssueDeliverySettings,
+    pub confirmation_outbox: ConfirmationOutboxSettings,
+    pub subscriptions: SubscriptionSettings,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -57,23 +61,200 @@ impl DatabaseSettings {
 
 #[derive(serde::Deserialize, Clone)]
 pub struct EmailClientSettings {
+    pub provider: EmailProviderKind,
     pub base_url: String,
     pub sender_email: String,
+    /// Mailbox for `mailto:` unsubscribe requests. Defaults to `sender_email`.
+    pub unsubscribe_email: Option<String>,
     pub authorization_token: Secret<String>,
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub timeout_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub messages_per_second: u32,
+    /// How many calls to the provider may be in flight at once.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_in_flight: usize,
+    pub circuit_breaker: CircuitBreakerSettings,
+    /// Required by the `smtp` provider.
+    pub smtp: Option<SmtpSettings>,
+    /// Required by the `file_outbox` provider.
+    pub outbox_directory: Option<String>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailProviderKind {
+    Postmark,
+    Smtp,
+    FileOutbox,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct CircuitBreakerSettings {
+    /// Consecutive failed calls to the provider before the circuit opens.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a probe call is let through.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub cooldown_milliseconds: u64,
+}
+
+impl CircuitBreakerSettings {
+    pub fn cooldown(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.cooldown_milliseconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    pub require_tls: bool,
 }
 
 impl EmailClientSettings {
+    pub fn client(self) -> EmailClient {
+        let sender_email = self.sender().expect("Invalid sender email address");
+        let unsubscribe_mailbox = self
+            .unsubscribe_mailbox()
+            .expect("Invalid unsubscribe email address");
+        let timeout = self.timeout();
+        let client = match self.provider {
+            EmailProviderKind::Postmark => EmailClient::new(
+                sender_email,
+                PostmarkClient::new(
+                    self.base_url,
+                    self.authorization_token.expose_secret().to_string(),
+                    timeout,
+                ),
+            ),
+            EmailProviderKind::Smtp => {
+                let smtp = self
+                    .smtp
+                    .expect("The smtp provider requires `email_client.smtp`");
+                let credentials = smtp
+                    .username
+                    .zip(smtp.password)
+                    .map(|(username, password)| (username, password.expose_secret().clone()));
+                let provider = SmtpClient::new(
+                    &smtp.host,
+                    smtp.port,
+                    credentials,
+                    smtp.require_tls,
+                    timeout,
+                )
+                .expect("Failed to build the SMTP transport");
+                EmailClient::new(sender_email, provider)
+            }
+            EmailProviderKind::FileOutbox => {
+                let directory = self
+                    .outbox_directory
+                    .expect("The file_outbox provider requires `email_client.outbox_directory`");
+                let provider =
+                    FileOutbox::new(directory).expect("Failed to create the outbox directory");
+                EmailClient::new(sender_email, provider)
+            }
+        };
+        let client = match unsubscribe_mailbox {
+            Some(mailbox) => client.with_unsubscribe_mailbox(mailbox),
+            None => client,
+        };
+        client
+            .with_rate_limit(self.messages_per_second, self.max_in_flight)
+            .with_circuit_breaker(
+                self.circuit_breaker.failure_threshold,
+                self.circuit_breaker.cooldown(),
+            )
+    }
+
     pub fn sender(&self) -> Result<SubscriberEmail, String> {
         SubscriberEmail::parse(self.sender_email.clone())
     }
 
+    pub fn unsubscribe_mailbox(&self) -> Result<Option<SubscriberEmail>, String> {
+        self.unsubscribe_email
+            .clone()
+            .map(SubscriberEmail::parse)
+            .transpose()
+    }
+
     pub fn timeout(&self) -> std::time::Duration {
         std::time::Duration::from_millis(self.timeout_milliseconds)
     }
 }
 
+#[derive(serde::Deserialize, Clone)]
+pub struct IssueDeliverySettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: i16,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub initial_backoff_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_backoff_milliseconds: u64,
+    /// How many queued deliveries a worker picks up and sends in one go.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub batch_size: i64,
+}
+
+impl IssueDeliverySettings {
+    pub fn initial_backoff(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.initial_backoff_milliseconds)
+    }
+
+    pub fn max_backoff(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.max_backoff_milliseconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ConfirmationOutboxSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: i16,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub initial_backoff_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_backoff_milliseconds: u64,
+}
+
+impl ConfirmationOutboxSettings {
+    pub fn initial_backoff(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.initial_backoff_milliseconds)
+    }
+
+    pub fn max_backoff(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.max_backoff_milliseconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SubscriptionSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub confirmation_token_ttl_hours: u64,
+    /// How long stale tokens and never-confirmed subscribers are kept around
+    /// before the cleanup task purges them.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retention_hours: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub cleanup_interval_seconds: u64,
+}
+
+impl SubscriptionSettings {
+    pub fn confirmation_token_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.confirmation_token_ttl_hours as i64)
+    }
+
+    pub fn retention(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.retention_hours as i64)
+    }
+
+    pub fn cleanup_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.cleanup_interval_seconds)
+    }
+}
+
 pub fn get_configuration() -> Result<Settings, config::ConfigError> {
     let base_path = std::env::current_dir().expect("Failed to determine the current directory");
     let configuration_directory = base_path.join("configurations");
@@ -0,0 +1,350 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::configuration::{ConfirmationOutboxSettings, Settings};
+use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
+use crate::email_client::{EmailClient, EmailError};
+use crate::issue_delivery_worker::{backoff, ExecutionOutcome};
+use crate::routes::{send_confirmation_email, send_email_change_confirmation};
+use crate::startup::Application;
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+/// Record that a confirmation email has to go out for `subscription_token`: to a
+/// new subscriber, or to the new address of a subscriber changing it.
+///
+/// Meant to be called in the same transaction that stores the token: either both
+/// are committed, or neither is.
+#[tracing::instrument(skip_all)]
+pub async fn enqueue_confirmation_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscription_token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO confirmation_email_outbox (subscription_token, enqueued_at)
+        VALUES ($1, now())
+        ON CONFLICT DO NOTHING
+        "#,
+        subscription_token,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Send the oldest due confirmation email in the outbox.
+///
+/// A transient failure is retried with exponential backoff, while an open circuit
+/// or a rate limit only pushes the email back without counting an attempt. The
+/// email is dropped if the provider rejects it outright or once it runs out of
+/// attempts: the subscriber can still ask for a new link from the confirmation page,
+/// or ask for the change of address again.
+#[tracing::instrument(skip_all, err)]
+pub async fn try_dispatch_confirmation_email(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+    settings: &ConfirmationOutboxSettings,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    dispatch(pool, email_client, base_url, settings, None).await
+}
+
+/// Send the confirmation email for `subscription_token` straight away, if it is
+/// still in the outbox and no dispatcher is working on it already.
+#[tracing::instrument(skip_all, err)]
+pub async fn dispatch_confirmation_email_now(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+    settings: &ConfirmationOutboxSettings,
+    subscription_token: &str,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    dispatch(
+        pool,
+        email_client,
+        base_url,
+        settings,
+        Some(subscription_token),
+    )
+    .await
+}
+
+struct OutboxEntry {
+    subscription_token: String,
+    n_retries: i16,
+    email: String,
+    name: String,
+    is_email_change: bool,
+}
+
+async fn dispatch(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+    settings: &ConfirmationOutboxSettings,
+    subscription_token: Option<&str>,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    let entry = match dequeue_entry(&mut transaction, subscription_token).await? {
+        Some(entry) => entry,
+        None => {
+            delete_stale_entries(&mut transaction).await?;
+            transaction.commit().await?;
+            return Ok(ExecutionOutcome::EmptyQueue);
+        }
+    };
+    let outcome = match (
+        SubscriberEmail::parse(entry.email.clone()),
+        SubscriberName::parse(entry.name.clone()),
+    ) {
+        (Ok(email), _) if entry.is_email_change => {
+            send_email_change_confirmation(
+                email_client,
+                &email,
+                base_url,
+                &entry.subscription_token,
+            )
+            .await
+        }
+        (Ok(email), Ok(name)) => {
+            send_confirmation_email(
+                email_client,
+                NewSubscriber { email, name },
+                base_url,
+                &entry.subscription_token,
+            )
+            .await
+        }
+        (Err(e), _) | (_, Err(e)) => Err(EmailError::Permanent(anyhow::anyhow!(e))),
+    };
+    match outcome {
+        Ok(()) => delete_entry(&mut transaction, &entry.subscription_token).await?,
+        Err(e) => record_failure(&mut transaction, &entry, e, settings).await?,
+    }
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+// `SKIP LOCKED` lets several dispatchers drain the outbox concurrently: entries
+// locked by another dispatcher are skipped instead of being sent twice.
+#[tracing::instrument(skip_all)]
+async fn dequeue_entry(
+    transaction: &mut PgTransaction,
+    subscription_token: Option<&str>,
+) -> Result<Option<OutboxEntry>, anyhow::Error> {
+    let entry = sqlx::query_as!(
+        OutboxEntry,
+        r#"
+        SELECT
+            o.subscription_token,
+            o.n_retries,
+            COALESCE(r.new_email, s.email) as "email!",
+            s.name,
+            r.new_email IS NOT NULL as "is_email_change!"
+        FROM confirmation_email_outbox o
+        JOIN subscription_tokens t ON t.subscription_token = o.subscription_token
+        JOIN subscriptions s ON s.id = t.subscriber_id
+        LEFT JOIN email_change_requests r ON r.subscription_token = o.subscription_token
+        WHERE
+            s.status = CASE
+                WHEN r.new_email IS NULL THEN 'pending_confirmation'
+                ELSE 'confirmed'
+            END AND
+            t.expires_at > now() AND
+            o.execute_after <= now() AND
+            ($1::text IS NULL OR o.subscription_token = $1)
+        ORDER BY o.execute_after
+        FOR UPDATE OF o
+        SKIP LOCKED
+        LIMIT 1
+        "#,
+        subscription_token,
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(entry)
+}
+
+async fn record_failure(
+    transaction: &mut PgTransaction,
+    entry: &OutboxEntry,
+    error: EmailError,
+    settings: &ConfirmationOutboxSettings,
+) -> Result<(), anyhow::Error> {
+    let n_attempts = entry.n_retries + 1;
+    if let Some(retry_after) = error.retry_after() {
+        tracing::warn!(
+            error.cause_chain = ?error,
+            error.message = %error,
+            retry_in_ms = retry_after.as_millis() as u64,
+            "Could not send a confirmation email for now. \
+            Retrying later without counting an attempt.",
+        );
+        return defer_entry(transaction, &entry.subscription_token, &error, retry_after).await;
+    }
+    match error {
+        EmailError::Permanent(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                n_attempts,
+                "The email provider rejected a confirmation email. Dropping it.",
+            );
+            delete_entry(transaction, &entry.subscription_token).await
+        }
+        e if n_attempts >= settings.max_attempts => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                n_attempts,
+                "Failed to send a confirmation email too many times. Dropping it.",
+            );
+            delete_entry(transaction, &entry.subscription_token).await
+        }
+        e => {
+            let delay = backoff(
+                entry.n_retries,
+                settings.initial_backoff(),
+                settings.max_backoff(),
+            );
+            tracing::warn!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                n_attempts,
+                retry_in_ms = delay.as_millis() as u64,
+                "Failed to send a confirmation email. Retrying later.",
+            );
+            schedule_retry(transaction, &entry.subscription_token, &e, delay).await
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn schedule_retry(
+    transaction: &mut PgTransaction,
+    subscription_token: &str,
+    error: &EmailError,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE confirmation_email_outbox
+        SET
+            n_retries = n_retries + 1,
+            last_error = $2,
+            execute_after = now() + make_interval(secs => $3)
+        WHERE subscription_token = $1
+        "#,
+        subscription_token,
+        error.to_string(),
+        delay.as_secs_f64()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn defer_entry(
+    transaction: &mut PgTransaction,
+    subscription_token: &str,
+    error: &EmailError,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE confirmation_email_outbox
+        SET
+            last_error = $2,
+            execute_after = now() + make_interval(secs => $3)
+        WHERE subscription_token = $1
+        "#,
+        subscription_token,
+        error.to_string(),
+        delay.as_secs_f64()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_entry(
+    transaction: &mut PgTransaction,
+    subscription_token: &str,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM confirmation_email_outbox
+        WHERE subscription_token = $1
+        "#,
+        subscription_token,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+/// Drop the entries that cannot be sent anymore: the subscriber has confirmed in the
+/// meantime, has left while changing their address, or the token has expired.
+#[tracing::instrument(skip_all)]
+async fn delete_stale_entries(transaction: &mut PgTransaction) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM confirmation_email_outbox o
+        USING subscription_tokens t
+            JOIN subscriptions s ON s.id = t.subscriber_id
+            LEFT JOIN email_change_requests r ON r.subscription_token = t.subscription_token
+        WHERE
+            t.subscription_token = o.subscription_token AND
+            (
+                s.status <> CASE
+                    WHEN r.new_email IS NULL THEN 'pending_confirmation'
+                    ELSE 'confirmed'
+                END OR
+                t.expires_at <= now()
+            )
+        "#,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+async fn dispatcher_loop(
+    pool: PgPool,
+    email_client: Arc<EmailClient>,
+    base_url: String,
+    settings: ConfirmationOutboxSettings,
+) -> Result<(), anyhow::Error> {
+    loop {
+        match try_dispatch_confirmation_email(&pool, &email_client, &base_url, &settings).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+// Only returns if Postgres cannot be reached on startup: the loop retries its own
+// failures. Raced against the API server in `main`.
+pub async fn run_dispatcher_until_stopped(
+    configuration: Settings,
+    email_client: Arc<EmailClient>,
+) -> Result<(), anyhow::Error> {
+    let connection_pool = Application::get_connection_pool(&configuration.database).await?;
+    dispatcher_loop(
+        connection_pool,
+        email_client,
+        configuration.application.base_url,
+        configuration.confirmation_outbox,
+    )
+    .await
+}
@@ -0,0 +1,171 @@
+use csv_core::{ReadRecordResult, Reader};
+
+/// A CSV record, or why its fields could not be read.
+pub type CsvRecord = Result<Vec<String>, String>;
+
+// Longer records are skipped rather than buffered
+const MAX_RECORD_LEN: usize = 64 * 1024;
+const MAX_FIELDS: usize = 1024;
+
+/// Splits CSV into records as it comes in, chunk by chunk, so that an upload can
+/// be processed without holding all of it in memory.
+///
+/// Empty lines are skipped. A record may span several chunks, and a quoted field
+/// several lines. A record over 64 KiB or 1024 fields is returned as an error.
+pub struct CsvRecords {
+    reader: Reader,
+    // The fields of the record being read, back to back, and where each of them ends
+    output: Vec<u8>,
+    output_len: usize,
+    ends: Vec<usize>,
+    n_ends: usize,
+    // Set once the record being read has outgrown the buffers: the rest of it is
+    // read over what came before, and thrown away.
+    too_long: bool,
+}
+
+impl Default for CsvRecords {
+    fn default() -> Self {
+        Self {
+            reader: Reader::new(),
+            output: vec![0; 1024],
+            output_len: 0,
+            ends: vec![0; 16],
+            n_ends: 0,
+            too_long: false,
+        }
+    }
+}
+
+impl CsvRecords {
+    /// Parse `chunk`, returning the records it completes.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<CsvRecord> {
+        // An empty input tells the reader that the CSV is over.
+        if chunk.is_empty() {
+            return Vec::new();
+        }
+        self.read(chunk)
+    }
+
+    /// Return the last record, once there is no more input.
+    pub fn finish(&mut self) -> Vec<CsvRecord> {
+        self.read(&[])
+    }
+
+    fn read(&mut self, mut input: &[u8]) -> Vec<CsvRecord> {
+        let mut records = Vec::new();
+        loop {
+            let (result, n_in, n_out, n_ends) = self.reader.read_record(
+                input,
+                &mut self.output[self.output_len..],
+                &mut self.ends[self.n_ends..],
+            );
+            input = &input[n_in..];
+            self.output_len += n_out;
+            self.n_ends += n_ends;
+            match result {
+                ReadRecordResult::InputEmpty | ReadRecordResult::End => return records,
+                ReadRecordResult::OutputFull if self.output.len() >= MAX_RECORD_LEN => {
+                    self.too_long = true;
+                    self.output_len = 0;
+                }
+                ReadRecordResult::OutputFull => self.output.resize(self.output.len() * 2, 0),
+                ReadRecordResult::OutputEndsFull if self.ends.len() >= MAX_FIELDS => {
+                    self.too_long = true;
+                    self.n_ends = 0;
+                }
+                ReadRecordResult::OutputEndsFull => self.ends.resize(self.ends.len() * 2, 0),
+                ReadRecordResult::Record if self.too_long => {
+                    self.too_long = false;
+                    self.output_len = 0;
+                    self.n_ends = 0;
+                    records.push(Err("The row is too long.".to_string()));
+                }
+                ReadRecordResult::Record => records.push(self.take_record()),
+            }
+        }
+    }
+
+    fn take_record(&mut self) -> CsvRecord {
+        let mut start = 0;
+        let fields = self.ends[..self.n_ends]
+            .iter()
+            .map(|&end| {
+                let field = String::from_utf8(self.output[start..end].to_vec())
+                    .map_err(|_| "The row is not valid UTF-8.".to_string());
+                start = end;
+                field
+            })
+            .collect();
+        self.output_len = 0;
+        self.n_ends = 0;
+        fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CsvRecords;
+
+    fn read_in_chunks(csv: &str, chunk_size: usize) -> Vec<Vec<String>> {
+        let mut reader = CsvRecords::default();
+        let mut records = Vec::new();
+        for chunk in csv.as_bytes().chunks(chunk_size) {
+            records.extend(reader.push(chunk));
+        }
+        records.extend(reader.finish());
+        records.into_iter().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn records_do_not_depend_on_how_the_input_is_split() {
+        let csv = "email,name\r\nursula@example.com,\"Le Guin, Ursula\"\n\nbob@example.com,Bob";
+        let expected = vec![
+            vec!["email", "name"],
+            vec!["ursula@example.com", "Le Guin, Ursula"],
+            vec!["bob@example.com", "Bob"],
+        ];
+        for chunk_size in [1, 2, 7, 1000] {
+            assert_eq!(read_in_chunks(csv, chunk_size), expected);
+        }
+    }
+
+    #[test]
+    fn long_records_are_read_whole() {
+        let name = "a".repeat(5000);
+        let fields: Vec<String> = (0..40).map(|i| i.to_string()).collect();
+        let csv = format!("{},{}\n", name, fields.join(","));
+        let records = read_in_chunks(&csv, 100);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0][0], name);
+        assert_eq!(records[0].len(), 41);
+    }
+
+    #[test]
+    fn records_that_are_too_long_only_fail_their_own_row() {
+        let mut reader = CsvRecords::default();
+        let long_field = "a".repeat(100 * 1024);
+        let many_fields = vec!["b"; 2000].join(",");
+        let csv = format!("{}\n{}\nc,d\n", long_field, many_fields);
+        let mut records = Vec::new();
+        for chunk in csv.as_bytes().chunks(1000) {
+            records.extend(reader.push(chunk));
+        }
+        records.extend(reader.finish());
+        assert_eq!(records.len(), 3);
+        assert!(records[0].is_err());
+        assert!(records[1].is_err());
+        assert_eq!(records[2], Ok(vec!["c".to_string(), "d".to_string()]));
+        assert!(reader.output.len() <= super::MAX_RECORD_LEN);
+        assert!(reader.ends.len() <= super::MAX_FIELDS);
+    }
+
+    #[test]
+    fn invalid_utf8_only_fails_its_own_row() {
+        let mut reader = CsvRecords::default();
+        let mut records = reader.push(b"a,\xff\nb,c\n");
+        records.extend(reader.finish());
+        assert!(records[0].is_err());
+        assert_eq!(records[1], Ok(vec!["b".to_string(), "c".to_string()]));
+    }
+}
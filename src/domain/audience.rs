@@ -0,0 +1,93 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Who a newsletter issue goes to.
+///
+/// A confirmed subscriber receives the issue if they belong to one of `lists` or
+/// carry one of `tags`, or if both are empty, unless they belong to one of
+/// `exclude_lists` or carry one of `exclude_tags`.
+#[derive(serde::Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Audience {
+    pub lists: Vec<String>,
+    pub tags: Vec<String>,
+    pub exclude_lists: Vec<String>,
+    pub exclude_tags: Vec<String>,
+}
+
+impl Audience {
+    /// Trim every name, rejecting empty or malformed ones.
+    pub fn parse(self) -> Result<Audience, String> {
+        let parse_all = |labels: Vec<String>| -> Result<Vec<String>, String> {
+            labels.into_iter().map(parse_label).collect()
+        };
+        Ok(Audience {
+            lists: parse_all(self.lists)?,
+            tags: parse_all(self.tags)?,
+            exclude_lists: parse_all(self.exclude_lists)?,
+            exclude_tags: parse_all(self.exclude_tags)?,
+        })
+    }
+
+    /// Every list the audience refers to, included or excluded.
+    pub fn list_names(&self) -> Vec<String> {
+        self.lists
+            .iter()
+            .chain(self.exclude_lists.iter())
+            .cloned()
+            .collect()
+    }
+}
+
+/// The name of a list or a tag.
+///
+/// Names show up in comma-separated form fields, hence no commas.
+pub fn parse_label(s: String) -> Result<String, String> {
+    let label = s.trim();
+    if label.is_empty() || label.graphemes(true).count() > 64 || label.contains(',') {
+        Err(format!("`{}` is not a valid list or tag name.", s))
+    } else {
+        Ok(label.to_string())
+    }
+}
+
+/// Split a comma-separated form field into names, ignoring blanks.
+pub fn split_labels(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|label| !label.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::audience::{parse_label, split_labels, Audience};
+    use claim::{assert_err, assert_ok_eq};
+
+    #[test]
+    fn labels_are_trimmed() {
+        assert_ok_eq!(parse_label(" rust ".into()), "rust".to_string());
+    }
+
+    #[test]
+    fn empty_labels_and_labels_with_commas_are_rejected() {
+        assert_err!(parse_label("  ".into()));
+        assert_err!(parse_label("rust,go".into()));
+        assert_err!(parse_label("a".repeat(65)));
+    }
+
+    #[test]
+    fn comma_separated_fields_are_split_into_labels() {
+        assert_eq!(split_labels(" rust, ,go ,"), vec!["rust", "go"]);
+        assert!(split_labels("").is_empty());
+    }
+
+    #[test]
+    fn an_audience_is_rejected_if_any_of_its_labels_is_invalid() {
+        let audience = Audience {
+            exclude_tags: vec!["".into()],
+            ..Audience::default()
+        };
+        assert_err!(audience.parse());
+    }
+}
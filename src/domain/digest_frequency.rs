@@ -0,0 +1,54 @@
+/// How often a subscriber wants to hear from us: every issue as it is published,
+/// or a digest of the issues published since the previous one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DigestFrequency {
+    EveryIssue,
+    Weekly,
+    Monthly,
+}
+
+impl DigestFrequency {
+    pub const ALL: [DigestFrequency; 3] = [Self::EveryIssue, Self::Weekly, Self::Monthly];
+
+    pub fn parse(s: &str) -> Result<DigestFrequency, String> {
+        Self::ALL
+            .into_iter()
+            .find(|frequency| frequency.as_str() == s)
+            .ok_or_else(|| format!("{} is not a valid digest frequency.", s))
+    }
+
+    /// The value stored in `subscriptions.digest_frequency`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::EveryIssue => "every_issue",
+            Self::Weekly => "weekly",
+            Self::Monthly => "monthly",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::EveryIssue => "Every issue, as soon as it is published",
+            Self::Weekly => "A weekly digest",
+            Self::Monthly => "A monthly digest",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::DigestFrequency;
+    use claim::{assert_err, assert_ok_eq};
+
+    #[test]
+    fn frequencies_round_trip_through_their_stored_value() {
+        for frequency in DigestFrequency::ALL {
+            assert_ok_eq!(DigestFrequency::parse(frequency.as_str()), frequency);
+        }
+    }
+
+    #[test]
+    fn unknown_frequencies_are_rejected() {
+        assert_err!(DigestFrequency::parse("daily"));
+    }
+}
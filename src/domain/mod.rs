@@ -1,7 +1,13 @@
+mod audience;
+mod digest_frequency;
 mod new_subscriber;
+mod newsletter_template;
 mod subscriber_email;
 mod subscriber_name;
 
+pub use audience::{parse_label, split_labels, Audience};
+pub use digest_frequency::DigestFrequency;
 pub use new_subscriber::NewSubscriber;
+pub use newsletter_template::{MergeField, MergeValues, NewsletterTemplate};
 pub use subscriber_email::SubscriberEmail;
 pub use subscriber_name::SubscriberName;
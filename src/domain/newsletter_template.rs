@@ -0,0 +1,172 @@
+/// The values a newsletter issue can refer to with a `{{ placeholder }}`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeField {
+    Name,
+    UnsubscribeUrl,
+    PreferencesUrl,
+    IssueUrl,
+}
+
+impl MergeField {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "name" => Some(Self::Name),
+            "unsubscribe_url" => Some(Self::UnsubscribeUrl),
+            "preferences_url" => Some(Self::PreferencesUrl),
+            "issue_url" => Some(Self::IssueUrl),
+            _ => None,
+        }
+    }
+}
+
+/// What the merge fields of an issue are replaced with for a given subscriber.
+pub struct MergeValues<'a> {
+    pub name: &'a str,
+    pub unsubscribe_url: &'a str,
+    pub preferences_url: &'a str,
+    pub issue_url: &'a str,
+}
+
+impl MergeValues<'_> {
+    fn get(&self, field: MergeField) -> &str {
+        match field {
+            MergeField::Name => self.name,
+            MergeField::UnsubscribeUrl => self.unsubscribe_url,
+            MergeField::PreferencesUrl => self.preferences_url,
+            MergeField::IssueUrl => self.issue_url,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Field(MergeField),
+}
+
+/// The body of a newsletter issue, with `{{ name }}`, `{{ unsubscribe_url }}`,
+/// `{{ preferences_url }}` and `{{ issue_url }}` placeholders to be filled in for
+/// each subscriber.
+#[derive(Debug)]
+pub struct NewsletterTemplate(Vec<Segment>);
+
+impl NewsletterTemplate {
+    pub fn parse(s: &str) -> Result<NewsletterTemplate, String> {
+        let mut segments = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| format!("`{}` is never closed.", truncate(&rest[start..])))?;
+            let placeholder = rest[start + 2..start + end].trim();
+            let field = MergeField::parse(placeholder)
+                .ok_or_else(|| format!("`{{{{ {}}}}}` is not a known merge field.", placeholder))?;
+            segments.push(Segment::Field(field));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        Ok(Self(segments))
+    }
+
+    /// A body without any placeholder, sent as is.
+    pub fn literal(s: String) -> NewsletterTemplate {
+        Self(vec![Segment::Literal(s)])
+    }
+
+    pub fn uses(&self, field: MergeField) -> bool {
+        self.0.contains(&Segment::Field(field))
+    }
+
+    /// Fill in the placeholders of an HTML body: values are HTML-escaped.
+    pub fn render_html(&self, values: &MergeValues) -> String {
+        self.render(values, htmlescape::encode_minimal)
+    }
+
+    /// Fill in the placeholders of a plain-text body.
+    pub fn render_text(&self, values: &MergeValues) -> String {
+        self.render(values, str::to_string)
+    }
+
+    fn render(&self, values: &MergeValues, encode: impl Fn(&str) -> String) -> String {
+        let mut rendered = String::new();
+        for segment in &self.0 {
+            match segment {
+                Segment::Literal(literal) => rendered.push_str(literal),
+                Segment::Field(field) => rendered.push_str(&encode(values.get(*field))),
+            }
+        }
+        rendered
+    }
+}
+
+// Enough of an unclosed placeholder to point the author at it.
+fn truncate(s: &str) -> &str {
+    match s.char_indices().nth(20) {
+        Some((i, _)) => &s[..i],
+        None => s,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::newsletter_template::{MergeField, MergeValues, NewsletterTemplate};
+    use claim::assert_err;
+
+    fn values() -> MergeValues<'static> {
+        MergeValues {
+            name: "Ursula & co",
+            unsubscribe_url: "https://example.com/unsubscribe?a=1&b=2",
+            preferences_url: "https://example.com/preferences",
+            issue_url: "https://example.com/issues/1",
+        }
+    }
+
+    #[test]
+    fn a_body_without_placeholders_is_rendered_as_is() {
+        let template = NewsletterTemplate::parse("<p>Hello</p>").unwrap();
+        assert_eq!(template.render_html(&values()), "<p>Hello</p>");
+    }
+
+    #[test]
+    fn placeholders_are_replaced_with_the_merge_values() {
+        let template =
+            NewsletterTemplate::parse("Hi {{name}}! Read online: {{ issue_url }}").unwrap();
+        assert_eq!(
+            template.render_text(&values()),
+            "Hi Ursula & co! Read online: https://example.com/issues/1"
+        );
+    }
+
+    #[test]
+    fn values_are_html_escaped_in_html_bodies() {
+        let template =
+            NewsletterTemplate::parse(r#"<p>{{ name }}</p><a href="{{ unsubscribe_url }}">"#)
+                .unwrap();
+        assert_eq!(
+            template.render_html(&values()),
+            r#"<p>Ursula &amp; co</p><a href="https://example.com/unsubscribe?a=1&amp;b=2">"#
+        );
+    }
+
+    #[test]
+    fn unknown_placeholders_are_rejected() {
+        assert_err!(NewsletterTemplate::parse("Hi {{ email }}"));
+    }
+
+    #[test]
+    fn unclosed_placeholders_are_rejected() {
+        assert_err!(NewsletterTemplate::parse("Hi {{ name"));
+    }
+
+    #[test]
+    fn uses_tells_which_fields_appear_in_the_body() {
+        let template = NewsletterTemplate::parse("{{ unsubscribe_url }}").unwrap();
+        assert!(template.uses(MergeField::UnsubscribeUrl));
+        assert!(!template.uses(MergeField::Name));
+    }
+}
@@ -0,0 +1,166 @@
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Calls go through to the provider.
+    Closed,
+    /// The provider keeps failing: calls fail fast until the cooldown is over.
+    Open,
+    /// The cooldown is over: a single probe call decides whether to close again.
+    HalfOpen,
+}
+
+/// Stops us from waiting on a provider that is down: after `failure_threshold`
+/// consecutive failed calls the circuit opens and every call fails straight away
+/// for `cooldown`, after which one probe call is let through.
+pub(super) struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    probe_started_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            inner: Mutex::new(Inner {
+                consecutive_failures: 0,
+                opened_at: None,
+                probe_started_at: None,
+            }),
+        }
+    }
+
+    /// A breaker that never opens.
+    pub fn disabled() -> Self {
+        Self::new(u32::MAX, Duration::ZERO)
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let inner = self.inner.lock().unwrap();
+        match inner.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Whether a call may go through to the provider right now.
+    pub fn try_acquire(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let opened_at = match inner.opened_at {
+            None => return true,
+            Some(opened_at) => opened_at,
+        };
+        if opened_at.elapsed() < self.cooldown {
+            return false;
+        }
+        // Only one probe at a time. A probe that never reported back (e.g. because
+        // its future was dropped) is given up on after another cooldown.
+        match inner.probe_started_at {
+            Some(started_at) if started_at.elapsed() < self.cooldown => false,
+            _ => {
+                inner.probe_started_at = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        inner.opened_at = None;
+        inner.probe_started_at = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+        inner.probe_started_at = None;
+        if inner.opened_at.is_some() || inner.consecutive_failures >= self.failure_threshold {
+            if inner.opened_at.is_none() {
+                tracing::warn!(
+                    consecutive_failures = inner.consecutive_failures,
+                    "The email provider keeps failing. Opening the circuit breaker."
+                );
+            }
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitState};
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn the_circuit_opens_after_the_failure_threshold() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+        for _ in 0..2 {
+            assert!(breaker.try_acquire());
+            breaker.record_failure();
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_success_resets_the_failure_count() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_single_probe_is_let_through_after_the_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        breaker.record_failure();
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.try_acquire());
+        assert!(!breaker.try_acquire());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_successful_probe_closes_the_circuit() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        breaker.record_failure();
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.try_acquire());
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_probe_opens_the_circuit_again() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        breaker.record_failure();
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.try_acquire());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire());
+    }
+}
@@ -0,0 +1,63 @@
+use crate::email_client::smtp::build_message;
+use crate::email_client::{Email, EmailError, EmailProvider};
+use lettre::{AsyncFileTransport, AsyncTransport, Tokio1Executor};
+use std::path::Path;
+
+/// Writes every email to a directory as an `.eml` file instead of sending it.
+/// Handy for local development: the files open in any mail client.
+pub struct FileOutbox {
+    transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileOutbox {
+    pub fn new(directory: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        std::fs::create_dir_all(&directory)?;
+        Ok(Self {
+            transport: AsyncFileTransport::new(directory),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailProvider for FileOutbox {
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        let message = build_message(email)?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| EmailError::Transient(e.into()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailClient, FileOutbox};
+    use claim::assert_ok;
+
+    #[tokio::test]
+    async fn send_email_writes_an_eml_file_to_the_outbox() {
+        // Arrange
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let sender = SubscriberEmail::parse("newsletter@example.com".into()).unwrap();
+        let recipient = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+        let email_client = EmailClient::new(sender, FileOutbox::new(&directory).unwrap());
+        // Act
+        let outcome = email_client
+            .send_email(&recipient, "Newsletter", "<p>Hello!</p>", Some("Hello!"))
+            .await;
+        // Assert
+        assert_ok!(outcome);
+        let files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let eml = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(eml.contains("To: ursula@example.com"));
+        assert!(eml.contains("Subject: Newsletter"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+mod circuit_breaker;
+mod file_outbox;
+mod plain_text;
+mod postmark;
+mod smtp;
+mod throttle;
+
+use crate::domain::SubscriberEmail;
+use circuit_breaker::CircuitBreaker;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::time::Duration;
+use throttle::Throttle;
+
+pub use circuit_breaker::CircuitState;
+pub use file_outbox::FileOutbox;
+pub use plain_text::html_to_text;
+pub use postmark::PostmarkClient;
+pub use smtp::SmtpClient;
+
+/// A custom header to be set on an outgoing email, in Postmark's `Headers` format.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmailHeader {
+    pub name: String,
+    pub value: String,
+}
+
+impl EmailHeader {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+}
+
+/// A single message, as handed over to an `EmailProvider`.
+pub struct Email<'a> {
+    pub from: &'a SubscriberEmail,
+    pub to: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_body: &'a str,
+    pub text_body: &'a str,
+    pub headers: &'a [EmailHeader],
+}
+
+/// Why a provider failed to accept an email, and whether it is worth trying again.
+#[derive(thiserror::Error, Debug)]
+pub enum EmailError {
+    #[error(transparent)]
+    Transient(anyhow::Error),
+    #[error(transparent)]
+    Permanent(anyhow::Error),
+    /// The provider asked us to slow down, possibly telling us for how long.
+    #[error("The email provider is rate limiting us")]
+    RateLimited { retry_after: Option<Duration> },
+    /// We did not even try: the provider has been failing and the circuit is open
+    /// for another `retry_after` at most.
+    #[error("The email provider is unavailable: the circuit breaker is open")]
+    CircuitOpen { retry_after: Duration },
+}
+
+/// How long to hold sends back after a 429 that did not come with a `Retry-After`.
+const DEFAULT_RATE_LIMIT_PAUSE: Duration = Duration::from_secs(1);
+
+impl EmailError {
+    /// How long to wait before trying again if the failure says nothing about the
+    /// email itself, in which case it should not count as an attempt.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            EmailError::RateLimited { retry_after } => {
+                Some(retry_after.unwrap_or(DEFAULT_RATE_LIMIT_PAUSE))
+            }
+            EmailError::CircuitOpen { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
+/// A backend able to deliver emails on our behalf.
+#[async_trait::async_trait]
+pub trait EmailProvider: Send + Sync {
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError>;
+
+    /// The largest number of emails `send_batch` accepts in a single call.
+    fn max_batch_size(&self) -> usize {
+        1
+    }
+
+    /// Send several emails at once, returning one outcome per email, in order.
+    /// Backends without a batch API send them one after the other.
+    async fn send_batch(&self, emails: &[Email<'_>]) -> Vec<Result<(), EmailError>> {
+        let mut outcomes = Vec::with_capacity(emails.len());
+        for email in emails {
+            outcomes.push(self.send(email).await);
+        }
+        outcomes
+    }
+}
+
+/// One of the messages passed to `EmailClient::send_batch`.
+pub struct BatchEmail<'a> {
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    /// Derived from `html_content` if omitted.
+    pub text_content: Option<&'a str>,
+    pub headers: &'a [EmailHeader],
+}
+
+pub struct EmailClient {
+    provider: Box<dyn EmailProvider>,
+    sender: SubscriberEmail,
+    unsubscribe_mailbox: Option<SubscriberEmail>,
+    throttle: Throttle,
+    circuit_breaker: CircuitBreaker,
+}
+
+impl EmailClient {
+    pub fn new(sender: SubscriberEmail, provider: impl EmailProvider + 'static) -> Self {
+        Self {
+            provider: Box::new(provider),
+            sender,
+            unsubscribe_mailbox: None,
+            throttle: Throttle::unlimited(),
+            circuit_breaker: CircuitBreaker::disabled(),
+        }
+    }
+
+    /// Send at most `messages_per_second` messages, with no more than `max_in_flight`
+    /// calls to the provider at any given time.
+    pub fn with_rate_limit(mut self, messages_per_second: u32, max_in_flight: usize) -> Self {
+        self.throttle = Throttle::new(messages_per_second, max_in_flight);
+        self
+    }
+
+    /// Fail fast for `cooldown` once `failure_threshold` consecutive calls to the
+    /// provider have failed with a transient error.
+    pub fn with_circuit_breaker(mut self, failure_threshold: u32, cooldown: Duration) -> Self {
+        self.circuit_breaker = CircuitBreaker::new(failure_threshold, cooldown);
+        self
+    }
+
+    /// Where `mailto:` unsubscribe requests go. Defaults to the sender.
+    pub fn with_unsubscribe_mailbox(mut self, mailbox: SubscriberEmail) -> Self {
+        self.unsubscribe_mailbox = Some(mailbox);
+        self
+    }
+
+    pub fn circuit_state(&self) -> CircuitState {
+        self.circuit_breaker.state()
+    }
+
+    pub fn unsubscribe_mailbox(&self) -> &SubscriberEmail {
+        self.unsubscribe_mailbox.as_ref().unwrap_or(&self.sender)
+    }
+
+    /// Send a single email. The plain-text part is derived from `html_content` if
+    /// `text_content` is omitted.
+    pub async fn send_email(
+        &self,
+        recepient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: Option<&str>,
+    ) -> Result<(), EmailError> {
+        self.send_email_with_headers(recepient, subject, html_content, text_content, &[])
+            .await
+    }
+
+    pub async fn send_email_with_headers(
+        &self,
+        recepient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: Option<&str>,
+        headers: &[EmailHeader],
+    ) -> Result<(), EmailError> {
+        let text_content = text_or_derived(html_content, text_content);
+        let email = Email {
+            from: &self.sender,
+            to: recepient,
+            subject,
+            html_body: html_content,
+            text_body: &text_content,
+            headers,
+        };
+        if !self.circuit_breaker.try_acquire() {
+            return Err(self.circuit_open());
+        }
+        let _permit = self.throttle.acquire(1).await;
+        let outcome = self.provider.send(&email).await;
+        self.record_outcomes(std::slice::from_ref(&outcome));
+        outcome
+    }
+
+    /// Send many emails with as few provider calls as possible: `batch` is split into
+    /// chunks of the provider's maximum batch size. The outcome of each email is
+    /// reported separately, in the same order as `batch`.
+    pub async fn send_batch(&self, batch: &[BatchEmail<'_>]) -> Vec<Result<(), EmailError>> {
+        let text_contents: Vec<_> = batch
+            .iter()
+            .map(|email| text_or_derived(email.html_content, email.text_content))
+            .collect();
+        let emails: Vec<_> = batch
+            .iter()
+            .zip(&text_contents)
+            .map(|(email, text_content)| Email {
+                from: &self.sender,
+                to: email.recipient,
+                subject: email.subject,
+                html_body: email.html_content,
+                text_body: text_content,
+                headers: email.headers,
+            })
+            .collect();
+        let mut outcomes = Vec::with_capacity(emails.len());
+        for chunk in emails.chunks(self.provider.max_batch_size().max(1)) {
+            if !self.circuit_breaker.try_acquire() {
+                outcomes.extend(chunk.iter().map(|_| Err(self.circuit_open())));
+                continue;
+            }
+            let _permit = self.throttle.acquire(chunk.len()).await;
+            let chunk_outcomes = self.provider.send_batch(chunk).await;
+            self.record_outcomes(&chunk_outcomes);
+            outcomes.extend(chunk_outcomes);
+        }
+        outcomes
+    }
+
+    fn circuit_open(&self) -> EmailError {
+        EmailError::CircuitOpen {
+            retry_after: self.circuit_breaker.cooldown(),
+        }
+    }
+
+    /// Feed the outcomes of a single call to the provider to the throttle and the
+    /// circuit breaker.
+    fn record_outcomes(&self, outcomes: &[Result<(), EmailError>]) {
+        let errors = || outcomes.iter().filter_map(|o| o.as_ref().err());
+        if let Some(pause) = errors().find_map(|e| match e {
+            EmailError::RateLimited { .. } => e.retry_after(),
+            _ => None,
+        }) {
+            tracing::warn!(
+                pause_ms = pause.as_millis() as u64,
+                "The email provider is rate limiting us. Pausing outgoing emails."
+            );
+            self.throttle.pause(pause);
+        }
+        // A rejected recipient or a 429 still tells us the provider is up.
+        if errors().any(|e| matches!(e, EmailError::Transient(_))) {
+            self.circuit_breaker.record_failure();
+        } else {
+            self.circuit_breaker.record_success();
+        }
+    }
+}
+
+fn text_or_derived<'a>(html_content: &str, text_content: Option<&'a str>) -> Cow<'a, str> {
+    match text_content {
+        Some(text_content) => Cow::Borrowed(text_content),
+        None => Cow::Owned(html_to_text(html_content)),
+    }
+}
@@ -0,0 +1,49 @@
+/// Lines are never wrapped: mail clients reflow plain text themselves, and a hard
+/// wrap would break long links in two.
+const NO_WRAPPING: usize = 10_000;
+
+/// A plain-text alternative for an HTML body: paragraphs are separated by an empty
+/// line, list items get a bullet or their number, and links become footnotes.
+pub fn html_to_text(html: &str) -> String {
+    html2text::from_read(html.as_bytes(), NO_WRAPPING)
+        .trim_end()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::html_to_text;
+
+    #[test]
+    fn paragraphs_are_separated_by_an_empty_line() {
+        let text = html_to_text("<p>First paragraph.</p><p>Second<br>paragraph.</p>");
+        assert_eq!(text, "First paragraph.\n\nSecond\nparagraph.");
+    }
+
+    #[test]
+    fn list_items_get_bullets_or_numbers() {
+        let text = html_to_text("<ul><li>one</li><li>two</li></ul><ol><li>three</li></ol>");
+        assert_eq!(text, "* one\n* two\n\n1. three");
+    }
+
+    #[test]
+    fn links_become_footnotes() {
+        let text = html_to_text(r#"<p>Read <a href="https://example.com/docs">the docs</a>.</p>"#);
+        assert_eq!(text, "Read [the docs][1].\n\n[1]: https://example.com/docs");
+    }
+
+    #[test]
+    fn long_links_are_not_wrapped() {
+        let url = format!("https://example.com/{}", "a".repeat(200));
+        let text = html_to_text(&format!(r#"<a href="{}">link</a>"#, url));
+        assert!(text.lines().any(|line| line == format!("[1]: {}", url)));
+    }
+
+    #[test]
+    fn stylesheets_are_left_out() {
+        let text = html_to_text(
+            "<html><head><style>p { color: red; }</style></head><body><p>Hi</p></body></html>",
+        );
+        assert_eq!(text, "Hi");
+    }
+}
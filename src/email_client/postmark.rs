@@ -0,0 +1,535 @@
+use crate::email_client::{Email, EmailError, EmailHeader, EmailProvider};
+use chrono::Utc;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Postmark accepts at most 500 messages per `/email/batch` call.
+const MAX_BATCH_SIZE: usize = 500;
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    headers: &'a [EmailHeader],
+}
+
+impl<'a> From<&'a Email<'a>> for SendEmailRequest<'a> {
+    fn from(email: &'a Email<'a>) -> Self {
+        Self {
+            from: email.from.as_ref(),
+            to: email.to.as_ref(),
+            subject: email.subject,
+            html_body: email.html_body,
+            text_body: email.text_body,
+            headers: email.headers,
+        }
+    }
+}
+
+/// The outcome of a single message in a `/email/batch` response.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BatchResult {
+    error_code: i64,
+    message: String,
+}
+
+/// Sends emails through Postmark's `/email` and `/email/batch` JSON endpoints.
+pub struct PostmarkClient {
+    http_client: Client,
+    base_url: String,
+    authorization_token: String,
+}
+
+impl PostmarkClient {
+    pub fn new(
+        base_url: String,
+        authorization_token: String,
+        timeout: std::time::Duration,
+    ) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        Self {
+            http_client,
+            base_url,
+            authorization_token,
+        }
+    }
+
+    async fn post_batch(&self, emails: &[Email<'_>]) -> Result<Vec<BatchResult>, EmailError> {
+        let url = format!("{}/email/batch", self.base_url);
+        let request_body: Vec<_> = emails.iter().map(SendEmailRequest::from).collect();
+        let response = self
+            .http_client
+            .post(&url)
+            .header("X-Postmark-Server-Token", &self.authorization_token)
+            .json(&request_body)
+            .send()
+            .await?;
+        Ok(check_status(response)?.json().await?)
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailProvider for PostmarkClient {
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        let url = format!("{}/email", self.base_url);
+        let request_body = SendEmailRequest::from(email);
+        let response = self
+            .http_client
+            .post(&url)
+            .header("X-Postmark-Server-Token", &self.authorization_token)
+            .json(&request_body)
+            .send()
+            .await?;
+        check_status(response)?;
+        Ok(())
+    }
+
+    fn max_batch_size(&self) -> usize {
+        MAX_BATCH_SIZE
+    }
+
+    async fn send_batch(&self, emails: &[Email<'_>]) -> Vec<Result<(), EmailError>> {
+        match self.post_batch(emails).await {
+            Ok(results) if results.len() == emails.len() => results
+                .into_iter()
+                .map(|result| match result.error_code {
+                    0 => Ok(()),
+                    code => Err(EmailError::Permanent(anyhow::anyhow!(
+                        "Postmark rejected the message ({}): {}",
+                        code,
+                        result.message
+                    ))),
+                })
+                .collect(),
+            // We can't tell which messages went through: retrying could send some of
+            // them twice, so leave it to a human.
+            Ok(results) => emails
+                .iter()
+                .map(|_| {
+                    Err(EmailError::Permanent(anyhow::anyhow!(
+                        "Postmark returned {} results for a batch of {} messages",
+                        results.len(),
+                        emails.len()
+                    )))
+                })
+                .collect(),
+            // The whole request failed: every message shares the same fate.
+            Err(e) => {
+                let e = Arc::new(e);
+                emails.iter().map(|_| Err(share(&e))).collect()
+            }
+        }
+    }
+}
+
+/// Postmark answers with a 429 when we exceed its rate limits: surface it, along with
+/// how long it wants us to wait, so that the client can pause.
+fn check_status(response: Response) -> Result<Response, EmailError> {
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(EmailError::RateLimited {
+            retry_after: retry_after(response.headers()),
+        });
+    }
+    Ok(response.error_for_status()?)
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+/// Hand the same failure to every message of a batch.
+fn share(e: &Arc<EmailError>) -> EmailError {
+    match **e {
+        EmailError::Transient(_) => EmailError::Transient(anyhow::Error::new(e.clone())),
+        EmailError::Permanent(_) => EmailError::Permanent(anyhow::Error::new(e.clone())),
+        EmailError::RateLimited { retry_after } => EmailError::RateLimited { retry_after },
+        EmailError::CircuitOpen { retry_after } => EmailError::CircuitOpen { retry_after },
+    }
+}
+
+impl From<reqwest::Error> for EmailError {
+    fn from(e: reqwest::Error) -> Self {
+        // Timeouts, connection errors and 5xx are likely to go away on their own.
+        // Anything else (e.g. a 422 for a rejected recipient) will fail again.
+        // 429s never get here: `check_status` turns them into `RateLimited`.
+        let is_transient = match e.status() {
+            Some(status) => status.is_server_error(),
+            None => true,
+        };
+        if is_transient {
+            Self::Transient(e.into())
+        } else {
+            Self::Permanent(e.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{BatchEmail, EmailClient, EmailError, EmailHeader, PostmarkClient};
+    use claim::{assert_err, assert_ok};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::{Fake, Faker};
+    use std::time::Duration;
+    use wiremock::matchers::{any, body_partial_json, header, header_exists, method, path};
+    use wiremock::Request;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    struct SendEmailBodyMatcher;
+
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            // Try to parse the body as a JSON value
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+            if let Ok(body) = result {
+                // Check that all the mandatory fields are populated
+                // without inspecting the field values
+                body.get("From").is_some()
+                    && body.get("To").is_some()
+                    && body.get("Subject").is_some()
+                    && body.get("HtmlBody").is_some()
+                    && body.get("TextBody").is_some()
+            } else {
+                // If parsing failed, do not match the request
+                false
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_sends_the_expected_request() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // Act
+        let _ = email_client(mock_server.uri())
+            .send_email(&email(), &subject(), &content(), Some(&content()))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn send_email_with_headers_sends_them_in_the_headers_array() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "Headers": [{"Name": "List-Unsubscribe-Post", "Value": "List-Unsubscribe=One-Click"}]
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let headers = [EmailHeader::new(
+            "List-Unsubscribe-Post",
+            "List-Unsubscribe=One-Click",
+        )];
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email_with_headers(&email(), &subject(), &content(), Some(&content()), &headers)
+            .await;
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_succeeds_if_the_server_returns_200() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        // We do not copy in all the matchers we have in the other test
+        // The purpose of this test is not to assert the request. We add the bare minumum
+        // needed to trigger the path we want to test in `send_email`
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email(&email(), &subject(), &content(), Some(&content()))
+            .await;
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_returns_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            // Not a 200 anymore!
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let outcome = email_client(mock_server.uri())
+            .send_email(&email(), &subject(), &content(), Some(&content()))
+            .await;
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_times_out_if_the_server_takes_too_long() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let response = ResponseTemplate::new(200)
+            // 3 minutes!
+            .set_delay(std::time::Duration::from_secs(180));
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email(&email(), &subject(), &content(), Some(&content()))
+            .await;
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_transient() {
+        for status in [500, 503] {
+            // Arrange
+            let mock_server = MockServer::start().await;
+            Mock::given(any())
+                .respond_with(ResponseTemplate::new(status))
+                .mount(&mock_server)
+                .await;
+            // Act
+            let outcome = email_client(mock_server.uri())
+                .send_email(&email(), &subject(), &content(), Some(&content()))
+                .await;
+            // Assert
+            assert!(
+                matches!(outcome, Err(EmailError::Transient(_))),
+                "A {} was not deemed transient",
+                status
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_429_is_reported_with_its_retry_after() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "7"))
+            .mount(&mock_server)
+            .await;
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email(&email(), &subject(), &content(), Some(&content()))
+            .await;
+        // Assert
+        assert!(matches!(
+            outcome,
+            Err(EmailError::RateLimited {
+                retry_after: Some(d)
+            }) if d == Duration::from_secs(7)
+        ));
+    }
+
+    #[tokio::test]
+    async fn the_client_holds_back_after_a_429() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let email_client = email_client(mock_server.uri());
+        assert_err!(
+            email_client
+                .send_email(&email(), &subject(), &content(), Some(&content()))
+                .await
+        );
+        // Act
+        let start = std::time::Instant::now();
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), Some(&content()))
+            .await;
+        // Assert
+        assert_ok!(outcome);
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_are_permanent() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .mount(&mock_server)
+            .await;
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email(&email(), &subject(), &content(), Some(&content()))
+            .await;
+        // Assert
+        assert!(matches!(outcome, Err(EmailError::Permanent(_))));
+    }
+
+    /// Answer a `/email/batch` request, accepting every message but the ones sent to
+    /// `rejected`.
+    fn batch_response(request: &Request, rejected: &str) -> ResponseTemplate {
+        let messages: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let results: Vec<_> = messages
+            .iter()
+            .map(|message| match message["To"] == rejected {
+                true => serde_json::json!({"ErrorCode": 406, "Message": "Inactive recipient"}),
+                false => serde_json::json!({"ErrorCode": 0, "Message": "OK"}),
+            })
+            .collect();
+        ResponseTemplate::new(200).set_body_json(results)
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_the_outcome_of_each_message() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let recipients = [email(), email(), email()];
+        let rejected_address = recipients[1].as_ref().to_owned();
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(move |request: &Request| batch_response(request, &rejected_address))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let (subject, content) = (subject(), content());
+        let batch: Vec<_> = recipients
+            .iter()
+            .map(|recipient| BatchEmail {
+                recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: Some(&content),
+                headers: &[],
+            })
+            .collect();
+        // Act
+        let outcomes = email_client(mock_server.uri()).send_batch(&batch).await;
+        // Assert
+        assert_eq!(outcomes.len(), 3);
+        assert_ok!(&outcomes[0]);
+        assert!(matches!(outcomes[1], Err(EmailError::Permanent(_))));
+        assert_ok!(&outcomes[2]);
+    }
+
+    #[tokio::test]
+    async fn send_batch_splits_large_batches() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/email/batch"))
+            .respond_with(|request: &Request| batch_response(request, ""))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        let recipient = email();
+        let (subject, content) = (subject(), content());
+        let batch: Vec<_> = (0..501)
+            .map(|_| BatchEmail {
+                recipient: &recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: Some(&content),
+                headers: &[],
+            })
+            .collect();
+        // Act
+        let outcomes = email_client(mock_server.uri()).send_batch(&batch).await;
+        // Assert
+        assert_eq!(outcomes.len(), 501);
+        assert!(outcomes.iter().all(Result::is_ok));
+    }
+
+    #[tokio::test]
+    async fn a_failed_batch_request_fails_every_message() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let recipients = [email(), email()];
+        let (subject, content) = (subject(), content());
+        let batch: Vec<_> = recipients
+            .iter()
+            .map(|recipient| BatchEmail {
+                recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: Some(&content),
+                headers: &[],
+            })
+            .collect();
+        // Act
+        let outcomes = email_client(mock_server.uri()).send_batch(&batch).await;
+        // Assert
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, Err(EmailError::Transient(_)))));
+    }
+
+    /// Generate a random email subject
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    /// Generate a random email content
+    fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+
+    /// Generate a random subscriber email
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    /// Get a test instance of `EmailClient`.
+    fn email_client(base_url: String) -> EmailClient {
+        EmailClient::new(
+            email(),
+            PostmarkClient::new(
+                base_url,
+                Faker.fake(),
+                std::time::Duration::from_millis(200),
+            ),
+        )
+    }
+}
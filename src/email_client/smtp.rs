@@ -0,0 +1,207 @@
+use crate::email_client::{Email, EmailError, EmailProvider};
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+/// Sends emails to an SMTP relay.
+pub struct SmtpClient {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpClient {
+    /// `require_tls` upgrades the connection with STARTTLS: only turn it off to talk
+    /// to a local stand-in.
+    pub fn new(
+        host: &str,
+        port: u16,
+        credentials: Option<(String, String)>,
+        require_tls: bool,
+        timeout: std::time::Duration,
+    ) -> Result<Self, anyhow::Error> {
+        let mut builder = if require_tls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        }
+        .port(port)
+        .timeout(Some(timeout));
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        Ok(Self {
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailProvider for SmtpClient {
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        let message = build_message(email)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for EmailError {
+    fn from(e: lettre::transport::smtp::Error) -> Self {
+        // 5xx replies and malformed commands will fail again; 4xx replies, timeouts
+        // and connection errors are worth another go.
+        if e.is_permanent() || e.is_client() {
+            Self::Permanent(e.into())
+        } else {
+            Self::Transient(e.into())
+        }
+    }
+}
+
+/// Build a multipart/alternative MIME message out of an `Email`.
+pub(super) fn build_message(email: &Email<'_>) -> Result<Message, EmailError> {
+    let mailbox = |address: &str| {
+        address
+            .parse::<Mailbox>()
+            .map_err(|e| EmailError::Permanent(e.into()))
+    };
+    let mut builder = Message::builder()
+        .from(mailbox(email.from.as_ref())?)
+        .to(mailbox(email.to.as_ref())?)
+        .subject(email.subject);
+    for header in email.headers {
+        let name = HeaderName::new_from_ascii(header.name.clone())
+            .map_err(|e| EmailError::Permanent(e.into()))?;
+        builder = builder.raw_header(HeaderValue::new(name, header.value.clone()));
+    }
+    builder
+        .multipart(MultiPart::alternative_plain_html(
+            email.text_body.to_string(),
+            email.html_body.to_string(),
+        ))
+        .map_err(|e| EmailError::Permanent(e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailClient, EmailError, EmailHeader, SmtpClient};
+    use claim::assert_ok;
+    use fake::faker::internet::en::SafeEmail;
+    use fake::Fake;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+
+    /// A minimal SMTP server accepting a single session on a background thread.
+    /// It answers `RCPT TO` with `rcpt_reply` and hands back whatever is sent
+    /// after `DATA`.
+    fn smtp_stand_in(rcpt_reply: &'static str) -> (u16, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut reply = |line: &str| writer.write_all(format!("{}\r\n", line).as_bytes());
+            reply("220 localhost ESMTP").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let command = line.to_ascii_uppercase();
+                if command.starts_with("EHLO") || command.starts_with("HELO") {
+                    reply("250 localhost").unwrap();
+                } else if command.starts_with("RCPT") {
+                    reply(rcpt_reply).unwrap();
+                } else if command.starts_with("DATA") {
+                    reply("354 End data with <CR><LF>.<CR><LF>").unwrap();
+                    let mut data = String::new();
+                    loop {
+                        let mut data_line = String::new();
+                        reader.read_line(&mut data_line).unwrap();
+                        if data_line == ".\r\n" {
+                            break;
+                        }
+                        data.push_str(&data_line);
+                    }
+                    sender.send(data).unwrap();
+                    reply("250 OK").unwrap();
+                } else if command.starts_with("QUIT") {
+                    reply("221 Bye").unwrap();
+                    break;
+                } else {
+                    reply("250 OK").unwrap();
+                }
+                line.clear();
+            }
+        });
+        (port, receiver)
+    }
+
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    fn email_client(port: u16) -> EmailClient {
+        let provider = SmtpClient::new(
+            "127.0.0.1",
+            port,
+            None,
+            false,
+            std::time::Duration::from_secs(1),
+        )
+        .unwrap();
+        EmailClient::new(email(), provider)
+    }
+
+    #[tokio::test]
+    async fn send_email_delivers_a_multipart_message_with_custom_headers() {
+        // Arrange
+        let (port, received) = smtp_stand_in("250 OK");
+        let recipient = email();
+        let headers = [EmailHeader::new(
+            "List-Unsubscribe-Post",
+            "List-Unsubscribe=One-Click",
+        )];
+        // Act
+        let outcome = email_client(port)
+            .send_email_with_headers(
+                &recipient,
+                "Newsletter",
+                "<p>Hello!</p>",
+                Some("Hello!"),
+                &headers,
+            )
+            .await;
+        // Assert
+        assert_ok!(outcome);
+        let data = received.recv().unwrap();
+        assert!(data.contains(&format!("To: {}", recipient.as_ref())));
+        assert!(data.contains("Subject: Newsletter"));
+        assert!(data.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+        assert!(data.contains("multipart/alternative"));
+        assert!(data.contains("<p>Hello!</p>"));
+    }
+
+    #[tokio::test]
+    async fn a_4xx_reply_is_a_transient_error() {
+        // Arrange
+        let (port, _) = smtp_stand_in("451 Try again later");
+        // Act
+        let outcome = email_client(port)
+            .send_email(&email(), "Newsletter", "<p>Hello!</p>", Some("Hello!"))
+            .await;
+        // Assert
+        assert!(matches!(outcome, Err(EmailError::Transient(_))));
+    }
+
+    #[tokio::test]
+    async fn a_5xx_reply_is_a_permanent_error() {
+        // Arrange
+        let (port, _) = smtp_stand_in("550 No such user");
+        // Act
+        let outcome = email_client(port)
+            .send_email(&email(), "Newsletter", "<p>Hello!</p>", Some("Hello!"))
+            .await;
+        // Assert
+        assert!(matches!(outcome, Err(EmailError::Permanent(_))));
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::Instant;
+
+/// Client-side flow control for outgoing emails: a token bucket caps the number of
+/// messages per second, a semaphore caps the number of calls to the provider in
+/// flight, and the whole thing can be paused when the provider asks us to back off.
+pub(super) struct Throttle {
+    in_flight: Semaphore,
+    bucket: Mutex<Bucket>,
+    messages_per_second: f64,
+}
+
+struct Bucket {
+    // Can go negative: a batch larger than the bucket takes all it holds and the
+    // following calls wait for the rest to refill.
+    tokens: f64,
+    refilled_at: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new(messages_per_second: u32, max_in_flight: usize) -> Self {
+        let messages_per_second = f64::from(messages_per_second.max(1));
+        Self {
+            in_flight: Semaphore::new(max_in_flight.clamp(1, Semaphore::MAX_PERMITS)),
+            bucket: Mutex::new(Bucket {
+                tokens: messages_per_second,
+                refilled_at: Instant::now(),
+                paused_until: None,
+            }),
+            messages_per_second,
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self {
+            in_flight: Semaphore::new(Semaphore::MAX_PERMITS),
+            bucket: Mutex::new(Bucket {
+                tokens: f64::INFINITY,
+                refilled_at: Instant::now(),
+                paused_until: None,
+            }),
+            messages_per_second: f64::INFINITY,
+        }
+    }
+
+    /// Wait until we are allowed to send `n_messages` in a single call to the provider.
+    /// The call counts as in flight until the returned permit is dropped.
+    pub async fn acquire(&self, n_messages: usize) -> SemaphorePermit<'_> {
+        let permit = self
+            .in_flight
+            .acquire()
+            .await
+            .expect("The in-flight semaphore is never closed");
+        while let Some(wait) = self.try_take(n_messages as f64) {
+            tokio::time::sleep(wait).await;
+        }
+        permit
+    }
+
+    /// Hold every send back for `duration`, e.g. after a 429.
+    pub fn pause(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.paused_until = Some(bucket.paused_until.map_or(until, |u| u.max(until)));
+    }
+
+    /// Take `n` tokens, or return how long to wait before trying again.
+    fn try_take(&self, n: f64) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        if let Some(until) = bucket.paused_until {
+            if until > now {
+                return Some(until - now);
+            }
+            bucket.paused_until = None;
+        }
+        if self.messages_per_second.is_infinite() {
+            return None;
+        }
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens =
+            (bucket.tokens + elapsed * self.messages_per_second).min(self.messages_per_second);
+        bucket.refilled_at = now;
+        let needed = n.min(self.messages_per_second);
+        if bucket.tokens >= needed {
+            bucket.tokens -= n;
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                (needed - bucket.tokens) / self.messages_per_second,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Throttle;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn a_full_bucket_lets_a_burst_through() {
+        let throttle = Throttle::new(5, 10);
+        let start = Instant::now();
+        for _ in 0..5 {
+            drop(throttle.acquire(1).await);
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_bucket_refills_at_the_configured_rate() {
+        let throttle = Throttle::new(5, 10);
+        let start = Instant::now();
+        for _ in 0..10 {
+            drop(throttle.acquire(1).await);
+        }
+        // The first five go straight away, the next five need a second to refill
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(990) && elapsed <= Duration::from_millis(1010));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_batch_larger_than_the_bucket_is_paid_back_afterwards() {
+        let throttle = Throttle::new(5, 10);
+        let start = Instant::now();
+        drop(throttle.acquire(15).await);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        drop(throttle.acquire(1).await);
+        // 10 tokens of debt plus one more message, at 5 per second
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(2190) && elapsed <= Duration::from_millis(2210));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pausing_holds_every_send_back() {
+        let throttle = Throttle::unlimited();
+        throttle.pause(Duration::from_secs(3));
+        let start = Instant::now();
+        drop(throttle.acquire(1).await);
+        assert!(start.elapsed() >= Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calls_beyond_the_in_flight_limit_wait_for_a_permit() {
+        let throttle = Throttle::new(100, 1);
+        let permit = throttle.acquire(1).await;
+        let second = tokio::time::timeout(Duration::from_secs(60), throttle.acquire(1)).await;
+        assert!(second.is_err());
+        drop(permit);
+        let third = tokio::time::timeout(Duration::from_secs(60), throttle.acquire(1)).await;
+        assert!(third.is_ok());
+    }
+}
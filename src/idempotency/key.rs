@@ -0,0 +1,53 @@
+#[derive(Debug)]
+pub struct IdempotencyKey(String);
+
+impl TryFrom<String> for IdempotencyKey {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        if s.is_empty() {
+            anyhow::bail!("The idempotency key cannot be empty");
+        }
+        let max_length = 50;
+        if s.len() >= max_length {
+            anyhow::bail!(
+                "The idempotency key must be shorter than {} characters",
+                max_length
+            );
+        }
+        Ok(Self(s))
+    }
+}
+
+impl From<IdempotencyKey> for String {
+    fn from(k: IdempotencyKey) -> Self {
+        k.0
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdempotencyKey;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn empty_key_is_rejected() {
+        assert_err!(IdempotencyKey::try_from("".to_string()));
+    }
+
+    #[test]
+    fn a_50_characters_long_key_is_rejected() {
+        assert_err!(IdempotencyKey::try_from("a".repeat(50)));
+    }
+
+    #[test]
+    fn a_uuid_is_a_valid_key() {
+        assert_ok!(IdempotencyKey::try_from(uuid::Uuid::new_v4().to_string()));
+    }
+}
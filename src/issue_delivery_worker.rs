@@ -0,0 +1,667 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::Span;
+use uuid::Uuid;
+
+use crate::configuration::{IssueDeliverySettings, Settings};
+use crate::domain::{MergeField, MergeValues, NewsletterTemplate, SubscriberEmail};
+use crate::email_client::{BatchEmail, EmailClient, EmailError, EmailHeader};
+use crate::startup::{Application, HmacSecret};
+use crate::subscriber_links::SubscriberLinks;
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+/// Why a delivery attempt failed, and whether it is worth trying again.
+#[derive(thiserror::Error, Debug)]
+enum DeliveryError {
+    #[error(transparent)]
+    Transient(anyhow::Error),
+    #[error(transparent)]
+    Permanent(anyhow::Error),
+    /// The email was not looked at, because the provider is rate limiting us or the
+    /// circuit breaker is open: try again after `retry_after` without counting an attempt.
+    #[error("{error}")]
+    Deferred {
+        error: anyhow::Error,
+        retry_after: Duration,
+    },
+}
+
+impl From<EmailError> for DeliveryError {
+    fn from(e: EmailError) -> Self {
+        if let Some(retry_after) = e.retry_after() {
+            return Self::Deferred {
+                error: e.into(),
+                retry_after,
+            };
+        }
+        match e {
+            EmailError::Transient(e) => Self::Transient(e),
+            EmailError::Permanent(e) => Self::Permanent(e),
+            e @ (EmailError::RateLimited { .. } | EmailError::CircuitOpen { .. }) => {
+                Self::Transient(e.into())
+            }
+        }
+    }
+}
+
+/// Pop a batch of due tasks off `issue_delivery_queue` and deliver them in as few
+/// calls to the email provider as possible, each with a personalised unsubscribe
+/// link appended to both bodies.
+///
+/// The batch is leased rather than kept locked while the provider is called: the
+/// outcome of every email is written as soon as the provider answers, so that a
+/// failure to record one outcome cannot send the rest of the batch again.
+///
+/// A task that fails with a transient error is rescheduled with exponential
+/// backoff; once it runs out of attempts, or if it fails permanently, it is moved
+/// to `issue_delivery_dead_letters` for an admin to inspect and requeue.
+#[tracing::instrument(skip_all, fields(n_tasks=tracing::field::Empty), err)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    settings: &IssueDeliverySettings,
+    links: &SubscriberLinks,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    let tasks = dequeue_tasks(&mut transaction, settings.batch_size).await?;
+    if tasks.is_empty() {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    }
+    Span::current().record("n_tasks", tasks.len());
+    lease_tasks(&mut transaction, &tasks).await?;
+    let mut issues = HashMap::new();
+    let mut deliveries = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        let (subscriber_id, subscriber_name) = match (&task.subscriber_id, &task.subscriber_name) {
+            (Some(id), Some(name)) => (*id, name.clone()),
+            _ => {
+                tracing::info!(
+                    subscriber_email = %task.subscriber_email,
+                    "Skipping a subscriber who is no longer confirmed."
+                );
+                delete_task(&mut transaction, &task).await?;
+                continue;
+            }
+        };
+        let recipient = match SubscriberEmail::parse(task.subscriber_email.clone()) {
+            Ok(recipient) => recipient,
+            Err(e) => {
+                let error = DeliveryError::Permanent(anyhow::anyhow!(e));
+                record_failure(&mut transaction, &task, error, settings).await?;
+                continue;
+            }
+        };
+        if let Entry::Vacant(entry) = issues.entry(task.newsletter_issue_id) {
+            let issue = get_issue(pool, task.newsletter_issue_id)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("A queued newsletter issue does not exist."))?;
+            entry.insert(issue);
+        }
+        let issue = &issues[&task.newsletter_issue_id];
+        let unsubscribe_url = links.unsubscribe_url(subscriber_id);
+        let preferences_url = links.preferences_url(subscriber_id);
+        let issue_url = links.issue_url(task.newsletter_issue_id);
+        let values = MergeValues {
+            name: &subscriber_name,
+            unsubscribe_url: &unsubscribe_url,
+            preferences_url: &preferences_url,
+            issue_url: &issue_url,
+        };
+        let rendered = issue.render(&values);
+        deliveries.push(Delivery {
+            recipient,
+            html_content: rendered.html_content,
+            text_content: rendered.text_content,
+            headers: list_unsubscribe_headers(
+                email_client,
+                &links.one_click_unsubscribe_url(subscriber_id),
+            ),
+            task,
+        });
+    }
+    transaction.commit().await?;
+
+    let batch: Vec<_> = deliveries
+        .iter()
+        .map(|delivery| BatchEmail {
+            recipient: &delivery.recipient,
+            subject: &issues[&delivery.task.newsletter_issue_id].title,
+            html_content: &delivery.html_content,
+            text_content: Some(&delivery.text_content),
+            headers: &delivery.headers,
+        })
+        .collect();
+    let outcomes = email_client.send_batch(&batch).await;
+    let (mut sent, mut failed) = (Vec::new(), Vec::new());
+    for (delivery, outcome) in deliveries.iter().zip(outcomes) {
+        match outcome {
+            Ok(()) => sent.push(&delivery.task),
+            Err(e) => failed.push((&delivery.task, e)),
+        }
+    }
+    delete_tasks(pool, &sent).await?;
+    for (task, e) in failed {
+        let mut transaction = pool.begin().await?;
+        record_failure(&mut transaction, task, e.into(), settings).await?;
+        transaction.commit().await?;
+    }
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// A task that is ready to go out, with its personalised bodies and headers.
+struct Delivery {
+    task: DeliveryTask,
+    recipient: SubscriberEmail,
+    html_content: String,
+    text_content: String,
+    headers: [EmailHeader; 2],
+}
+
+async fn record_failure(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    error: DeliveryError,
+    settings: &IssueDeliverySettings,
+) -> Result<(), anyhow::Error> {
+    let n_attempts = task.n_retries + 1;
+    match error {
+        DeliveryError::Deferred { error, retry_after } => {
+            tracing::warn!(
+                error.cause_chain = ?error,
+                error.message = %error,
+                newsletter_issue_id = %task.newsletter_issue_id,
+                subscriber_email = %task.subscriber_email,
+                retry_in_ms = retry_after.as_millis() as u64,
+                "Could not deliver issue to a confirmed subscriber for now. \
+                Retrying later without counting an attempt.",
+            );
+            defer_task(transaction, task, &error, retry_after).await
+        }
+        DeliveryError::Transient(e) if n_attempts < settings.max_attempts => {
+            let delay = backoff(
+                task.n_retries,
+                settings.initial_backoff(),
+                settings.max_backoff(),
+            );
+            tracing::warn!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                newsletter_issue_id = %task.newsletter_issue_id,
+                subscriber_email = %task.subscriber_email,
+                n_attempts,
+                retry_in_ms = delay.as_millis() as u64,
+                "Failed to deliver issue to a confirmed subscriber. \
+                Retrying later.",
+            );
+            schedule_retry(transaction, task, &e, delay).await
+        }
+        DeliveryError::Transient(e) | DeliveryError::Permanent(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                newsletter_issue_id = %task.newsletter_issue_id,
+                subscriber_email = %task.subscriber_email,
+                n_attempts,
+                "Failed to deliver issue to a confirmed subscriber. \
+                Moving it to the dead letters.",
+            );
+            dead_letter_task(transaction, task, n_attempts, &e).await
+        }
+    }
+}
+
+/// Exponential backoff with jitter: the n-th retry waits between half and all of
+/// `initial_backoff * 2^n`, capped at `max_backoff`.
+pub(crate) fn backoff(
+    n_retries: i16,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+) -> Duration {
+    let exponential = initial_backoff.saturating_mul(2u32.saturating_pow(n_retries.max(0) as u32));
+    let capped = exponential.min(max_backoff);
+    let half = capped / 2;
+    half + half.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+// Every email to a subscriber links to the preferences page and lets them
+// unsubscribe: issues that do not place those links themselves get a footer with
+// the missing ones.
+fn render_html(template: &NewsletterTemplate, values: &MergeValues) -> String {
+    html_with_footer(
+        &template.render_html(values),
+        &footer_links(template, values),
+    )
+}
+
+fn render_text(template: &NewsletterTemplate, values: &MergeValues) -> String {
+    text_with_footer(
+        &template.render_text(values),
+        &footer_links(template, values),
+    )
+}
+
+// The (label, url) pairs of the footer
+fn footer_links<'a>(
+    template: &NewsletterTemplate,
+    values: &MergeValues<'a>,
+) -> Vec<(&'static str, &'a str)> {
+    let mut links = Vec::new();
+    if !template.uses(MergeField::PreferencesUrl) {
+        links.push(("Manage your preferences", values.preferences_url));
+    }
+    if !template.uses(MergeField::UnsubscribeUrl) {
+        links.push(("Unsubscribe", values.unsubscribe_url));
+    }
+    links
+}
+
+pub(crate) fn html_with_footer(html_content: &str, links: &[(&str, &str)]) -> String {
+    if links.is_empty() {
+        return html_content.to_string();
+    }
+    let links: Vec<_> = links
+        .iter()
+        .map(|(label, url)| {
+            format!(
+                r#"<a href="{}">{}</a>"#,
+                htmlescape::encode_minimal(url),
+                label
+            )
+        })
+        .collect();
+    let footer = format!("<p>{}</p>", links.join(" | "));
+    // Keep the footer inside the document if the issue is a full HTML page
+    match html_content.to_ascii_lowercase().rfind("</body>") {
+        Some(i) => format!("{}{}{}", &html_content[..i], footer, &html_content[i..]),
+        None => format!("{}{}", html_content, footer),
+    }
+}
+
+pub(crate) fn text_with_footer(text_content: &str, links: &[(&str, &str)]) -> String {
+    if links.is_empty() {
+        return text_content.to_string();
+    }
+    let links: Vec<_> = links
+        .iter()
+        .map(|(label, url)| format!("{}: {}", label, url))
+        .collect();
+    format!("{}\n\n{}", text_content, links.join("\n"))
+}
+
+// RFC 2369 `List-Unsubscribe` plus the RFC 8058 one-click marker, so that mail
+// clients can show their own unsubscribe button.
+pub(crate) fn list_unsubscribe_headers(
+    email_client: &EmailClient,
+    one_click_unsubscribe_url: &str,
+) -> [EmailHeader; 2] {
+    [
+        EmailHeader::new(
+            "List-Unsubscribe",
+            format!(
+                "<mailto:{}?subject=unsubscribe>, <{}>",
+                email_client.unsubscribe_mailbox(),
+                one_click_unsubscribe_url
+            ),
+        ),
+        EmailHeader::new("List-Unsubscribe-Post", "List-Unsubscribe=One-Click"),
+    ]
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+struct DeliveryTask {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    n_retries: i16,
+    // Only set if the subscriber is still confirmed
+    subscriber_id: Option<Uuid>,
+    subscriber_name: Option<String>,
+}
+
+// How long a dequeued batch is hidden from other workers while it is being sent.
+// A worker that dies mid-batch leaves its tasks to be picked up once it runs out.
+pub(crate) const LEASE: Duration = Duration::from_secs(5 * 60);
+
+// `SKIP LOCKED` lets several workers drain the queue concurrently: rows locked by
+// another worker are skipped instead of being delivered twice.
+#[tracing::instrument(skip_all)]
+async fn dequeue_tasks(
+    transaction: &mut PgTransaction,
+    batch_size: i64,
+) -> Result<Vec<DeliveryTask>, anyhow::Error> {
+    let tasks = sqlx::query_as!(
+        DeliveryTask,
+        r#"
+        SELECT
+            q.newsletter_issue_id,
+            q.subscriber_email,
+            q.n_retries,
+            s.id AS "subscriber_id?",
+            s.name AS "subscriber_name?"
+        FROM issue_delivery_queue q
+        LEFT JOIN subscriptions s ON s.email = q.subscriber_email AND s.status = 'confirmed'
+        WHERE q.execute_after <= now()
+        FOR UPDATE OF q
+        SKIP LOCKED
+        LIMIT $1
+        "#,
+        batch_size
+    )
+    .fetch_all(transaction)
+    .await?;
+    Ok(tasks)
+}
+
+#[tracing::instrument(skip_all)]
+async fn lease_tasks(
+    transaction: &mut PgTransaction,
+    tasks: &[DeliveryTask],
+) -> Result<(), anyhow::Error> {
+    let (issue_ids, emails): (Vec<_>, Vec<_>) = tasks
+        .iter()
+        .map(|task| (task.newsletter_issue_id, task.subscriber_email.clone()))
+        .unzip();
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET execute_after = now() + make_interval(secs => $3)
+        WHERE (newsletter_issue_id, subscriber_email) IN (
+            SELECT * FROM UNNEST($1::uuid[], $2::text[])
+        )
+        "#,
+        &issue_ids,
+        &emails,
+        LEASE.as_secs_f64()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+// Outside of a transaction: emails the provider accepted must not be sent again,
+// whatever happens to the rest of the batch.
+#[tracing::instrument(skip_all)]
+async fn delete_tasks(pool: &PgPool, tasks: &[&DeliveryTask]) -> Result<(), anyhow::Error> {
+    let (issue_ids, emails): (Vec<_>, Vec<_>) = tasks
+        .iter()
+        .map(|task| (task.newsletter_issue_id, task.subscriber_email.clone()))
+        .unzip();
+    sqlx::query!(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE (newsletter_issue_id, subscriber_email) IN (
+            SELECT * FROM UNNEST($1::uuid[], $2::text[])
+        )
+        "#,
+        &issue_ids,
+        &emails
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn schedule_retry(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    error: &anyhow::Error,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET
+            n_retries = n_retries + 1,
+            last_error = $3,
+            execute_after = now() + make_interval(secs => $4)
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        error.to_string(),
+        delay.as_secs_f64()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn defer_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    error: &anyhow::Error,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET
+            last_error = $3,
+            execute_after = now() + make_interval(secs => $4)
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        error.to_string(),
+        delay.as_secs_f64()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn dead_letter_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    n_attempts: i16,
+    error: &anyhow::Error,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_dead_letters (
+            newsletter_issue_id,
+            subscriber_email,
+            n_attempts,
+            last_error,
+            failed_at
+        )
+        VALUES ($1, $2, $3, $4, now())
+        ON CONFLICT (newsletter_issue_id, subscriber_email) DO UPDATE
+        SET
+            n_attempts = EXCLUDED.n_attempts,
+            last_error = EXCLUDED.last_error,
+            failed_at = EXCLUDED.failed_at
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        n_attempts,
+        error.to_string()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    delete_task(transaction, task).await
+}
+
+pub(crate) struct NewsletterIssue {
+    pub title: String,
+    text_content: NewsletterTemplate,
+    html_content: NewsletterTemplate,
+}
+
+/// The bodies of an issue as a given subscriber receives them.
+pub(crate) struct RenderedIssue {
+    pub html_content: String,
+    pub text_content: String,
+}
+
+impl NewsletterIssue {
+    pub(crate) fn render(&self, values: &MergeValues) -> RenderedIssue {
+        RenderedIssue {
+            html_content: render_html(&self.html_content, values),
+            text_content: render_text(&self.text_content, values),
+        }
+    }
+
+    /// The bodies without a footer, for emails that bring their own.
+    pub(crate) fn render_content(&self, values: &MergeValues) -> RenderedIssue {
+        RenderedIssue {
+            html_content: self.html_content.render_html(values),
+            text_content: self.text_content.render_text(values),
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+pub(crate) async fn get_issue(
+    pool: &PgPool,
+    issue_id: Uuid,
+) -> Result<Option<NewsletterIssue>, anyhow::Error> {
+    let issue = sqlx::query!(
+        r#"
+        SELECT title, text_content, html_content
+        FROM newsletter_issues
+        WHERE
+            newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(issue.map(|issue| NewsletterIssue {
+        title: issue.title,
+        text_content: parse_template(issue.text_content),
+        html_content: parse_template(issue.html_content),
+    }))
+}
+
+// Bodies are validated when an issue is published. Issues published before merge
+// fields existed may contain stray braces though: those are sent verbatim.
+fn parse_template(body: String) -> NewsletterTemplate {
+    NewsletterTemplate::parse(&body).unwrap_or_else(|_| NewsletterTemplate::literal(body))
+}
+
+async fn worker_loop(
+    pool: PgPool,
+    email_client: Arc<EmailClient>,
+    settings: IssueDeliverySettings,
+    links: SubscriberLinks,
+) -> Result<(), anyhow::Error> {
+    loop {
+        match try_execute_task(&pool, &email_client, &settings, &links).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+// Only returns if Postgres cannot be reached on startup: the loop retries its own
+// failures. Raced against the API server in `main`.
+pub async fn run_worker_until_stopped(
+    configuration: Settings,
+    email_client: Arc<EmailClient>,
+) -> Result<(), anyhow::Error> {
+    let connection_pool = Application::get_connection_pool(&configuration.database).await?;
+    let links = SubscriberLinks::new(
+        configuration.application.base_url,
+        HmacSecret(configuration.application.hmac_secret),
+    );
+    worker_loop(
+        connection_pool,
+        email_client,
+        configuration.issue_delivery,
+        links,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, html_with_footer, text_with_footer};
+    use std::time::Duration;
+
+    const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+    const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn backoff_doubles_with_every_retry() {
+        for n_retries in 0..3 {
+            let delay = backoff(n_retries, INITIAL_BACKOFF, MAX_BACKOFF);
+            let expected = Duration::from_millis(1000 * 2u64.pow(n_retries as u32));
+            assert!(delay >= expected / 2 && delay <= expected);
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let delay = backoff(i16::MAX, INITIAL_BACKOFF, MAX_BACKOFF);
+        assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(10));
+    }
+
+    #[test]
+    fn the_footer_goes_inside_the_html_body() {
+        let html = html_with_footer(
+            "<html><BODY><p>Hi</p></BODY></html>",
+            &[("Unsubscribe", "url")],
+        );
+        assert_eq!(
+            html,
+            r#"<html><BODY><p>Hi</p><p><a href="url">Unsubscribe</a></p></BODY></html>"#
+        );
+    }
+
+    #[test]
+    fn the_footer_is_appended_to_html_fragments() {
+        let html = html_with_footer("<p>Hi</p>", &[("Preferences", "a"), ("Unsubscribe", "b")]);
+        assert_eq!(
+            html,
+            r#"<p>Hi</p><p><a href="a">Preferences</a> | <a href="b">Unsubscribe</a></p>"#
+        );
+    }
+
+    #[test]
+    fn there_is_no_footer_without_links() {
+        assert_eq!(html_with_footer("<p>Hi</p>", &[]), "<p>Hi</p>");
+        assert_eq!(text_with_footer("Hi", &[]), "Hi");
+    }
+}
@@ -0,0 +1,336 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::configuration::{IssueDeliverySettings, Settings};
+use crate::domain::{DigestFrequency, MergeValues, SubscriberEmail};
+use crate::email_client::{EmailClient, EmailError};
+use crate::issue_delivery_worker::{
+    backoff, get_issue, html_with_footer, list_unsubscribe_headers, text_with_footer,
+    ExecutionOutcome, LEASE,
+};
+use crate::startup::{Application, HmacSecret};
+use crate::subscriber_links::SubscriberLinks;
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+struct DigestSubscriber {
+    id: Uuid,
+    email: String,
+    name: String,
+    digest_frequency: String,
+    last_digest_at: DateTime<Utc>,
+    digest_n_retries: i16,
+    dequeued_at: DateTime<Utc>,
+}
+
+/// Send their digest to one subscriber whose digest is due: every issue in their
+/// audience published since the previous one, in a single email.
+///
+/// The subscriber is leased rather than kept locked while the provider is called.
+/// A digest that fails with a transient error is tried again later, with the same
+/// backoff and number of attempts as single issues; an open circuit or a rate limit
+/// only pushes it back without counting an attempt. If the email provider rejects
+/// it outright, or once it runs out of attempts, those issues are skipped.
+#[tracing::instrument(skip_all, fields(subscriber_id=tracing::field::Empty), err)]
+pub async fn try_send_digest(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    settings: &IssueDeliverySettings,
+    links: &SubscriberLinks,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    let subscriber = match dequeue_subscriber(&mut transaction).await? {
+        Some(subscriber) => subscriber,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    tracing::Span::current().record("subscriber_id", tracing::field::display(subscriber.id));
+    let issue_ids = get_issues_since(&mut transaction, &subscriber).await?;
+    if issue_ids.is_empty() {
+        mark_digest_as_sent(&mut transaction, &subscriber).await?;
+        transaction.commit().await?;
+        return Ok(ExecutionOutcome::TaskCompleted);
+    }
+    postpone_digest(&mut transaction, subscriber.id, LEASE).await?;
+    transaction.commit().await?;
+
+    let outcome = send_digest(pool, email_client, links, &subscriber, &issue_ids).await;
+    let mut transaction = pool.begin().await?;
+    if let Err(e) = &outcome {
+        if let Some(delay) = e.retry_after() {
+            tracing::warn!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                retry_in_ms = delay.as_millis() as u64,
+                "Could not send a digest for now. Retrying later without counting an attempt.",
+            );
+            postpone_digest(&mut transaction, subscriber.id, delay).await?;
+            transaction.commit().await?;
+            return Ok(ExecutionOutcome::TaskCompleted);
+        }
+    }
+    match outcome {
+        Ok(()) => {}
+        Err(EmailError::Permanent(e)) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "The email provider rejected a digest. Skipping it.",
+            );
+        }
+        Err(e) if subscriber.digest_n_retries + 1 < settings.max_attempts => {
+            let delay = backoff(
+                subscriber.digest_n_retries,
+                settings.initial_backoff(),
+                settings.max_backoff(),
+            );
+            tracing::warn!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                n_attempts = subscriber.digest_n_retries + 1,
+                retry_in_ms = delay.as_millis() as u64,
+                "Failed to send a digest. Retrying later.",
+            );
+            schedule_retry(&mut transaction, subscriber.id, delay).await?;
+            transaction.commit().await?;
+            return Ok(ExecutionOutcome::TaskCompleted);
+        }
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                n_attempts = subscriber.digest_n_retries + 1,
+                "Failed to send a digest too many times. Skipping it.",
+            );
+        }
+    }
+    mark_digest_as_sent(&mut transaction, &subscriber).await?;
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+async fn send_digest(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    links: &SubscriberLinks,
+    subscriber: &DigestSubscriber,
+    issue_ids: &[Uuid],
+) -> Result<(), EmailError> {
+    let recipient = SubscriberEmail::parse(subscriber.email.clone())
+        .map_err(|e| EmailError::Permanent(anyhow::anyhow!(e)))?;
+    let unsubscribe_url = links.unsubscribe_url(subscriber.id);
+    let preferences_url = links.preferences_url(subscriber.id);
+    let mut html_sections = Vec::with_capacity(issue_ids.len());
+    let mut text_sections = Vec::with_capacity(issue_ids.len());
+    for issue_id in issue_ids {
+        let issue = get_issue(pool, *issue_id)
+            .await
+            .map_err(EmailError::Transient)?
+            .ok_or_else(|| EmailError::Transient(anyhow::anyhow!("An issue has vanished.")))?;
+        let issue_url = links.issue_url(*issue_id);
+        let rendered = issue.render_content(&MergeValues {
+            name: &subscriber.name,
+            unsubscribe_url: &unsubscribe_url,
+            preferences_url: &preferences_url,
+            issue_url: &issue_url,
+        });
+        html_sections.push(format!(
+            "<h1>{}</h1>{}",
+            htmlescape::encode_minimal(&issue.title),
+            rendered.html_content
+        ));
+        text_sections.push(format!("{}\n\n{}", issue.title, rendered.text_content));
+    }
+    // Digests always carry both links: each issue may or may not place them itself.
+    let footer = [
+        ("Manage your preferences", preferences_url.as_str()),
+        ("Unsubscribe", unsubscribe_url.as_str()),
+    ];
+    let html_content = html_with_footer(&html_sections.join("<hr>"), &footer);
+    let text_content = text_with_footer(&text_sections.join("\n\n---\n\n"), &footer);
+    let subject = match DigestFrequency::parse(&subscriber.digest_frequency) {
+        Ok(DigestFrequency::Monthly) => "Your monthly digest",
+        _ => "Your weekly digest",
+    };
+    email_client
+        .send_email_with_headers(
+            &recipient,
+            subject,
+            &html_content,
+            Some(&text_content),
+            &list_unsubscribe_headers(
+                email_client,
+                &links.one_click_unsubscribe_url(subscriber.id),
+            ),
+        )
+        .await
+}
+
+// `SKIP LOCKED` lets several senders run side by side without sending a digest twice.
+// The lock only lasts until the subscriber is leased with `postpone_digest`.
+#[tracing::instrument(skip_all)]
+async fn dequeue_subscriber(
+    transaction: &mut PgTransaction,
+) -> Result<Option<DigestSubscriber>, anyhow::Error> {
+    let subscriber = sqlx::query_as!(
+        DigestSubscriber,
+        r#"
+        SELECT
+            id,
+            email,
+            name,
+            digest_frequency,
+            last_digest_at as "last_digest_at!",
+            digest_n_retries,
+            now() as "dequeued_at!"
+        FROM subscriptions
+        WHERE
+            status = 'confirmed' AND
+            digest_frequency <> 'every_issue' AND
+            last_digest_at <= now() - CASE digest_frequency
+                WHEN 'weekly' THEN interval '7 days'
+                ELSE interval '1 month'
+            END AND
+            (digest_execute_after IS NULL OR digest_execute_after <= now())
+        ORDER BY last_digest_at
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT 1
+        "#,
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(subscriber)
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issues_since(
+    transaction: &mut PgTransaction,
+    subscriber: &DigestSubscriber,
+) -> Result<Vec<Uuid>, anyhow::Error> {
+    let issues = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id
+        FROM newsletter_issues i
+        WHERE
+            i.status IN ('sending', 'sent') AND
+            i.published_at > $2 AND
+            in_audience($1, i.include_lists, i.include_tags, i.exclude_lists, i.exclude_tags)
+        ORDER BY i.published_at
+        "#,
+        subscriber.id,
+        subscriber.last_digest_at,
+    )
+    .fetch_all(transaction)
+    .await?;
+    Ok(issues.into_iter().map(|i| i.newsletter_issue_id).collect())
+}
+
+#[tracing::instrument(skip_all)]
+async fn mark_digest_as_sent(
+    transaction: &mut PgTransaction,
+    subscriber: &DigestSubscriber,
+) -> Result<(), anyhow::Error> {
+    // Up to when the issues were looked up: anything published while the digest
+    // was being sent goes into the next one.
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET
+            last_digest_at = $2,
+            digest_n_retries = 0,
+            digest_execute_after = NULL
+        WHERE id = $1
+        "#,
+        subscriber.id,
+        subscriber.dequeued_at,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+// Hide the subscriber from other senders for `delay`, without counting an attempt.
+#[tracing::instrument(skip_all)]
+async fn postpone_digest(
+    transaction: &mut PgTransaction,
+    subscriber_id: Uuid,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET digest_execute_after = now() + make_interval(secs => $2)
+        WHERE id = $1
+        "#,
+        subscriber_id,
+        delay.as_secs_f64()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn schedule_retry(
+    transaction: &mut PgTransaction,
+    subscriber_id: Uuid,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET
+            digest_n_retries = digest_n_retries + 1,
+            digest_execute_after = now() + make_interval(secs => $2)
+        WHERE id = $1
+        "#,
+        subscriber_id,
+        delay.as_secs_f64()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+async fn digest_loop(
+    pool: PgPool,
+    email_client: Arc<EmailClient>,
+    settings: IssueDeliverySettings,
+    links: SubscriberLinks,
+) -> Result<(), anyhow::Error> {
+    loop {
+        match try_send_digest(&pool, &email_client, &settings, &links).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+// Only returns if Postgres cannot be reached on startup: the loop retries its own
+// failures. Raced against the API server in `main`.
+pub async fn run_digest_sender_until_stopped(
+    configuration: Settings,
+    email_client: Arc<EmailClient>,
+) -> Result<(), anyhow::Error> {
+    let connection_pool = Application::get_connection_pool(&configuration.database).await?;
+    let links = SubscriberLinks::new(
+        configuration.application.base_url,
+        HmacSecret(configuration.application.hmac_secret),
+    );
+    digest_loop(
+        connection_pool,
+        email_client,
+        configuration.issue_delivery,
+        links,
+    )
+    .await
+}
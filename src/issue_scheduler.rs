@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use crate::configuration::Settings;
+use crate::issue_delivery_worker::ExecutionOutcome;
+use crate::routes::enqueue_delivery_tasks;
+use crate::startup::Application;
+
+/// Move one scheduled issue whose time has come from `scheduled` to `sending`,
+/// queueing a delivery task per confirmed subscriber in its audience.
+///
+/// Once there is nothing left to publish, issues whose deliveries have all been
+/// processed are marked as `sent`.
+#[tracing::instrument(skip_all, fields(newsletter_issue_id=tracing::field::Empty), err)]
+pub async fn try_publish_due_issue(pool: &PgPool) -> Result<ExecutionOutcome, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    // `SKIP LOCKED` lets several schedulers run side by side without publishing an
+    // issue twice.
+    let due_issue = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id
+        FROM newsletter_issues
+        WHERE status = 'scheduled' AND scheduled_for <= now()
+        ORDER BY scheduled_for
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT 1
+        "#
+    )
+    .fetch_optional(&mut transaction)
+    .await?;
+    let newsletter_issue_id = match due_issue {
+        Some(issue) => issue.newsletter_issue_id,
+        None => {
+            mark_delivered_issues_as_sent(pool).await?;
+            return Ok(ExecutionOutcome::EmptyQueue);
+        }
+    };
+    tracing::Span::current().record(
+        "newsletter_issue_id",
+        tracing::field::display(newsletter_issue_id),
+    );
+    enqueue_delivery_tasks(&mut transaction, newsletter_issue_id).await?;
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = 'sending', published_at = now(), updated_at = now()
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+// Failed deliveries are out of the queue too: they live in the dead letters.
+#[tracing::instrument(skip_all)]
+async fn mark_delivered_issues_as_sent(pool: &PgPool) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues i
+        SET status = 'sent', updated_at = now()
+        WHERE
+            i.status = 'sending' AND
+            NOT EXISTS (
+                SELECT 1 FROM issue_delivery_queue q
+                WHERE q.newsletter_issue_id = i.newsletter_issue_id
+            )
+        "#
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn scheduler_loop(pool: PgPool) -> Result<(), anyhow::Error> {
+    loop {
+        match try_publish_due_issue(&pool).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+// Only returns if Postgres cannot be reached on startup: the loop retries its own
+// failures. Raced against the API server in `main`.
+pub async fn run_scheduler_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
+    let connection_pool = Application::get_connection_pool(&configuration.database).await?;
+    scheduler_loop(connection_pool).await
+}
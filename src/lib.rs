@@ -1,9 +1,19 @@
 pub mod authentication;
 pub mod configuration;
+pub mod confirmation_email_outbox;
+pub mod csv_records;
 pub mod domain;
 pub mod email_client;
+pub mod idempotency;
+pub mod issue_delivery_worker;
+pub mod issue_digest;
+pub mod issue_scheduler;
+pub mod lists;
+pub mod markdown;
 pub mod routes;
 pub mod session_state;
 pub mod startup;
+pub mod subscriber_links;
+pub mod subscription_cleanup;
 pub mod telemetry;
 pub mod utils;
@@ -0,0 +1,57 @@
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// The names among `names` that do not match any list.
+#[tracing::instrument(skip(pool))]
+pub async fn find_unknown_lists(
+    pool: &PgPool,
+    names: &[String],
+) -> Result<Vec<String>, sqlx::Error> {
+    let unknown = sqlx::query!(
+        r#"
+        SELECT wanted.name as "name!"
+        FROM unnest($1::text[]) AS wanted(name)
+        WHERE NOT EXISTS (SELECT 1 FROM lists l WHERE l.name = wanted.name)
+        "#,
+        names,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(unknown.into_iter().map(|r| r.name).collect())
+}
+
+/// Replace the lists a subscriber belongs to with the ones named in `names`.
+/// Unknown names are ignored.
+#[tracing::instrument(skip(transaction))]
+pub async fn set_subscriber_lists(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    names: &[String],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM subscription_lists
+        WHERE subscriber_id = $1
+        "#,
+        subscriber_id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_lists (subscriber_id, list_id)
+        SELECT $1, list_id
+        FROM lists
+        WHERE name = ANY($2)
+        "#,
+        subscriber_id,
+        names,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+pub fn unknown_lists_error(unknown: &[String]) -> String {
+    format!("There is no list named {}.", unknown.join(", "))
+}
@@ -1,5 +1,14 @@
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+
+use tokio::task::JoinError;
 use z2p::configuration::get_configuration;
+use z2p::confirmation_email_outbox::run_dispatcher_until_stopped;
+use z2p::issue_delivery_worker::run_worker_until_stopped;
+use z2p::issue_digest::run_digest_sender_until_stopped;
+use z2p::issue_scheduler::run_scheduler_until_stopped;
 use z2p::startup::Application;
+use z2p::subscription_cleanup::run_cleanup_until_stopped;
 use z2p::telemetry::{get_subscriber, initialize_subscriber};
 
 #[tokio::main]
@@ -7,7 +16,56 @@ async fn main() -> anyhow::Result<()> {
     let subscriber = get_subscriber("z2p".into(), "info".into(), std::io::stdout);
     initialize_subscriber(subscriber);
     let configuration = get_configuration().expect("Error reading configurations");
-    let application = Application::build(configuration).await?;
-    application.run_server_until_stopped().await?;
+    // One client for everything that sends email: they share its rate limit, and its
+    // circuit breaker is the one `/healthz` reports on
+    let email_client = Arc::new(configuration.email_client.clone().client());
+    let application = Application::build(configuration.clone(), email_client.clone()).await?;
+    let application_task = tokio::spawn(application.run_server_until_stopped());
+    let worker_task = tokio::spawn(run_worker_until_stopped(
+        configuration.clone(),
+        email_client.clone(),
+    ));
+    let scheduler_task = tokio::spawn(run_scheduler_until_stopped(configuration.clone()));
+    let digest_task = tokio::spawn(run_digest_sender_until_stopped(
+        configuration.clone(),
+        email_client.clone(),
+    ));
+    let dispatcher_task = tokio::spawn(run_dispatcher_until_stopped(
+        configuration.clone(),
+        email_client,
+    ));
+    let cleanup_task = tokio::spawn(run_cleanup_until_stopped(configuration));
+    tokio::select! {
+        o = application_task => report_exit("API", o),
+        o = worker_task => report_exit("Background worker", o),
+        o = scheduler_task => report_exit("Issue scheduler", o),
+        o = digest_task => report_exit("Digest sender", o),
+        o = dispatcher_task => report_exit("Confirmation email dispatcher", o),
+        o = cleanup_task => report_exit("Subscription cleanup", o),
+    };
     Ok(())
 }
+
+fn report_exit(task_name: &str, outcome: Result<Result<(), impl Debug + Display>, JoinError>) {
+    match outcome {
+        Ok(Ok(())) => {
+            tracing::info!("{} has exited", task_name)
+        }
+        Ok(Err(e)) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "{} failed",
+                task_name
+            )
+        }
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "{} task failed to complete",
+                task_name
+            )
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+
+/// A newsletter issue written in Markdown, rendered to both of the bodies an email
+/// needs so that they can never drift apart.
+pub struct RenderedMarkdown {
+    pub html: String,
+    pub text: String,
+}
+
+pub fn render(markdown: &str) -> RenderedMarkdown {
+    RenderedMarkdown {
+        html: render_html(markdown),
+        text: render_text(markdown),
+    }
+}
+
+fn parser(markdown: &str) -> Parser<'_, '_> {
+    Parser::new_ext(
+        markdown,
+        Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES,
+    )
+}
+
+// Markdown lets authors embed raw HTML: whatever it produces goes through a
+// whitelist before it reaches anybody's inbox.
+fn render_html(markdown: &str) -> String {
+    let mut unsafe_html = String::new();
+    // pulldown-cmark percent-encodes link targets, which would hide a merge field
+    // such as `[read it online]({{issue_url}})` from the template engine.
+    let events = parser(markdown).map(|event| match event {
+        Event::Start(Tag::Link(_, url, _)) if url.contains("{{") => {
+            Event::Html(format!(r#"<a href="{}">"#, htmlescape::encode_minimal(&url)).into())
+        }
+        Event::End(Tag::Link(_, url, _)) if url.contains("{{") => Event::Html("</a>".into()),
+        event => event,
+    });
+    html::push_html(&mut unsafe_html, events);
+    ammonia::clean(&unsafe_html)
+}
+
+/// A readable plain-text version: markup is dropped, list items get a bullet or
+/// their number, and link targets follow the link text in parentheses.
+fn render_text(markdown: &str) -> String {
+    let mut text = String::new();
+    // The next number of each ordered list we are in, `None` for bullet lists
+    let mut lists: Vec<Option<u64>> = Vec::new();
+    let mut link_targets = Vec::new();
+    for event in parser(markdown) {
+        match event {
+            Event::Start(Tag::List(first_number)) => {
+                if lists.is_empty() {
+                    end_block(&mut text);
+                }
+                lists.push(first_number);
+            }
+            Event::End(Tag::List(_)) => {
+                lists.pop();
+                if lists.is_empty() {
+                    text.push('\n');
+                }
+            }
+            Event::Start(Tag::Item) => {
+                if !text.is_empty() && !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                text.push_str(&"  ".repeat(lists.len().saturating_sub(1)));
+                match lists.last_mut() {
+                    Some(Some(n)) => {
+                        text.push_str(&format!("{}. ", n));
+                        *n += 1;
+                    }
+                    _ => text.push_str("- "),
+                }
+            }
+            Event::End(Tag::Item) if !text.ends_with('\n') => text.push('\n'),
+            Event::Start(
+                Tag::Paragraph | Tag::Heading(..) | Tag::BlockQuote | Tag::CodeBlock(_),
+            ) if lists.is_empty() => end_block(&mut text),
+            Event::Start(Tag::Link(_, url, _) | Tag::Image(_, url, _)) => {
+                link_targets.push((url, text.len()))
+            }
+            Event::End(Tag::Link(..) | Tag::Image(..)) => {
+                if let Some((url, start)) = link_targets.pop() {
+                    // Autolinks already show their target
+                    if text[start..] != *url {
+                        text.push_str(&format!(" ({})", url));
+                    }
+                }
+            }
+            Event::Text(s) | Event::Code(s) => text.push_str(&s),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::Rule => {
+                end_block(&mut text);
+                text.push_str("---");
+            }
+            Event::TaskListMarker(done) => text.push_str(if done { "[x] " } else { "[ ] " }),
+            _ => {}
+        }
+    }
+    text.trim().to_string()
+}
+
+// Leave an empty line between two blocks
+fn end_block(text: &mut String) {
+    if text.is_empty() {
+        return;
+    }
+    while !text.ends_with("\n\n") {
+        text.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+
+    #[test]
+    fn markdown_is_rendered_to_html() {
+        let rendered = render("# Title\n\nSome *emphasis*.");
+        assert_eq!(
+            rendered.html,
+            "<h1>Title</h1>\n<p>Some <em>emphasis</em>.</p>\n"
+        );
+    }
+
+    #[test]
+    fn dangerous_html_is_stripped() {
+        let rendered = render("Hi <script>alert(1)</script><a href=\"javascript:alert(1)\">x</a>");
+        assert!(!rendered.html.contains("<script"));
+        assert!(!rendered.html.contains("javascript:"));
+    }
+
+    #[test]
+    fn paragraphs_are_separated_by_an_empty_line() {
+        let rendered = render("# Title\n\nFirst paragraph.\n\nSecond paragraph.");
+        assert_eq!(
+            rendered.text,
+            "Title\n\nFirst paragraph.\n\nSecond paragraph."
+        );
+    }
+
+    #[test]
+    fn list_items_get_bullets_or_numbers() {
+        let rendered = render("Intro\n\n* one\n* two\n\n3. three\n4. four");
+        assert_eq!(rendered.text, "Intro\n\n- one\n- two\n\n3. three\n4. four");
+    }
+
+    #[test]
+    fn link_targets_follow_the_link_text() {
+        let rendered = render("Read [the docs](https://example.com/docs) or <https://example.com>");
+        assert_eq!(
+            rendered.text,
+            "Read the docs (https://example.com/docs) or https://example.com"
+        );
+    }
+
+    #[test]
+    fn merge_fields_survive_rendering() {
+        let rendered = render("Hi {{ name }}, [read it online]({{issue_url}})");
+        assert!(rendered.html.contains("Hi {{ name }}"));
+        assert!(rendered.html.contains(r#"href="{{issue_url}}""#));
+        assert_eq!(
+            rendered.text,
+            "Hi {{ name }}, read it online ({{issue_url}})"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use anyhow::Context;
+use htmlescape::encode_minimal;
+use sqlx::PgPool;
+use std::fmt::Write;
+
+use crate::utils::e500;
+
+/// The lists and tags issues can be addressed to, with how many confirmed
+/// subscribers each of them reaches.
+pub async fn audience(
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", encode_minimal(m.content())).unwrap();
+    }
+    let mut lists_html = String::new();
+    for (name, n_subscribers) in get_lists(&pool).await.map_err(e500)? {
+        writeln!(
+            lists_html,
+            "<tr><td>{}</td><td>{}</td></tr>",
+            encode_minimal(&name),
+            n_subscribers
+        )
+        .unwrap();
+    }
+    let mut tags_html = String::new();
+    for (tag, n_subscribers) in get_tags(&pool).await.map_err(e500)? {
+        writeln!(
+            tags_html,
+            "<tr><td>{}</td><td>{}</td></tr>",
+            encode_minimal(&tag),
+            n_subscribers
+        )
+        .unwrap();
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Lists and tags</title>
+				</head>
+				<body>
+					{msg_html}
+					<h1>Lists</h1>
+					<table>
+						<tr><th>Name</th><th>Confirmed subscribers</th></tr>
+						{lists_html}
+					</table>
+					<form action="/admin/audience/lists" method="post">
+						<label>Name
+							<input type="text" name="name">
+						</label>
+						<button type="submit">Create list</button>
+					</form>
+					<h1>Tags</h1>
+					<table>
+						<tr><th>Tag</th><th>Confirmed subscribers</th></tr>
+						{tags_html}
+					</table>
+					<form action="/admin/audience/tags" method="post">
+						<label>Subscriber email
+							<input type="email" name="email">
+						</label>
+						<label>Tag
+							<input type="text" name="tag">
+						</label>
+						<button type="submit">Tag</button>
+						<button type="submit" formaction="/admin/audience/tags/remove">Untag</button>
+					</form>
+					<p><a href="/admin/dashboard">&lt;- Back</a></p>
+				</body>
+				</html>
+				"#
+        )))
+}
+
+#[tracing::instrument(name = "Get lists", skip(pool))]
+async fn get_lists(pool: &PgPool) -> Result<Vec<(String, i64)>, anyhow::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT l.name, COUNT(s.id) as "n_subscribers!"
+        FROM lists l
+        LEFT JOIN subscription_lists sl ON sl.list_id = l.list_id
+        LEFT JOIN subscriptions s ON s.id = sl.subscriber_id AND s.status = 'confirmed'
+        GROUP BY l.name
+        ORDER BY l.name
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to retrieve the lists.")?;
+    Ok(rows
+        .into_iter()
+        .map(|r| (r.name, r.n_subscribers))
+        .collect())
+}
+
+#[tracing::instrument(name = "Get tags", skip(pool))]
+async fn get_tags(pool: &PgPool) -> Result<Vec<(String, i64)>, anyhow::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT t.tag, COUNT(s.id) as "n_subscribers!"
+        FROM subscriber_tags t
+        LEFT JOIN subscriptions s ON s.id = t.subscriber_id AND s.status = 'confirmed'
+        GROUP BY t.tag
+        ORDER BY t.tag
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to retrieve the tags.")?;
+    Ok(rows.into_iter().map(|r| (r.tag, r.n_subscribers)).collect())
+}
@@ -0,0 +1,5 @@
+mod get;
+mod post;
+
+pub use get::audience;
+pub use post::{create_list, tag_subscriber, untag_subscriber};
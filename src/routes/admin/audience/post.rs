@@ -0,0 +1,119 @@
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::domain::parse_label;
+use crate::utils::{e500, see_other};
+
+#[derive(serde::Deserialize)]
+pub struct ListFormData {
+    name: String,
+}
+
+pub async fn create_list(
+    form: web::Form<ListFormData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let name = match parse_label(form.0.name) {
+        Ok(name) => name,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other("/admin/audience"));
+        }
+    };
+    let n_inserted = sqlx::query!(
+        r#"
+        INSERT INTO lists (list_id, name, created_at)
+        VALUES ($1, $2, now())
+        ON CONFLICT (name) DO NOTHING
+        "#,
+        Uuid::new_v4(),
+        name
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to create the list.")
+    .map_err(e500)?
+    .rows_affected();
+    if n_inserted == 0 {
+        FlashMessage::error(format!("There is already a list named {}.", name)).send();
+    } else {
+        FlashMessage::info(format!("The list {} has been created.", name)).send();
+    }
+    Ok(see_other("/admin/audience"))
+}
+
+#[derive(serde::Deserialize)]
+pub struct TagFormData {
+    email: String,
+    tag: String,
+}
+
+pub async fn tag_subscriber(
+    form: web::Form<TagFormData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let TagFormData { email, tag } = form.0;
+    let tag = match parse_label(tag) {
+        Ok(tag) => tag,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other("/admin/audience"));
+        }
+    };
+    let n_matched = sqlx::query!(
+        r#"
+        INSERT INTO subscriber_tags (subscriber_id, tag)
+        SELECT id, $2
+        FROM subscriptions
+        WHERE email = $1
+        ON CONFLICT DO NOTHING
+        "#,
+        email.trim(),
+        tag
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to tag the subscriber.")
+    .map_err(e500)?
+    .rows_affected();
+    if n_matched == 0 {
+        FlashMessage::error(format!(
+            "{} is not subscribed or is already tagged {}.",
+            email, tag
+        ))
+        .send();
+    } else {
+        FlashMessage::info(format!("{} has been tagged {}.", email, tag)).send();
+    }
+    Ok(see_other("/admin/audience"))
+}
+
+pub async fn untag_subscriber(
+    form: web::Form<TagFormData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let TagFormData { email, tag } = form.0;
+    let n_deleted = sqlx::query!(
+        r#"
+        DELETE FROM subscriber_tags t
+        USING subscriptions s
+        WHERE s.id = t.subscriber_id AND s.email = $1 AND t.tag = $2
+        "#,
+        email.trim(),
+        tag.trim()
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to untag the subscriber.")
+    .map_err(e500)?
+    .rows_affected();
+    if n_deleted == 0 {
+        FlashMessage::error(format!("{} is not tagged {}.", email, tag)).send();
+    } else {
+        FlashMessage::info(format!("{} is not tagged {} anymore.", email, tag)).send();
+    }
+    Ok(see_other("/admin/audience"))
+}
@@ -1,24 +1,16 @@
-use actix_web::{
-    http::header::{ContentType, LOCATION},
-    web, HttpResponse,
-};
+use actix_web::{http::header::ContentType, web, HttpResponse};
 use anyhow::Context;
 use sqlx::PgPool;
 use uuid::Uuid;
 
-use crate::{session_state::TypedSession, utils::e500};
+use crate::{authentication::UserId, utils::e500};
 
 pub async fn admin_dashboard(
     pool: web::Data<PgPool>,
-    session: TypedSession,
+    user_id: web::ReqData<UserId>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let username = if let Some(user_id) = session.get_user_id().map_err(e500)? {
-        get_username(user_id, &pool).await.map_err(e500)?
-    } else {
-        return Ok(HttpResponse::SeeOther()
-            .insert_header((LOCATION, "/login"))
-            .finish());
-    };
+    let user_id = user_id.into_inner();
+    let username = get_username(*user_id, &pool).await.map_err(e500)?;
     Ok(HttpResponse::Ok()
         .content_type(ContentType::html())
         .body(format!(
@@ -31,6 +23,20 @@ pub async fn admin_dashboard(
 						</head>
 						<body>
 								<p>Welcome {username}!</p>
+								<p>Available actions:</p>
+								<ol>
+										<li><a href="/admin/newsletters">Newsletter issues</a></li>
+										<li><a href="/admin/audience">Lists and tags</a></li>
+										<li><a href="/admin/subscribers/import">Import subscribers</a></li>
+										<li><a href="/admin/password">Change password</a></li>
+										<li><a href="/admin/email">Email address for test copies</a></li>
+										<li><a href="/admin/deliveries/failed">Failed deliveries</a></li>
+										<li>
+												<form name="logoutForm" action="/admin/logout" method="post">
+														<input type="submit" value="Logout">
+												</form>
+										</li>
+								</ol>
 						</body>
 						</html>
 						"#
@@ -52,3 +58,19 @@ pub async fn get_username(user_id: Uuid, pool: &PgPool) -> Result<String, anyhow
     .context("Failed to perform a query to retrieve a username.")?;
     Ok(row.username)
 }
+
+#[tracing::instrument(name = "Get email", skip(pool))]
+pub async fn get_email(user_id: Uuid, pool: &PgPool) -> Result<Option<String>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT email
+        FROM users
+        WHERE user_id = $1
+        "#,
+        user_id,
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to perform a query to retrieve an email.")?;
+    Ok(row.email)
+}
@@ -0,0 +1,112 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use anyhow::Context;
+use htmlescape::encode_minimal;
+use sqlx::PgPool;
+use std::fmt::Write;
+
+use crate::utils::e500;
+
+pub async fn failed_deliveries(
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", encode_minimal(m.content())).unwrap();
+    }
+    let dead_letters = get_dead_letters(&pool).await.map_err(e500)?;
+    let mut rows_html = String::new();
+    for d in &dead_letters {
+        writeln!(
+            rows_html,
+            r#"<tr>
+					<td>{title}</td>
+					<td>{email}</td>
+					<td>{n_attempts}</td>
+					<td>{last_error}</td>
+					<td>{failed_at}</td>
+					<td>
+						<form action="/admin/deliveries/failed/requeue" method="post">
+							<input hidden type="text" name="newsletter_issue_id" value="{issue_id}">
+							<input hidden type="text" name="subscriber_email" value="{email}">
+							<button type="submit">Requeue</button>
+						</form>
+					</td>
+				</tr>"#,
+            title = encode_minimal(&d.title),
+            email = encode_minimal(&d.subscriber_email),
+            n_attempts = d.n_attempts,
+            last_error = encode_minimal(&d.last_error),
+            failed_at = d.failed_at.to_rfc3339(),
+            issue_id = d.newsletter_issue_id,
+        )
+        .unwrap();
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Failed deliveries</title>
+				</head>
+				<body>
+					{msg_html}
+					<p>{n_failed} failed deliveries.</p>
+					<table>
+						<tr>
+							<th>Issue</th>
+							<th>Subscriber</th>
+							<th>Attempts</th>
+							<th>Last error</th>
+							<th>Failed at</th>
+							<th></th>
+						</tr>
+						{rows_html}
+					</table>
+					<form action="/admin/deliveries/failed/requeue-all" method="post">
+						<button type="submit">Requeue all</button>
+					</form>
+					<p><a href="/admin/dashboard">&lt;- Back</a></p>
+				</body>
+				</html>
+				"#,
+            n_failed = dead_letters.len(),
+        )))
+}
+
+struct DeadLetter {
+    newsletter_issue_id: uuid::Uuid,
+    title: String,
+    subscriber_email: String,
+    n_attempts: i16,
+    last_error: String,
+    failed_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[tracing::instrument(name = "Get failed deliveries", skip(pool))]
+async fn get_dead_letters(pool: &PgPool) -> Result<Vec<DeadLetter>, anyhow::Error> {
+    let dead_letters = sqlx::query_as!(
+        DeadLetter,
+        r#"
+        SELECT
+            d.newsletter_issue_id,
+            i.title,
+            d.subscriber_email,
+            d.n_attempts,
+            d.last_error,
+            d.failed_at
+        FROM issue_delivery_dead_letters d
+        JOIN newsletter_issues i ON i.newsletter_issue_id = d.newsletter_issue_id
+        ORDER BY d.failed_at DESC
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to retrieve failed deliveries.")?;
+    Ok(dead_letters)
+}
@@ -0,0 +1,80 @@
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use anyhow::Context;
+use sqlx::PgPool;
+
+use crate::utils::{e500, see_other};
+
+#[derive(serde::Deserialize)]
+pub struct FormData {
+    newsletter_issue_id: uuid::Uuid,
+    subscriber_email: String,
+}
+
+pub async fn requeue_failed_delivery(
+    form: web::Form<FormData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let n_requeued = requeue_dead_letters(&pool, Some(&form.0))
+        .await
+        .map_err(e500)?;
+    if n_requeued == 0 {
+        FlashMessage::error("The delivery could not be found.").send();
+    } else {
+        FlashMessage::info("The delivery has been requeued.").send();
+    }
+    Ok(see_other("/admin/deliveries/failed"))
+}
+
+pub async fn requeue_all_failed_deliveries(
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let n_requeued = requeue_dead_letters(&pool, None).await.map_err(e500)?;
+    FlashMessage::info(format!("{} deliveries have been requeued.", n_requeued)).send();
+    Ok(see_other("/admin/deliveries/failed"))
+}
+
+// Move dead letters back to `issue_delivery_queue` with a fresh attempt budget.
+// `None` requeues every dead letter.
+#[tracing::instrument(name = "Requeue failed deliveries", skip(pool, delivery))]
+async fn requeue_dead_letters(
+    pool: &PgPool,
+    delivery: Option<&FormData>,
+) -> Result<u64, anyhow::Error> {
+    let (issue_id, email) = match delivery {
+        Some(d) => (
+            Some(d.newsletter_issue_id),
+            Some(d.subscriber_email.as_str()),
+        ),
+        None => (None, None),
+    };
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let n_requeued = sqlx::query!(
+        r#"
+        WITH requeued AS (
+            DELETE FROM issue_delivery_dead_letters
+            WHERE
+                ($1::uuid IS NULL OR newsletter_issue_id = $1) AND
+                ($2::text IS NULL OR subscriber_email = $2)
+            RETURNING newsletter_issue_id, subscriber_email
+        )
+        INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email)
+        SELECT newsletter_issue_id, subscriber_email FROM requeued
+        ON CONFLICT DO NOTHING
+        "#,
+        issue_id,
+        email
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to requeue failed deliveries.")?
+    .rows_affected();
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction.")?;
+    Ok(n_requeued)
+}
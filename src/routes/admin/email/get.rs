@@ -0,0 +1,47 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use htmlescape::encode_minimal;
+use sqlx::PgPool;
+use std::fmt::Write;
+
+use crate::authentication::UserId;
+use crate::routes::admin::dashboard::get_email;
+use crate::utils::e500;
+
+pub async fn admin_email_form(
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let email = get_email(**user_id, &pool).await.map_err(e500)?;
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", encode_minimal(m.content())).unwrap();
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Your email address</title>
+				</head>
+				<body>
+					{msg_html}
+					<p>Test copies of newsletter issues are sent to this address unless you pick another one.</p>
+					<form action="/admin/email" method="post">
+						<label>Email address
+							<input type="email" name="email" value="{email}">
+						</label>
+						<button type="submit">Save</button>
+					</form>
+					<p><a href="/admin/dashboard">&lt;- Back</a></p>
+				</body>
+				</html>
+				"#,
+            email = encode_minimal(email.as_deref().unwrap_or_default()),
+        )))
+}
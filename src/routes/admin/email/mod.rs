@@ -0,0 +1,5 @@
+mod get;
+mod post;
+
+pub use get::admin_email_form;
+pub use post::change_admin_email;
@@ -0,0 +1,49 @@
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use anyhow::Context;
+use sqlx::PgPool;
+
+use crate::authentication::UserId;
+use crate::domain::SubscriberEmail;
+use crate::utils::{e500, see_other};
+
+#[derive(serde::Deserialize)]
+pub struct FormData {
+    email: String,
+}
+
+/// Set the address test copies go to by default. An empty address removes it.
+pub async fn change_admin_email(
+    form: web::Form<FormData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let email = match form.0.email.trim() {
+        "" => None,
+        email => match SubscriberEmail::parse(email.to_string()) {
+            Ok(email) => Some(email),
+            Err(e) => {
+                FlashMessage::error(e).send();
+                return Ok(see_other("/admin/email"));
+            }
+        },
+    };
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET email = $2
+        WHERE user_id = $1
+        "#,
+        **user_id,
+        email.as_ref().map(|email| email.as_ref())
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to update the email of the user.")
+    .map_err(e500)?;
+    match email {
+        Some(_) => FlashMessage::info("Your email address has been saved.").send(),
+        None => FlashMessage::info("Your email address has been removed.").send(),
+    }
+    Ok(see_other("/admin/email"))
+}
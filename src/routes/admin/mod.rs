@@ -1,5 +1,17 @@
+mod audience;
 mod dashboard;
+mod deliveries;
+mod email;
+mod logout;
+mod newsletters;
 mod password;
+mod subscribers;
 
+pub use audience::*;
 pub use dashboard::admin_dashboard;
+pub use deliveries::*;
+pub use email::*;
+pub use logout::log_out;
+pub use newsletters::*;
 pub use password::*;
+pub use subscribers::*;
@@ -0,0 +1,346 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use anyhow::Context;
+use htmlescape::encode_minimal;
+use sqlx::PgPool;
+use std::fmt::Write;
+use uuid::Uuid;
+
+use crate::authentication::UserId;
+use crate::domain::MergeValues;
+use crate::issue_delivery_worker::get_issue;
+use crate::routes::admin::dashboard::{get_email, get_username};
+use crate::subscriber_links::SubscriberLinks;
+use crate::utils::{e500, see_other};
+
+pub async fn newsletter_issues(
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", encode_minimal(m.content())).unwrap();
+    }
+    let issues = get_issue_summaries(&pool).await.map_err(e500)?;
+    let mut rows_html = String::new();
+    for issue in &issues {
+        let actions = match issue.status.as_str() {
+            "draft" => format!(
+                r#"<a href="/admin/newsletters/{id}/edit">Edit</a>
+						<a href="/admin/newsletters/{id}/preview">Preview</a>"#,
+                id = issue.newsletter_issue_id
+            ),
+            "scheduled" => format!(
+                r#"<a href="/admin/newsletters/{id}/preview">Preview</a>
+						<form action="/admin/newsletters/{id}/unschedule" method="post">
+							<button type="submit">Back to draft</button>
+						</form>"#,
+                id = issue.newsletter_issue_id
+            ),
+            _ => format!(
+                r#"<a href="/admin/newsletters/{id}/preview">Preview</a>"#,
+                id = issue.newsletter_issue_id
+            ),
+        };
+        writeln!(
+            rows_html,
+            r#"<tr>
+					<td>{title}</td>
+					<td>{status}</td>
+					<td>{scheduled_for}</td>
+					<td>{published_at}</td>
+					<td>{actions}</td>
+				</tr>"#,
+            title = encode_minimal(&issue.title),
+            status = issue.status,
+            scheduled_for = issue
+                .scheduled_for
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_default(),
+            published_at = issue
+                .published_at
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_default(),
+        )
+        .unwrap();
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Newsletter issues</title>
+				</head>
+				<body>
+					{msg_html}
+					<p><a href="/admin/newsletters/new">Write a new issue</a></p>
+					<table>
+						<tr>
+							<th>Title</th>
+							<th>Status</th>
+							<th>Scheduled for</th>
+							<th>Published at</th>
+							<th></th>
+						</tr>
+						{rows_html}
+					</table>
+					<p><a href="/admin/dashboard">&lt;- Back</a></p>
+				</body>
+				</html>
+				"#
+        )))
+}
+
+pub async fn new_draft_form(
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", encode_minimal(m.content())).unwrap();
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>New issue</title>
+				</head>
+				<body>
+					{msg_html}
+					{form}
+					<p><a href="/admin/newsletters">&lt;- Back</a></p>
+				</body>
+				</html>
+				"#,
+            form = draft_form("/admin/newsletters", "", "", ""),
+        )))
+}
+
+pub async fn edit_draft_form(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let draft = match get_draft(&pool, newsletter_issue_id).await.map_err(e500)? {
+        Some(draft) => draft,
+        None => {
+            FlashMessage::error("Only drafts can be edited.").send();
+            return Ok(see_other("/admin/newsletters"));
+        }
+    };
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", encode_minimal(m.content())).unwrap();
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Edit issue</title>
+				</head>
+				<body>
+					{msg_html}
+					{form}
+					<p><a href="/admin/newsletters/{id}/preview">Preview</a></p>
+					<form action="/admin/newsletters/{id}/schedule" method="post">
+						<label>Send at (UTC)
+							<input type="datetime-local" name="scheduled_for">
+						</label>
+						<p>Audience, as comma-separated names. Leave the first two empty to send to every subscriber.</p>
+						<label>Lists
+							<input type="text" name="lists" value="{lists}">
+						</label>
+						<label>Tags
+							<input type="text" name="tags" value="{tags}">
+						</label>
+						<label>Except lists
+							<input type="text" name="exclude_lists" value="{exclude_lists}">
+						</label>
+						<label>Except tags
+							<input type="text" name="exclude_tags" value="{exclude_tags}">
+						</label>
+						<button type="submit">Schedule</button>
+					</form>
+					<p><a href="/admin/newsletters">&lt;- Back</a></p>
+				</body>
+				</html>
+				"#,
+            form = draft_form(
+                &format!("/admin/newsletters/{}/edit", newsletter_issue_id),
+                &draft.title,
+                &draft.html_content,
+                // A generated body is left out, to be generated again from the
+                // HTML body as it is when the draft is next saved
+                if draft.text_content_derived {
+                    ""
+                } else {
+                    &draft.text_content
+                }
+            ),
+            id = newsletter_issue_id,
+            lists = encode_minimal(&draft.include_lists.join(", ")),
+            tags = encode_minimal(&draft.include_tags.join(", ")),
+            exclude_lists = encode_minimal(&draft.exclude_lists.join(", ")),
+            exclude_tags = encode_minimal(&draft.exclude_tags.join(", ")),
+        )))
+}
+
+/// The issue as a subscriber would receive it, addressed to the logged-in admin.
+pub async fn preview_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    links: web::Data<SubscriberLinks>,
+    user_id: web::ReqData<UserId>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let issue = match get_issue(&pool, newsletter_issue_id).await.map_err(e500)? {
+        Some(issue) => issue,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let username = get_username(**user_id, &pool).await.map_err(e500)?;
+    let email = get_email(**user_id, &pool).await.map_err(e500)?;
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", encode_minimal(m.content())).unwrap();
+    }
+    let issue_url = links.issue_url(newsletter_issue_id);
+    let rendered = issue.render(&MergeValues {
+        name: &username,
+        unsubscribe_url: "#",
+        preferences_url: "#",
+        issue_url: &issue_url,
+    });
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Preview: {title}</title>
+				</head>
+				<body>
+					{msg_html}
+					<h1>{title}</h1>
+					<form action="/admin/newsletters/{id}/test" method="post">
+						<label>Send a test copy to
+							<input type="email" name="email" value="{email}">
+						</label>
+						<button type="submit">Send</button>
+					</form>
+					<h2>HTML</h2>
+					<iframe sandbox srcdoc="{html_content}" width="100%" height="600"></iframe>
+					<h2>Plain text</h2>
+					<pre>{text_content}</pre>
+					<p><a href="/admin/newsletters">&lt;- Back</a></p>
+				</body>
+				</html>
+				"#,
+            title = encode_minimal(&issue.title),
+            id = newsletter_issue_id,
+            email = encode_minimal(email.as_deref().unwrap_or_default()),
+            html_content = encode_minimal(&rendered.html_content),
+            text_content = encode_minimal(&rendered.text_content),
+        )))
+}
+
+fn draft_form(action: &str, title: &str, html_content: &str, text_content: &str) -> String {
+    format!(
+        r#"<form action="{action}" method="post">
+						<label>Title
+							<input type="text" name="title" value="{title}">
+						</label>
+						<br>
+						<label>HTML body
+							<textarea name="html_content" rows="20" cols="80">{html_content}</textarea>
+						</label>
+						<br>
+						<label>Plain-text body (leave empty to generate it from the HTML body)
+							<textarea name="text_content" rows="20" cols="80">{text_content}</textarea>
+						</label>
+						<br>
+						<p>Merge fields: <code>{{{{ name }}}}</code>, <code>{{{{ unsubscribe_url }}}}</code>, <code>{{{{ preferences_url }}}}</code>, <code>{{{{ issue_url }}}}</code></p>
+						<button type="submit">Save draft</button>
+					</form>"#,
+        title = encode_minimal(title),
+        html_content = encode_minimal(html_content),
+        text_content = encode_minimal(text_content),
+    )
+}
+
+struct IssueSummary {
+    newsletter_issue_id: Uuid,
+    title: String,
+    status: String,
+    scheduled_for: Option<chrono::DateTime<chrono::Utc>>,
+    published_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[tracing::instrument(name = "Get newsletter issues", skip(pool))]
+async fn get_issue_summaries(pool: &PgPool) -> Result<Vec<IssueSummary>, anyhow::Error> {
+    let issues = sqlx::query_as!(
+        IssueSummary,
+        r#"
+        SELECT newsletter_issue_id, title, status, scheduled_for, published_at
+        FROM newsletter_issues
+        ORDER BY updated_at DESC
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to retrieve newsletter issues.")?;
+    Ok(issues)
+}
+
+struct Draft {
+    title: String,
+    html_content: String,
+    text_content: String,
+    text_content_derived: bool,
+    include_lists: Vec<String>,
+    include_tags: Vec<String>,
+    exclude_lists: Vec<String>,
+    exclude_tags: Vec<String>,
+}
+
+#[tracing::instrument(name = "Get draft", skip(pool))]
+async fn get_draft(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<Option<Draft>, anyhow::Error> {
+    let draft = sqlx::query_as!(
+        Draft,
+        r#"
+        SELECT
+            title,
+            html_content,
+            text_content,
+            text_content_derived,
+            include_lists,
+            include_tags,
+            exclude_lists,
+            exclude_tags
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1 AND status = 'draft'
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to retrieve the draft.")?;
+    Ok(draft)
+}
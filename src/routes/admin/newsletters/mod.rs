@@ -0,0 +1,5 @@
+mod get;
+mod post;
+
+pub use get::{edit_draft_form, new_draft_form, newsletter_issues, preview_issue};
+pub use post::{create_draft, schedule_issue, send_test_copy, unschedule_issue, update_draft};
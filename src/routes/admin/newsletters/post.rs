@@ -0,0 +1,347 @@
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use anyhow::Context;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::UserId;
+use crate::domain::{split_labels, Audience, MergeValues, SubscriberEmail};
+use crate::email_client::{html_to_text, EmailClient};
+use crate::issue_delivery_worker::get_issue;
+use crate::lists::{find_unknown_lists, unknown_lists_error};
+use crate::routes::admin::dashboard::{get_email, get_username};
+use crate::routes::validate_issue_bodies;
+use crate::subscriber_links::SubscriberLinks;
+use crate::utils::{e500, see_other};
+
+#[derive(serde::Deserialize)]
+pub struct DraftFormData {
+    title: String,
+    html_content: String,
+    text_content: String,
+    // Set if `text_content` was generated from `html_content`
+    #[serde(skip)]
+    text_content_derived: bool,
+}
+
+impl DraftFormData {
+    /// Check the draft, generating its plain-text body if it was left empty.
+    fn validate(mut self) -> Result<Self, String> {
+        if self.title.trim().is_empty() {
+            return Err("The title of an issue cannot be empty.".into());
+        }
+        if self.text_content.trim().is_empty() {
+            self.text_content = html_to_text(&self.html_content);
+            self.text_content_derived = true;
+        }
+        validate_issue_bodies(&self.html_content, &self.text_content)?;
+        Ok(self)
+    }
+}
+
+pub async fn create_draft(
+    form: web::Form<DraftFormData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let draft = match form.0.validate() {
+        Ok(draft) => draft,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other("/admin/newsletters/new"));
+        }
+    };
+    let newsletter_issue_id = insert_draft(&pool, &draft).await.map_err(e500)?;
+    FlashMessage::info("The draft has been saved.").send();
+    Ok(see_other(&format!(
+        "/admin/newsletters/{}/edit",
+        newsletter_issue_id
+    )))
+}
+
+pub async fn update_draft(
+    newsletter_issue_id: web::Path<Uuid>,
+    form: web::Form<DraftFormData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let edit_page = format!("/admin/newsletters/{}/edit", newsletter_issue_id);
+    let draft = match form.0.validate() {
+        Ok(draft) => draft,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other(&edit_page));
+        }
+    };
+    if update_draft_content(&pool, newsletter_issue_id, &draft)
+        .await
+        .map_err(e500)?
+    {
+        FlashMessage::info("The draft has been saved.").send();
+        Ok(see_other(&edit_page))
+    } else {
+        FlashMessage::error("Only drafts can be edited.").send();
+        Ok(see_other("/admin/newsletters"))
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ScheduleFormData {
+    scheduled_for: String,
+    // The audience, as comma-separated names
+    #[serde(default)]
+    lists: String,
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    exclude_lists: String,
+    #[serde(default)]
+    exclude_tags: String,
+}
+
+impl ScheduleFormData {
+    fn audience(&self) -> Result<Audience, String> {
+        Audience {
+            lists: split_labels(&self.lists),
+            tags: split_labels(&self.tags),
+            exclude_lists: split_labels(&self.exclude_lists),
+            exclude_tags: split_labels(&self.exclude_tags),
+        }
+        .parse()
+    }
+}
+
+pub async fn schedule_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    form: web::Form<ScheduleFormData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let edit_page = format!("/admin/newsletters/{}/edit", newsletter_issue_id);
+    let scheduled_for = match parse_scheduled_for(&form.scheduled_for) {
+        Some(scheduled_for) if scheduled_for > Utc::now() => scheduled_for,
+        _ => {
+            FlashMessage::error("Issues can only be scheduled for a time in the future.").send();
+            return Ok(see_other(&edit_page));
+        }
+    };
+    let audience = match form.audience() {
+        Ok(audience) => audience,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other(&edit_page));
+        }
+    };
+    let unknown_lists = find_unknown_lists(&pool, &audience.list_names())
+        .await
+        .context("Failed to look up the lists of the audience.")
+        .map_err(e500)?;
+    if !unknown_lists.is_empty() {
+        FlashMessage::error(unknown_lists_error(&unknown_lists)).send();
+        return Ok(see_other(&edit_page));
+    }
+    let n_scheduled = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET
+            status = 'scheduled',
+            scheduled_for = $2,
+            include_lists = $3,
+            include_tags = $4,
+            exclude_lists = $5,
+            exclude_tags = $6,
+            updated_at = now()
+        WHERE newsletter_issue_id = $1 AND status = 'draft'
+        "#,
+        newsletter_issue_id,
+        scheduled_for,
+        &audience.lists,
+        &audience.tags,
+        &audience.exclude_lists,
+        &audience.exclude_tags
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to schedule the issue.")
+    .map_err(e500)?
+    .rows_affected();
+    if n_scheduled == 0 {
+        FlashMessage::error("Only drafts can be scheduled.").send();
+    } else {
+        FlashMessage::info(format!(
+            "The issue will be sent at {}.",
+            scheduled_for.to_rfc3339()
+        ))
+        .send();
+    }
+    Ok(see_other("/admin/newsletters"))
+}
+
+pub async fn unschedule_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let n_unscheduled = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = 'draft', scheduled_for = NULL, updated_at = now()
+        WHERE newsletter_issue_id = $1 AND status = 'scheduled'
+        "#,
+        newsletter_issue_id.into_inner()
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to unschedule the issue.")
+    .map_err(e500)?
+    .rows_affected();
+    if n_unscheduled == 0 {
+        FlashMessage::error("The issue is not scheduled anymore.").send();
+    } else {
+        FlashMessage::info("The issue is a draft again.").send();
+    }
+    Ok(see_other("/admin/newsletters"))
+}
+
+#[derive(serde::Deserialize)]
+pub struct TestCopyFormData {
+    email: Option<String>,
+}
+
+/// Send an issue to a single address, as the logged-in admin would receive it.
+///
+/// The copy goes straight to the email provider: nothing is enqueued for delivery
+/// and the status of the issue is left untouched.
+#[tracing::instrument(
+    name = "Send a test copy of an issue",
+    skip(form, pool, email_client, links, user_id)
+)]
+pub async fn send_test_copy(
+    newsletter_issue_id: web::Path<Uuid>,
+    form: web::Form<TestCopyFormData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    links: web::Data<SubscriberLinks>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let preview_page = format!("/admin/newsletters/{}/preview", newsletter_issue_id);
+    let issue = match get_issue(&pool, newsletter_issue_id).await.map_err(e500)? {
+        Some(issue) => issue,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let email = match form.0.email.filter(|email| !email.trim().is_empty()) {
+        Some(email) => Some(email.trim().to_string()),
+        None => get_email(**user_id, &pool).await.map_err(e500)?,
+    };
+    let recipient = match email.map(SubscriberEmail::parse) {
+        Some(Ok(recipient)) => recipient,
+        Some(Err(e)) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other(&preview_page));
+        }
+        None => {
+            FlashMessage::error("Enter the address the test copy should be sent to.").send();
+            return Ok(see_other(&preview_page));
+        }
+    };
+    let username = get_username(**user_id, &pool).await.map_err(e500)?;
+    let issue_url = links.issue_url(newsletter_issue_id);
+    let rendered = issue.render(&MergeValues {
+        name: &username,
+        unsubscribe_url: "#",
+        preferences_url: "#",
+        issue_url: &issue_url,
+    });
+    let subject = format!("[TEST] {}", issue.title);
+    match email_client
+        .send_email(
+            &recipient,
+            &subject,
+            &rendered.html_content,
+            Some(&rendered.text_content),
+        )
+        .await
+    {
+        Ok(()) => FlashMessage::info(format!(
+            "A test copy has been sent to {}.",
+            recipient.as_ref()
+        ))
+        .send(),
+        Err(e) => {
+            tracing::warn!(error.cause_chain = ?e, "Failed to send a test copy.");
+            FlashMessage::error(format!("Failed to send the test copy: {}", e)).send()
+        }
+    }
+    Ok(see_other(&preview_page))
+}
+
+// Either an RFC 3339 timestamp, or the value of a `datetime-local` input, read as UTC.
+fn parse_scheduled_for(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t.with_timezone(&Utc));
+    }
+    ["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .map(|t| Utc.from_utc_datetime(&t))
+}
+
+#[tracing::instrument(name = "Save a new draft", skip(pool, draft))]
+async fn insert_draft(pool: &PgPool, draft: &DraftFormData) -> Result<Uuid, anyhow::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id,
+            title,
+            text_content,
+            html_content,
+            text_content_derived,
+            status,
+            updated_at
+        )
+        VALUES ($1, $2, $3, $4, $5, 'draft', now())
+        "#,
+        newsletter_issue_id,
+        draft.title,
+        draft.text_content,
+        draft.html_content,
+        draft.text_content_derived
+    )
+    .execute(pool)
+    .await
+    .context("Failed to save the draft.")?;
+    Ok(newsletter_issue_id)
+}
+
+// Returns `false` if the issue is not a draft (anymore).
+#[tracing::instrument(name = "Update a draft", skip(pool, draft))]
+async fn update_draft_content(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    draft: &DraftFormData,
+) -> Result<bool, anyhow::Error> {
+    let n_updated = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET
+            title = $2,
+            text_content = $3,
+            html_content = $4,
+            text_content_derived = $5,
+            updated_at = now()
+        WHERE newsletter_issue_id = $1 AND status = 'draft'
+        "#,
+        newsletter_issue_id,
+        draft.title,
+        draft.text_content,
+        draft.html_content,
+        draft.text_content_derived
+    )
+    .execute(pool)
+    .await
+    .context("Failed to update the draft.")?
+    .rows_affected();
+    Ok(n_updated == 1)
+}
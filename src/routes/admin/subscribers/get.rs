@@ -0,0 +1,41 @@
+use actix_web::http::header::ContentType;
+use actix_web::HttpResponse;
+use actix_web_flash_messages::IncomingFlashMessages;
+use htmlescape::encode_minimal;
+use std::fmt::Write;
+
+pub async fn import_form(
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", encode_minimal(m.content())).unwrap();
+    }
+    // The options come before the file: the form is processed as it is uploaded,
+    // and browsers send fields in the order they appear in.
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Import subscribers</title>
+				</head>
+				<body>
+					{msg_html}
+					<p>Upload a CSV file with an <code>email</code> and a <code>name</code> column. Addresses that are already subscribed are skipped.</p>
+					<form action="/admin/subscribers/import" method="post" enctype="multipart/form-data">
+						<label><input type="radio" name="status" value="double_opt_in" checked> Send them a confirmation email</label><br>
+						<label><input type="radio" name="status" value="confirmed"> They are already confirmed</label><br>
+						<label><input type="checkbox" name="dry_run" value="on"> Dry run: only check the file</label><br>
+						<input type="file" name="file" accept=".csv,text/csv">
+						<button type="submit">Import</button>
+					</form>
+					<p><a href="/admin/dashboard">&lt;- Back</a></p>
+				</body>
+				</html>
+				"#
+        )))
+}
@@ -0,0 +1,421 @@
+use actix_multipart::{Field, Multipart};
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use anyhow::Context;
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use htmlescape::encode_minimal;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashSet;
+use std::fmt::Write;
+use uuid::Uuid;
+
+use crate::configuration::SubscriptionSettings;
+use crate::csv_records::{CsvRecord, CsvRecords};
+use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
+use crate::routes::generate_subscription_token;
+use crate::utils::{e500, see_other};
+
+// Rows are inserted this many at a time
+const BATCH_SIZE: usize = 500;
+// Past this many skipped rows, the report only counts them
+const MAX_REPORTED_ROWS: usize = 1000;
+
+/// Import subscribers from an uploaded CSV file, one batch of rows at a time while
+/// the file is still coming in.
+///
+/// Imported subscribers are either confirmed right away, or go through double opt-in:
+/// their confirmation emails are written to the outbox. A dry run goes through the
+/// same steps and rolls them back, so its report is exactly what an import would do.
+///
+/// Since the file is processed as it comes in, the options have to come before it:
+/// a request that sends them after the file is rejected, and nothing is saved.
+#[tracing::instrument(name = "Import subscribers", skip(payload, pool, settings))]
+pub async fn import_subscribers(
+    mut payload: Multipart,
+    pool: web::Data<PgPool>,
+    settings: web::Data<SubscriptionSettings>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (mut double_opt_in, mut dry_run) = (None, false);
+    while let Some(mut field) = payload.try_next().await? {
+        match field.name() {
+            Some("status") => match read_text(&mut field).await?.as_str() {
+                "double_opt_in" => double_opt_in = Some(true),
+                "confirmed" => double_opt_in = Some(false),
+                _ => {
+                    FlashMessage::error("Pick how imported subscribers should be confirmed.")
+                        .send();
+                    return Ok(see_other("/admin/subscribers/import"));
+                }
+            },
+            Some("dry_run") => {
+                read_text(&mut field).await?;
+                dry_run = true;
+            }
+            Some("file") => {
+                let double_opt_in = match double_opt_in {
+                    Some(double_opt_in) => double_opt_in,
+                    None => {
+                        FlashMessage::error("Pick how imported subscribers should be confirmed.")
+                            .send();
+                        return Ok(see_other("/admin/subscribers/import"));
+                    }
+                };
+                let mut transaction = pool
+                    .begin()
+                    .await
+                    .context("Failed to acquire a Postgres connection from the pool")
+                    .map_err(e500)?;
+                let mut import = Import::new(double_opt_in);
+                let mut records = CsvRecords::default();
+                while let Some(chunk) = field.try_next().await? {
+                    if let Err(e) = import.add_all(records.push(&chunk)) {
+                        FlashMessage::error(e).send();
+                        return Ok(see_other("/admin/subscribers/import"));
+                    }
+                    if import.batch.len() >= BATCH_SIZE {
+                        import
+                            .flush(&mut transaction, &settings)
+                            .await
+                            .map_err(e500)?;
+                    }
+                }
+                if let Err(e) = import.add_all(records.finish()).and(import.check_header()) {
+                    FlashMessage::error(e).send();
+                    return Ok(see_other("/admin/subscribers/import"));
+                }
+                import
+                    .flush(&mut transaction, &settings)
+                    .await
+                    .map_err(e500)?;
+                // The next field is only handed out once this one is gone. Dropping
+                // the transaction rolls the import back.
+                drop(field);
+                while let Some(mut field) = payload.try_next().await? {
+                    if matches!(field.name(), Some("status" | "dry_run")) {
+                        FlashMessage::error("The options must come before the file.").send();
+                        return Ok(see_other("/admin/subscribers/import"));
+                    }
+                    read_text(&mut field).await?;
+                }
+                if dry_run {
+                    transaction
+                        .rollback()
+                        .await
+                        .context("Failed to roll back the dry run.")
+                        .map_err(e500)?;
+                } else {
+                    transaction
+                        .commit()
+                        .await
+                        .context("Failed to commit SQL transaction.")
+                        .map_err(e500)?;
+                }
+                return Ok(report_page(&import.into_report(), dry_run));
+            }
+            _ => {
+                read_text(&mut field).await?;
+            }
+        }
+    }
+    FlashMessage::error("Pick a CSV file to import.").send();
+    Ok(see_other("/admin/subscribers/import"))
+}
+
+// The value of a form field that is not the file. Anything past the first
+// kilobyte is dropped.
+async fn read_text(field: &mut Field) -> Result<String, actix_web::Error> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.try_next().await? {
+        let room = 1024usize.saturating_sub(value.len());
+        value.extend_from_slice(&chunk[..chunk.len().min(room)]);
+    }
+    Ok(String::from_utf8_lossy(&value).trim().to_string())
+}
+
+#[derive(Default)]
+struct ImportReport {
+    n_imported: u64,
+    n_skipped: u64,
+    // The first `MAX_REPORTED_ROWS` rows that were not imported, with why
+    skipped_rows: Vec<(u64, String)>,
+}
+
+impl ImportReport {
+    fn skip(&mut self, row: u64, reason: String) {
+        self.n_skipped += 1;
+        self.skipped_rows.push((row, reason));
+        // Rows are not skipped in order: those already subscribed are only found
+        // once their batch is inserted. Trimming only once in a while keeps the
+        // first rows without sorting on every push.
+        if self.skipped_rows.len() >= 2 * MAX_REPORTED_ROWS {
+            self.trim();
+        }
+    }
+
+    fn trim(&mut self) {
+        self.skipped_rows.sort_by_key(|(row, _)| *row);
+        self.skipped_rows.truncate(MAX_REPORTED_ROWS);
+    }
+}
+
+struct Import {
+    double_opt_in: bool,
+    // Where the email and name columns are, once the header has been read
+    columns: Option<(usize, usize)>,
+    n_rows: u64,
+    seen_emails: HashSet<String>,
+    batch: Vec<(u64, NewSubscriber)>,
+    report: ImportReport,
+}
+
+impl Import {
+    fn new(double_opt_in: bool) -> Self {
+        Self {
+            double_opt_in,
+            columns: None,
+            n_rows: 0,
+            seen_emails: HashSet::new(),
+            batch: Vec::new(),
+            report: ImportReport::default(),
+        }
+    }
+
+    // Only fails if the header cannot be used: problems with other rows go into
+    // the report.
+    fn add_all(&mut self, records: Vec<CsvRecord>) -> Result<(), String> {
+        for record in records {
+            self.n_rows += 1;
+            match self.columns {
+                None => self.columns = Some(parse_header(record)?),
+                Some(columns) => self.add(columns, record),
+            }
+        }
+        Ok(())
+    }
+
+    fn into_report(mut self) -> ImportReport {
+        self.report.trim();
+        self.report
+    }
+
+    fn check_header(&self) -> Result<(), String> {
+        match self.columns {
+            Some(_) => Ok(()),
+            None => Err("The file is empty.".into()),
+        }
+    }
+
+    fn add(&mut self, (email_column, name_column): (usize, usize), record: CsvRecord) {
+        let row = self.n_rows;
+        let new_subscriber = record.and_then(|fields| {
+            let field = |i: usize| fields.get(i).map(|s| s.trim()).unwrap_or_default();
+            Ok(NewSubscriber {
+                email: SubscriberEmail::parse(field(email_column).to_string())?,
+                name: SubscriberName::parse(field(name_column).to_string())?,
+            })
+        });
+        match new_subscriber {
+            Ok(new_subscriber) => {
+                if self
+                    .seen_emails
+                    .insert(new_subscriber.email.as_ref().to_string())
+                {
+                    self.batch.push((row, new_subscriber));
+                } else {
+                    self.report.skip(
+                        row,
+                        format!(
+                            "{} appears earlier in the file.",
+                            new_subscriber.email.as_ref()
+                        ),
+                    );
+                }
+            }
+            Err(e) => self.report.skip(row, e),
+        }
+    }
+
+    #[tracing::instrument(
+        name = "Insert a batch of imported subscribers",
+        skip_all,
+        fields(n_rows = self.batch.len())
+    )]
+    async fn flush(
+        &mut self,
+        transaction: &mut Transaction<'_, Postgres>,
+        settings: &SubscriptionSettings,
+    ) -> Result<(), anyhow::Error> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::take(&mut self.batch);
+        let ids: Vec<Uuid> = batch.iter().map(|_| Uuid::new_v4()).collect();
+        let emails: Vec<String> = batch
+            .iter()
+            .map(|(_, s)| s.email.as_ref().to_string())
+            .collect();
+        let names: Vec<String> = batch
+            .iter()
+            .map(|(_, s)| s.name.as_ref().to_string())
+            .collect();
+        let status = if self.double_opt_in {
+            "pending_confirmation"
+        } else {
+            "confirmed"
+        };
+        // Addresses that are already subscribed, whatever their status, are left alone.
+        let inserted: HashSet<Uuid> = sqlx::query!(
+            r#"
+            INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+            SELECT id, email, name, now(), $4
+            FROM UNNEST($1::uuid[], $2::text[], $3::text[]) AS t(id, email, name)
+            ON CONFLICT (email) DO NOTHING
+            RETURNING id
+            "#,
+            &ids,
+            &emails,
+            &names,
+            status,
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .context("Failed to insert the imported subscribers.")?
+        .into_iter()
+        .map(|r| r.id)
+        .collect();
+        let mut inserted_ids = Vec::with_capacity(inserted.len());
+        for ((row, new_subscriber), id) in batch.into_iter().zip(ids) {
+            if inserted.contains(&id) {
+                inserted_ids.push(id);
+            } else {
+                self.report.skip(
+                    row,
+                    format!("{} is already subscribed.", new_subscriber.email.as_ref()),
+                );
+            }
+        }
+        self.report.n_imported += inserted_ids.len() as u64;
+        if self.double_opt_in && !inserted_ids.is_empty() {
+            start_double_opt_in(transaction, &inserted_ids, settings).await?;
+        }
+        Ok(())
+    }
+}
+
+// Give every subscriber a confirmation token, and write their confirmation emails
+// to the outbox.
+#[tracing::instrument(skip_all)]
+async fn start_double_opt_in(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_ids: &[Uuid],
+    settings: &SubscriptionSettings,
+) -> Result<(), anyhow::Error> {
+    let tokens: Vec<String> = subscriber_ids
+        .iter()
+        .map(|_| generate_subscription_token())
+        .collect();
+    let issued_at = Utc::now();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens (subscription_token, subscriber_id, issued_at, expires_at)
+        SELECT subscription_token, subscriber_id, $3, $4
+        FROM UNNEST($1::text[], $2::uuid[]) AS t(subscription_token, subscriber_id)
+        "#,
+        &tokens,
+        subscriber_ids,
+        issued_at,
+        issued_at + settings.confirmation_token_ttl(),
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to store the confirmation tokens of the imported subscribers.")?;
+    sqlx::query!(
+        r#"
+        INSERT INTO confirmation_email_outbox (subscription_token, enqueued_at)
+        SELECT subscription_token, now()
+        FROM UNNEST($1::text[]) AS t(subscription_token)
+        "#,
+        &tokens,
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to write the confirmation emails to the outbox.")?;
+    Ok(())
+}
+
+// Find the email and name columns, whatever their case and order
+fn parse_header(record: CsvRecord) -> Result<(usize, usize), String> {
+    let header = record?;
+    let find = |name: &str| {
+        header.iter().position(|column| {
+            // Spreadsheets like to start their exports with a byte order mark
+            column
+                .trim_start_matches('\u{feff}')
+                .trim()
+                .eq_ignore_ascii_case(name)
+        })
+    };
+    match (find("email"), find("name")) {
+        (Some(email_column), Some(name_column)) => Ok((email_column, name_column)),
+        _ => Err("The first row of the file must name an email and a name column.".into()),
+    }
+}
+
+fn report_page(report: &ImportReport, dry_run: bool) -> HttpResponse {
+    let mut rows_html = String::new();
+    for (row, reason) in &report.skipped_rows {
+        writeln!(
+            rows_html,
+            "<tr><td>{}</td><td>{}</td></tr>",
+            row,
+            encode_minimal(reason)
+        )
+        .unwrap();
+    }
+    let (title, summary) = if dry_run {
+        (
+            "Dry run",
+            format!(
+                "{} subscribers would be imported. Nothing has been saved.",
+                report.n_imported
+            ),
+        )
+    } else {
+        (
+            "Import done",
+            format!("{} subscribers have been imported.", report.n_imported),
+        )
+    };
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>{title}</title>
+				</head>
+				<body>
+					<h1>{title}</h1>
+					<p>{summary}</p>
+					<p>{n_skipped} rows skipped{listed}:</p>
+					<table>
+						<tr><th>Row</th><th>Reason</th></tr>
+						{rows_html}
+					</table>
+					<p><a href="/admin/subscribers/import">Import another file</a></p>
+					<p><a href="/admin/dashboard">&lt;- Back</a></p>
+				</body>
+				</html>
+				"#,
+            n_skipped = report.n_skipped,
+            listed = if report.n_skipped > report.skipped_rows.len() as u64 {
+                format!(", the first {} are listed", report.skipped_rows.len())
+            } else {
+                String::new()
+            },
+        ))
+}
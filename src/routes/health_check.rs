@@ -1,5 +1,19 @@
-use actix_web::{HttpRequest, HttpResponse, Responder};
+use actix_web::{web, HttpResponse, Responder};
+use serde::Serialize;
 
-pub async fn health_check(_req: HttpRequest) -> impl Responder {
-    HttpResponse::Ok().body("Ok")
+use crate::email_client::{CircuitState, EmailClient};
+
+#[derive(Serialize)]
+struct HealthReport {
+    status: &'static str,
+    email_circuit_breaker: CircuitState,
+}
+
+// The API stays up while the email provider is down (confirmation emails are queued),
+// so an open circuit is reported but does not fail the check.
+pub async fn health_check(email_client: web::Data<EmailClient>) -> impl Responder {
+    HttpResponse::Ok().json(HealthReport {
+        status: "ok",
+        email_circuit_breaker: email_client.circuit_state(),
+    })
 }
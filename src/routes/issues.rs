@@ -0,0 +1,73 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use htmlescape::encode_minimal;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::domain::{MergeValues, NewsletterTemplate};
+use crate::subscriber_links::SubscriberLinks;
+use crate::utils::e500;
+
+/// The web version of a published issue, linked to from emails via `{{ issue_url }}`.
+///
+/// Drafts and scheduled issues are not public yet: they are a 404 like unknown ones.
+/// Nobody is signed in here, so the merge fields are filled in with neutral values.
+pub async fn issue_web_version(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    links: web::Data<SubscriberLinks>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let issue = sqlx::query!(
+        r#"
+        SELECT title, html_content
+        FROM newsletter_issues
+        WHERE
+            newsletter_issue_id = $1 AND
+            status IN ('sending', 'sent')
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to retrieve the newsletter issue")
+    .map_err(e500)?;
+    let issue = match issue {
+        Some(issue) => issue,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let template = NewsletterTemplate::parse(&issue.html_content)
+        .unwrap_or_else(|_| NewsletterTemplate::literal(issue.html_content));
+    let issue_url = links.issue_url(newsletter_issue_id);
+    let html_content = template.render_html(&MergeValues {
+        name: "reader",
+        unsubscribe_url: "#",
+        preferences_url: "#",
+        issue_url: &issue_url,
+    });
+    // Issues can be written as a whole HTML document or as a fragment
+    let page = if html_content.to_ascii_lowercase().contains("<html") {
+        html_content
+    } else {
+        format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>{}</title>
+				</head>
+				<body>
+					{}
+				</body>
+				</html>
+				"#,
+            encode_minimal(&issue.title),
+            html_content
+        )
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(page))
+}
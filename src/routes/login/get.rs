@@ -1,11 +1,17 @@
 use actix_web::{http::header::ContentType, HttpResponse};
-use actix_web_flash_messages::{IncomingFlashMessages, Level};
+use actix_web_flash_messages::IncomingFlashMessages;
+use htmlescape::encode_minimal;
 use std::fmt::Write;
 
 pub async fn login_form(flash_messages: IncomingFlashMessages) -> HttpResponse {
-    let mut error_html = String::new();
-    for m in flash_messages.iter().filter(|m| m.level() == Level::Error) {
-        write!(&mut error_html, "<p><i>{}</i></p>", m.content()).unwrap();
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        write!(
+            &mut msg_html,
+            "<p><i>{}</i></p>",
+            encode_minimal(m.content())
+        )
+        .unwrap();
     }
     let response = HttpResponse::Ok()
         .content_type(ContentType::html())
@@ -18,7 +24,7 @@ pub async fn login_form(flash_messages: IncomingFlashMessages) -> HttpResponse {
 	<title>Login</title>
 </head>
 <body>
-    {error_html}
+    {msg_html}
 	<form action="/login" method="post">
 		<label>Username
 			<input type="text" placeholder="Enter Username" name="username">
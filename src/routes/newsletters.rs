@@ -7,101 +7,212 @@ use actix_web::{
 };
 use anyhow::Context;
 use serde::Deserialize;
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
 
 use crate::{
     authentication::{basic_authentication, validate_credentials, AuthError},
-    domain::SubscriberEmail,
-    email_client::EmailClient,
+    domain::{Audience, NewsletterTemplate},
+    email_client::html_to_text,
+    idempotency::{save_response, try_processing, IdempotencyKey, NextAction},
+    lists::{find_unknown_lists, unknown_lists_error},
+    markdown,
     utils::error_chain_fmt,
 };
 
 #[tracing::instrument(
     name = "Publish a newsletter issue",
-    skip(body, pool, email_client, request),
+    skip(body, pool, request),
     fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
 )]
 pub async fn publish_newsletter(
     body: web::Json<BodyData>,
-    email_client: web::Data<EmailClient>,
     pool: web::Data<PgPool>,
     request: HttpRequest,
 ) -> Result<HttpResponse, PublishError> {
     let credentials = basic_authentication(request.headers()).map_err(PublishError::AuthError)?;
-    tracing::Span::current().record("username", &tracing::field::display(&credentials.username));
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
     let user_id = validate_credentials(credentials, &pool)
         .await
         .map_err(|e| match e {
             AuthError::InvalidCredentialsError(_) => PublishError::AuthError(e.into()),
             AuthError::UnexpectedError(_) => PublishError::UnexpectedError(e.into()),
         })?;
-    tracing::Span::current().record("user_id", &tracing::field::display(&user_id));
-    let subscribers = get_confirmed_subscribers(&pool).await?;
-    for subscriber in subscribers {
-        match subscriber {
-            Ok(subscriber) => {
-                email_client
-                    .send_email(
-                        &subscriber.email,
-                        &body.title,
-                        &body.content.html,
-                        &body.content.text,
-                    )
-                    .await
-                    .with_context(|| {
-                        format!("Failed to send news letter to {}", subscriber.email)
-                    })?;
-            }
-            Err(_) => {
-                tracing::warn!(
-                    "Skipping a confirmed subscriber, \
-                    Their stored contact details are invalid",
-                );
-            }
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+    let BodyData {
+        title,
+        content,
+        audience,
+        idempotency_key,
+    } = body.0;
+    let idempotency_key: Option<IdempotencyKey> = idempotency_key
+        .map(TryInto::try_into)
+        .transpose()
+        .map_err(|e: anyhow::Error| PublishError::ValidationError(e.to_string()))?;
+    let (html_content, text_content) = content.into_bodies();
+    validate_issue_bodies(&html_content, &text_content).map_err(PublishError::ValidationError)?;
+    let audience = audience.parse().map_err(PublishError::ValidationError)?;
+    let unknown_lists = find_unknown_lists(&pool, &audience.list_names())
+        .await
+        .context("Failed to look up the lists of the audience.")?;
+    if !unknown_lists.is_empty() {
+        return Err(PublishError::ValidationError(unknown_lists_error(
+            &unknown_lists,
+        )));
+    }
+    // Without a key the request is not deduplicated: a retry publishes the issue twice.
+    let mut transaction = match &idempotency_key {
+        Some(idempotency_key) => match try_processing(&pool, idempotency_key, user_id).await? {
+            NextAction::StartProcessing(t) => t,
+            NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+        },
+        None => pool
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool")?,
+    };
+    let issue_id = insert_newsletter_issue(
+        &mut transaction,
+        &title,
+        &text_content,
+        &html_content,
+        &audience,
+    )
+    .await
+    .context("Failed to store newsletter issue details")?;
+    enqueue_delivery_tasks(&mut transaction, issue_id)
+        .await
+        .context("Failed to enqueue delivery tasks")?;
+    let response = HttpResponse::Accepted().finish();
+    match idempotency_key {
+        Some(idempotency_key) => {
+            Ok(save_response(transaction, &idempotency_key, user_id, response).await?)
+        }
+        None => {
+            transaction
+                .commit()
+                .await
+                .context("Failed to commit SQL transaction.")?;
+            Ok(response)
         }
     }
-    Ok(HttpResponse::Ok().finish())
 }
 
-struct ConfirmedSubscriber {
-    email: SubscriberEmail,
+/// Check the merge fields of both bodies of an issue. They are only filled in by
+/// the delivery worker, when it is too late to tell the author.
+pub fn validate_issue_bodies(html_content: &str, text_content: &str) -> Result<(), String> {
+    NewsletterTemplate::parse(html_content)?;
+    NewsletterTemplate::parse(text_content)?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    title: &str,
+    text_content: &str,
+    html_content: &str,
+    audience: &Audience,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id,
+            title,
+            text_content,
+            html_content,
+            status,
+            published_at,
+            updated_at,
+            include_lists,
+            include_tags,
+            exclude_lists,
+            exclude_tags
+        )
+        VALUES ($1, $2, $3, $4, 'sending', now(), now(), $5, $6, $7, $8)
+        "#,
+        newsletter_issue_id,
+        title,
+        text_content,
+        html_content,
+        &audience.lists,
+        &audience.tags,
+        &audience.exclude_lists,
+        &audience.exclude_tags
+    )
+    .execute(transaction)
+    .await?;
+    Ok(newsletter_issue_id)
 }
 
-#[tracing::instrument(name = "Adding a new subscriber", skip(pool))]
-async fn get_confirmed_subscribers(
-    pool: &PgPool,
-) -> Result<Vec<Result<ConfirmedSubscriber, anyhow::Error>>, anyhow::Error> {
-    let confirmed_subscribers = sqlx::query!(
+// One delivery task per confirmed subscriber in the audience of the issue, picked up
+// by the issue delivery worker. Subscribers who asked for digests get it with their
+// next one instead.
+#[tracing::instrument(skip_all)]
+pub async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
         r#"
-        SELECT email FROM subscriptions
-        WHERE status = 'confirmed'
-        "#
+        INSERT INTO issue_delivery_queue (
+            newsletter_issue_id,
+            subscriber_email
+        )
+        SELECT i.newsletter_issue_id, s.email
+        FROM subscriptions s
+        JOIN newsletter_issues i ON i.newsletter_issue_id = $1
+        WHERE
+            s.status = 'confirmed' AND
+            s.digest_frequency = 'every_issue' AND
+            in_audience(s.id, i.include_lists, i.include_tags, i.exclude_lists, i.exclude_tags)
+        "#,
+        newsletter_issue_id,
     )
-    .fetch_all(pool)
-    .await?
-    .into_iter()
-    .map(|r| match SubscriberEmail::parse(r.email) {
-        Ok(email) => Ok(ConfirmedSubscriber { email }),
-        Err(error) => Err(anyhow::anyhow!(error)),
-    })
-    .collect();
-    Ok(confirmed_subscribers)
+    .execute(transaction)
+    .await?;
+    Ok(())
 }
 
 #[derive(Deserialize)]
 pub struct BodyData {
     title: String,
     content: Content,
+    #[serde(default)]
+    audience: Audience,
+    idempotency_key: Option<String>,
 }
 
+/// An issue is either written in HTML, optionally with its own plain-text version,
+/// or in Markdown from which the two bodies are rendered.
 #[derive(Deserialize)]
-pub struct Content {
-    html: String,
-    text: String,
+#[serde(untagged)]
+pub enum Content {
+    Html { html: String, text: Option<String> },
+    Markdown { markdown: String },
+}
+
+impl Content {
+    /// The HTML and plain-text bodies of the issue.
+    fn into_bodies(self) -> (String, String) {
+        match self {
+            Content::Html { html, text } => {
+                let text = text.unwrap_or_else(|| html_to_text(&html));
+                (html, text)
+            }
+            Content::Markdown { markdown } => {
+                let rendered = markdown::render(&markdown);
+                (rendered.html, rendered.text)
+            }
+        }
+    }
 }
 
 #[derive(thiserror::Error)]
 pub enum PublishError {
+    #[error("{0}")]
+    ValidationError(String),
     #[error("Authentication failed.")]
     AuthError(#[source] anyhow::Error),
     #[error(transparent)]
@@ -118,6 +229,7 @@ impl std::fmt::Debug for PublishError {
 impl ResponseError for PublishError {
     fn error_response(&self) -> HttpResponse {
         match self {
+            PublishError::ValidationError(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
             PublishError::UnexpectedError(_) => {
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
             }
@@ -8,15 +8,45 @@ use sqlx::{PgPool, Postgres, Transaction};
 use std::convert::{TryFrom, TryInto};
 use std::fmt::{Debug, Display};
 
-use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
-use crate::email_client::EmailClient;
+use crate::configuration::{ConfirmationOutboxSettings, SubscriptionSettings};
+use crate::confirmation_email_outbox::{
+    dispatch_confirmation_email_now, enqueue_confirmation_email,
+};
+use crate::domain::{parse_label, NewSubscriber, SubscriberEmail, SubscriberName};
+use crate::email_client::{EmailClient, EmailError};
+use crate::lists::{find_unknown_lists, set_subscriber_lists, unknown_lists_error};
 use crate::startup::ApplicationBaseUrl;
 use crate::utils::error_chain_fmt;
 
+// `lists` can be repeated, once per checkbox, which `serde_urlencoded` cannot map
+// to a struct field directly: go through the raw key-value pairs instead.
 #[derive(Deserialize)]
+#[serde(try_from = "Vec<(String, String)>")]
 pub struct FormData {
     name: String,
     email: String,
+    lists: Vec<String>,
+}
+
+impl TryFrom<Vec<(String, String)>> for FormData {
+    type Error = String;
+
+    fn try_from(pairs: Vec<(String, String)>) -> Result<Self, Self::Error> {
+        let (mut name, mut email, mut lists) = (None, None, Vec::new());
+        for (key, value) in pairs {
+            match key.as_str() {
+                "name" => name = Some(value),
+                "email" => email = Some(value),
+                "lists" => lists.push(value),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            name: name.ok_or("missing field `name`")?,
+            email: email.ok_or("missing field `email`")?,
+            lists,
+        })
+    }
 }
 
 impl TryFrom<FormData> for NewSubscriber {
@@ -33,7 +63,7 @@ impl TryFrom<FormData> for NewSubscriber {
 
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, connection_pool, email_client, base_url),
+    skip(form, connection_pool, email_client, base_url, settings, outbox_settings),
     fields(subsciber_email = %form.email, subsciber_name = %form.name)
 )]
 #[allow(clippy::async_yields_async)]
@@ -42,58 +72,223 @@ pub async fn subscribe(
     connection_pool: web::Data<PgPool>,
     email_client: web::Data<EmailClient>,
     base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<SubscriptionSettings>,
+    outbox_settings: web::Data<ConfirmationOutboxSettings>,
 ) -> Result<HttpResponse, SubscribeError> {
     // `web::Form` is a wrapper around `FormData`
     // `form.0` gives us access to the underlying `FormData`
-    let new_subscriber = form.0.try_into().map_err(SubscribeError::ValidationError)?;
+    let mut form = form.0;
+    let lists = std::mem::take(&mut form.lists)
+        .into_iter()
+        .map(parse_label)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(SubscribeError::ValidationError)?;
+    let new_subscriber: NewSubscriber = form.try_into().map_err(SubscribeError::ValidationError)?;
+    let unknown_lists = find_unknown_lists(&connection_pool, &lists)
+        .await
+        .context("Failed to look up the lists picked by the subscriber.")?;
+    if !unknown_lists.is_empty() {
+        return Err(SubscribeError::ValidationError(unknown_lists_error(
+            &unknown_lists,
+        )));
+    }
     let mut transaction = connection_pool
         .begin()
         .await
         .context("Failed to acquire a Postgres connection from the pool")?;
-    let subscriber_id = insert_subscriber(&mut transaction, &new_subscriber)
+    // Insert first rather than look the address up: a lookup cannot lock a row that
+    // does not exist yet, so two concurrent first submissions would both try to insert.
+    // This way the second one waits for the first to commit, then finds its row.
+    let new_subscriber_id = insert_subscriber(&mut transaction, &new_subscriber)
         .await
         .context("Failed to insert new subscriber in the database.")?;
-    let subscription_token = generate_subscription_token();
-    store_token(&mut transaction, subscriber_id, &subscription_token)
+    let subscription_token = match new_subscriber_id {
+        Some(subscriber_id) => {
+            set_subscriber_lists(&mut transaction, subscriber_id, &lists)
+                .await
+                .context("Failed to store the lists of a new subscriber.")?;
+            let subscription_token = generate_subscription_token();
+            store_token(
+                &mut transaction,
+                subscriber_id,
+                &subscription_token,
+                settings.confirmation_token_ttl(),
+            )
+            .await
+            .context("Failed to store the confirmation token for a new subscriber.")?;
+            subscription_token
+        }
+        None => match get_subscriber_by_email(&mut transaction, &new_subscriber.email)
+            .await
+            .context("Failed to look up the subscriber in the database.")?
+            .context("The subscriber was deleted while subscribing again.")?
+        {
+            // Nothing to do: we must not leak whether an address is on the list,
+            // so we answer exactly as we would for a new subscriber.
+            subscriber if subscriber.status == "confirmed" => {
+                return Ok(HttpResponse::Ok().finish());
+            }
+            // The first confirmation email may have been lost: send the link again,
+            // or a fresh one if it has expired in the meantime.
+            subscriber if subscriber.status == "pending_confirmation" => {
+                set_subscriber_lists(&mut transaction, subscriber.id, &lists)
+                    .await
+                    .context("Failed to update the lists of a pending subscriber.")?;
+                match get_token_for_subscriber(&mut transaction, subscriber.id)
+                    .await
+                    .context("Failed to retrieve the confirmation token of a pending subscriber.")?
+                {
+                    Some(subscription_token) => subscription_token,
+                    None => {
+                        let subscription_token = generate_subscription_token();
+                        store_token(
+                            &mut transaction,
+                            subscriber.id,
+                            &subscription_token,
+                            settings.confirmation_token_ttl(),
+                        )
+                        .await
+                        .context(
+                            "Failed to store the confirmation token for a pending subscriber.",
+                        )?;
+                        subscription_token
+                    }
+                }
+            }
+            // Coming back after unsubscribing: go through double opt-in again.
+            subscriber => {
+                restart_double_opt_in(&mut transaction, subscriber.id, &new_subscriber)
+                    .await
+                    .context("Failed to reset the subscription of a returning subscriber.")?;
+                set_subscriber_lists(&mut transaction, subscriber.id, &lists)
+                    .await
+                    .context("Failed to update the lists of a returning subscriber.")?;
+                let subscription_token = generate_subscription_token();
+                store_token(
+                    &mut transaction,
+                    subscriber.id,
+                    &subscription_token,
+                    settings.confirmation_token_ttl(),
+                )
+                .await
+                .context("Failed to store the confirmation token for a returning subscriber.")?;
+                subscription_token
+            }
+        },
+    };
+    enqueue_confirmation_email(&mut transaction, &subscription_token)
         .await
-        .context("Failed to store the confirmation token for a new subscriber.")?;
+        .context("Failed to write the confirmation email to the outbox.")?;
     transaction
         .commit()
         .await
         .context("Failed to commit SQL transaction.")?;
-    send_confirmation_email(
+    // Saves the subscriber a wait for the dispatcher. If the email provider is down
+    // the email stays in the outbox and the dispatcher retries it later.
+    let _ = dispatch_confirmation_email_now(
+        &connection_pool,
         &email_client,
-        new_subscriber,
         &base_url.0,
+        &outbox_settings,
         &subscription_token,
     )
-    .await
-    .context("Failed to send confirmation email.")?;
+    .await;
     Ok(HttpResponse::Ok().finish())
 }
 
+struct ExistingSubscriber {
+    id: uuid::Uuid,
+    status: String,
+}
+
+#[tracing::instrument(name = "Get subscriber by email", skip(transaction, email))]
+async fn get_subscriber_by_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<ExistingSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        ExistingSubscriber,
+        r#"
+        SELECT id, status FROM subscriptions
+        WHERE email = $1
+        FOR UPDATE
+        "#,
+        email.as_ref(),
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+#[tracing::instrument(
+    name = "Get the live confirmation token of a subscriber",
+    skip(transaction)
+)]
+async fn get_token_for_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: uuid::Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"
+        SELECT subscription_token FROM subscription_tokens
+        WHERE subscriber_id = $1 AND expires_at > now()
+        ORDER BY expires_at DESC
+        LIMIT 1
+        "#,
+        subscriber_id,
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(r.map(|r| r.subscription_token))
+}
+
+#[tracing::instrument(
+    name = "Restart double opt-in for a returning subscriber",
+    skip(transaction, new_subscriber)
+)]
+async fn restart_double_opt_in(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: uuid::Uuid,
+    new_subscriber: &NewSubscriber,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET name = $2, status = 'pending_confirmation', subscribed_at = $3
+        WHERE id = $1
+        "#,
+        subscriber_id,
+        new_subscriber.name.as_ref(),
+        chrono::Utc::now()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
 #[tracing::instrument(
     name = "Saving new subscriber details to database",
     skip(new_subscriber, transaction)
 )]
+// `None` if the address is already subscribed, whatever its status.
 async fn insert_subscriber(
     transaction: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
-) -> Result<uuid::Uuid, sqlx::Error> {
-    let subscriber_id = uuid::Uuid::new_v4();
-    sqlx::query!(
+) -> Result<Option<uuid::Uuid>, sqlx::Error> {
+    let r = sqlx::query!(
         r#"
         INSERT INTO subscriptions (id, email, name, subscribed_at, status)
         VALUES ($1, $2, $3, $4, 'pending_confirmation')
+        ON CONFLICT (email) DO NOTHING
+        RETURNING id
         "#,
-        subscriber_id,
+        uuid::Uuid::new_v4(),
         new_subscriber.email.as_ref(),
         new_subscriber.name.as_ref(),
         chrono::Utc::now()
     )
-    .execute(transaction)
+    .fetch_optional(transaction)
     .await?;
-    Ok(subscriber_id)
+    Ok(r.map(|r| r.id))
 }
 
 #[tracing::instrument(
@@ -105,26 +300,22 @@ pub async fn send_confirmation_email(
     new_subscriber: NewSubscriber,
     base_url: &str,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
+) -> Result<(), EmailError> {
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, subscription_token,
     );
-    let plain_body = format!(
-        "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
-        confirmation_link
-    );
     let html_body = format!(
         "Welcome to our newsletter!<br />\
         Click <a href=\"{}\">here</a> to confirm your subscription.",
         confirmation_link
     );
     email_client
-        .send_email(&new_subscriber.email, "Welcome!", &html_body, &plain_body)
+        .send_email(&new_subscriber.email, "Welcome!", &html_body, None)
         .await
 }
 
-fn generate_subscription_token() -> String {
+pub fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat_with(|| rng.sample(Alphanumeric))
         .map(char::from)
@@ -140,14 +331,18 @@ pub async fn store_token(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: uuid::Uuid,
     subscription_token: &str,
+    time_to_live: chrono::Duration,
 ) -> Result<(), StoreTokenError> {
+    let issued_at = chrono::Utc::now();
     sqlx::query!(
         r#"
-        INSERT INTO subscription_tokens (subscription_token, subscriber_id)
-        VALUES ($1, $2)
+        INSERT INTO subscription_tokens (subscription_token, subscriber_id, issued_at, expires_at)
+        VALUES ($1, $2, $3, $4)
         "#,
         subscription_token,
         subscriber_id,
+        issued_at,
+        issued_at + time_to_live,
     )
     .execute(transaction)
     .await
@@ -1,11 +1,21 @@
 use std::fmt::Debug;
 
+use actix_web::http::header::ContentType;
 use actix_web::http::StatusCode;
 use actix_web::{web, HttpResponse, ResponseError};
 use anyhow::Context;
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
 
+use crate::configuration::{ConfirmationOutboxSettings, SubscriptionSettings};
+use crate::confirmation_email_outbox::{
+    dispatch_confirmation_email_now, enqueue_confirmation_email,
+};
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::routes::{generate_subscription_token, store_email_change_request, store_token};
+use crate::startup::ApplicationBaseUrl;
 use crate::utils::error_chain_fmt;
 
 #[derive(Deserialize)]
@@ -15,44 +25,346 @@ pub struct Parameters {
 
 #[tracing::instrument(
 	name = "Confirm a pending subscriber"
-	skip(parameters)
+	skip(parameters, email_client)
 )]
 #[allow(clippy::async_yields_async)]
 pub async fn confirm(
     parameters: web::Query<Parameters>,
     pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
 ) -> Result<HttpResponse, SubscriptionConfirmError> {
-    let result = get_subscriber_id_from_token(&pool, &parameters.subscription_token)
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let token = get_subscription_token(&mut transaction, &parameters.subscription_token)
         .await
         .context("A database error has occurred while getting the subscriber_id")?;
-    if let Some(subscriber_id) = result {
-        confirm_subscriber(&pool, subscriber_id)
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+    if token.expires_at <= Utc::now() {
+        return Ok(expired_token_page(&parameters.subscription_token));
+    }
+    let requested_email = get_requested_email(&mut transaction, &parameters.subscription_token)
+        .await
+        .context("A database error has occurred while looking up a change of address")?;
+    if let Some(new_email) = requested_email {
+        return confirm_email_change(transaction, &email_client, token.subscriber_id, &new_email)
+            .await;
+    }
+    confirm_subscriber(&mut transaction, token.subscriber_id)
+        .await
+        .context("A database error has occured while confirming the subscriber")?;
+    // A confirmation link is good for a single use.
+    delete_tokens_of_subscriber(&mut transaction, token.subscriber_id)
+        .await
+        .context("A database error has occurred while invalidating the subscription token")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction.")?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+// The token was sent to a confirmed subscriber's new address: following it proves
+// they own that address. The old address is told about the change, in case the
+// preferences link of the subscriber ended up in the wrong hands.
+async fn confirm_email_change(
+    mut transaction: Transaction<'_, Postgres>,
+    email_client: &EmailClient,
+    subscriber_id: uuid::Uuid,
+    new_email: &str,
+) -> Result<HttpResponse, SubscriptionConfirmError> {
+    let old_email = sqlx::query!(
+        r#"
+		SELECT email FROM subscriptions WHERE id = $1 FOR UPDATE
+		"#,
+        subscriber_id,
+    )
+    .fetch_one(&mut transaction)
+    .await
+    .context("A database error has occurred while getting the current address")?
+    .email;
+    let update = sqlx::query!(
+        r#"
+		UPDATE subscriptions SET email = $2 WHERE id = $1
+		"#,
+        subscriber_id,
+        new_email,
+    )
+    .execute(&mut transaction)
+    .await;
+    match update {
+        // The address was subscribed since the change was requested.
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => {
+            return Ok(address_taken_page());
+        }
+        r => {
+            r.context("A database error has occurred while changing the address of the subscriber")?
+        }
+    };
+    delete_tokens_of_subscriber(&mut transaction, subscriber_id)
+        .await
+        .context("A database error has occurred while invalidating the subscription token")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction.")?;
+    if let Err(e) = send_email_changed_notice(email_client, &old_email, new_email).await {
+        tracing::warn!(
+            error.cause_chain = ?e,
+            "Failed to tell the previous address about the change."
+        );
+    }
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Address updated</title>
+				</head>
+				<body>
+					<p>Your email address has been updated.</p>
+				</body>
+				</html>
+				"#,
+    ))
+}
+
+#[tracing::instrument(
+    name = "Send a notice of change to the previous address",
+    skip(email_client)
+)]
+async fn send_email_changed_notice(
+    email_client: &EmailClient,
+    old_email: &str,
+    new_email: &str,
+) -> Result<(), anyhow::Error> {
+    // Addresses were validated when they were stored.
+    let recipient =
+        SubscriberEmail::parse(old_email.to_string()).map_err(|e| anyhow::anyhow!(e))?;
+    let html_body = format!(
+        "Our newsletter will be sent to {} from now on.<br />\
+        If you did not ask for this change, please reply to this email.",
+        htmlescape::encode_minimal(new_email)
+    );
+    email_client
+        .send_email(
+            &recipient,
+            "Your email address has changed",
+            &html_body,
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+fn address_taken_page() -> HttpResponse {
+    HttpResponse::Conflict()
+        .content_type(ContentType::html())
+        .body(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Address already subscribed</title>
+				</head>
+				<body>
+					<p>This address is already subscribed to our newsletter. Your email address has not changed.</p>
+				</body>
+				</html>
+				"#,
+        )
+}
+
+fn expired_token_page(subscription_token: &str) -> HttpResponse {
+    HttpResponse::Gone()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Confirmation link expired</title>
+				</head>
+				<body>
+					<p>This confirmation link has expired.</p>
+					<form action="/subscriptions/confirm/resend" method="post">
+						<input hidden type="text" name="subscription_token" value="{}">
+						<button type="submit">Send me a new link</button>
+					</form>
+				</body>
+				</html>
+				"#,
+            htmlescape::encode_minimal(subscription_token)
+        ))
+}
+
+/// Replace an expired confirmation link with a new one: for a new subscriber, or
+/// for the new address of a subscriber changing it.
+#[tracing::instrument(
+    name = "Resend a confirmation email",
+    skip(form, pool, email_client, base_url, settings, outbox_settings)
+)]
+pub async fn resend_confirmation(
+    form: web::Form<Parameters>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<SubscriptionSettings>,
+    outbox_settings: web::Data<ConfirmationOutboxSettings>,
+) -> Result<HttpResponse, SubscriptionConfirmError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let pending = get_pending_confirmation(&mut transaction, &form.subscription_token)
+        .await
+        .context("A database error has occurred while getting the subscriber")?;
+    let PendingConfirmation {
+        subscriber_id,
+        new_email,
+    } = match pending {
+        Some(pending) => pending,
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+    delete_tokens_of_subscriber(&mut transaction, subscriber_id)
+        .await
+        .context("A database error has occurred while invalidating the subscription token")?;
+    let subscription_token = generate_subscription_token();
+    store_token(
+        &mut transaction,
+        subscriber_id,
+        &subscription_token,
+        settings.confirmation_token_ttl(),
+    )
+    .await
+    .context("Failed to store the new confirmation token.")?;
+    if let Some(new_email) = new_email {
+        store_email_change_request(&mut transaction, &subscription_token, &new_email)
             .await
-            .context("A database error has occured while confirming the subscriber")?;
-        return Ok(HttpResponse::Ok().finish());
+            .context("Failed to store the change of email address.")?;
     }
-    Ok(HttpResponse::Unauthorized().finish())
+    enqueue_confirmation_email(&mut transaction, &subscription_token)
+        .await
+        .context("Failed to write the confirmation email to the outbox.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction.")?;
+    let _ = dispatch_confirmation_email_now(
+        &pool,
+        &email_client,
+        &base_url.0,
+        &outbox_settings,
+        &subscription_token,
+    )
+    .await;
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Confirmation link sent</title>
+				</head>
+				<body>
+					<p>We have sent you a new confirmation link. Please check your inbox.</p>
+				</body>
+				</html>
+				"#,
+    ))
+}
+
+struct SubscriptionToken {
+    subscriber_id: uuid::Uuid,
+    expires_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(name = "Get subscription token", skip(subscription_token, transaction))]
+async fn get_subscription_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscription_token: &str,
+) -> Result<Option<SubscriptionToken>, sqlx::Error> {
+    sqlx::query_as!(
+        SubscriptionToken,
+        r#"
+		SELECT subscriber_id, expires_at FROM subscription_tokens
+		WHERE subscription_token = $1
+		FOR UPDATE
+		"#,
+        subscription_token
+    )
+    .fetch_optional(transaction)
+    .await
 }
 
-#[tracing::instrument(name = "Get subscriber_id from token", skip(subscription_token, pool))]
-pub async fn get_subscriber_id_from_token(
-    pool: &PgPool,
+#[tracing::instrument(
+    name = "Get the address a token confirms",
+    skip(subscription_token, transaction)
+)]
+async fn get_requested_email(
+    transaction: &mut Transaction<'_, Postgres>,
     subscription_token: &str,
-) -> Result<Option<uuid::Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
+) -> Result<Option<String>, sqlx::Error> {
+    let r = sqlx::query!(
         r#"
-		SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1
+		SELECT new_email FROM email_change_requests
+		WHERE subscription_token = $1
 		"#,
         subscription_token
     )
-    .fetch_optional(pool)
+    .fetch_optional(transaction)
     .await?;
-    Ok(result.map(|r| r.subscriber_id))
+    Ok(r.map(|r| r.new_email))
+}
+
+struct PendingConfirmation {
+    subscriber_id: uuid::Uuid,
+    // Set if the token confirms the new address of a confirmed subscriber.
+    new_email: Option<String>,
+}
+
+#[tracing::instrument(
+    name = "Get pending confirmation from token",
+    skip(subscription_token, transaction)
+)]
+async fn get_pending_confirmation(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscription_token: &str,
+) -> Result<Option<PendingConfirmation>, sqlx::Error> {
+    sqlx::query_as!(
+        PendingConfirmation,
+        r#"
+		SELECT s.id as subscriber_id, r.new_email as "new_email?"
+		FROM subscription_tokens t
+		JOIN subscriptions s ON s.id = t.subscriber_id
+		LEFT JOIN email_change_requests r ON r.subscription_token = t.subscription_token
+		WHERE
+			t.subscription_token = $1 AND
+			s.status = CASE
+				WHEN r.new_email IS NULL THEN 'pending_confirmation'
+				ELSE 'confirmed'
+			END
+		FOR UPDATE OF s
+		"#,
+        subscription_token
+    )
+    .fetch_optional(transaction)
+    .await
 }
 
-#[tracing::instrument(name = "Mark a subscriber as confirmed", skip(subscriber_id, pool))]
+#[tracing::instrument(
+    name = "Mark a subscriber as confirmed",
+    skip(subscriber_id, transaction)
+)]
 pub async fn confirm_subscriber(
-    pool: &PgPool,
+    transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: uuid::Uuid,
 ) -> Result<(), sqlx::Error> {
     sqlx::query!(
@@ -61,7 +373,26 @@ pub async fn confirm_subscriber(
 		"#,
         subscriber_id,
     )
-    .execute(pool)
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Delete the subscription tokens of a subscriber",
+    skip(subscriber_id, transaction)
+)]
+async fn delete_tokens_of_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: uuid::Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+		DELETE FROM subscription_tokens WHERE subscriber_id = $1
+		"#,
+        subscriber_id,
+    )
+    .execute(transaction)
     .await?;
     Ok(())
 }
@@ -0,0 +1,441 @@
+use std::fmt::{Debug, Write};
+
+use actix_web::http::header::{ContentDisposition, ContentType, DispositionParam, DispositionType};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use htmlescape::encode_minimal;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::configuration::SubscriptionSettings;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::routes::generate_subscription_token;
+use crate::startup::ApplicationBaseUrl;
+use crate::utils::{error_chain_fmt, see_other};
+
+/// Where anybody can ask for the data we hold about their address, or for its erasure.
+pub async fn data_request_form(
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", encode_minimal(m.content())).unwrap();
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Your data</title>
+				</head>
+				<body>
+					{msg_html}
+					<p>We will email you a link to download or erase the data we hold about you.</p>
+					<form action="/subscriptions/data" method="post">
+						<label>Email
+							<input type="email" name="email">
+						</label>
+						<button type="submit">Send me a link</button>
+					</form>
+				</body>
+				</html>
+				"#
+        )))
+}
+
+#[derive(Deserialize)]
+pub struct DataRequestFormData {
+    email: String,
+}
+
+/// Mail a data access link to the address, if it is subscribed.
+///
+/// The answer is the same either way, so that the form cannot be used to find out
+/// who receives the newsletter.
+#[tracing::instrument(
+    name = "Request access to subscriber data",
+    skip(form, pool, email_client, base_url, settings)
+)]
+pub async fn request_data_access(
+    form: web::Form<DataRequestFormData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<SubscriptionSettings>,
+) -> Result<HttpResponse, SubscriberDataError> {
+    let email = match SubscriberEmail::parse(form.0.email.trim().to_string()) {
+        Ok(email) => email,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other("/subscriptions/data"));
+        }
+    };
+    let subscriber = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE email = $1"#,
+        email.as_ref(),
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to look up the subscriber.")?;
+    if let Some(subscriber) = subscriber {
+        let data_access_token = generate_subscription_token();
+        sqlx::query!(
+            r#"
+            INSERT INTO data_access_tokens (data_access_token, subscriber_id, expires_at)
+            VALUES ($1, $2, $3)
+            "#,
+            data_access_token,
+            subscriber.id,
+            Utc::now() + settings.confirmation_token_ttl(),
+        )
+        .execute(pool.get_ref())
+        .await
+        .context("Failed to store the data access token.")?;
+        if let Err(e) =
+            send_data_access_link(&email_client, &email, &base_url.0, &data_access_token).await
+        {
+            tracing::warn!(error.cause_chain = ?e, "Failed to send the data access link.");
+            FlashMessage::error("We could not send you a link. Please try again later.").send();
+            return Ok(see_other("/subscriptions/data"));
+        }
+    }
+    FlashMessage::info(format!(
+        "If {} is subscribed to our newsletter, we have sent it a link to access your data.",
+        email.as_ref()
+    ))
+    .send();
+    Ok(see_other("/subscriptions/data"))
+}
+
+#[tracing::instrument(
+    name = "Send a data access link",
+    skip(email_client, base_url, data_access_token)
+)]
+async fn send_data_access_link(
+    email_client: &EmailClient,
+    email: &SubscriberEmail,
+    base_url: &str,
+    data_access_token: &str,
+) -> Result<(), anyhow::Error> {
+    let access_link = format!(
+        "{}/subscriptions/data/access?token={}",
+        base_url, data_access_token
+    );
+    let html_body = format!(
+        "Click <a href=\"{}\">here</a> to download or erase the data we hold about you.<br />\
+        If you did not ask for this, you can ignore this email.",
+        access_link
+    );
+    email_client
+        .send_email(email, "Your data", &html_body, None)
+        .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct DataAccessParameters {
+    token: String,
+}
+
+#[tracing::instrument(name = "Render the data access page", skip(parameters, pool))]
+pub async fn data_access_page(
+    parameters: web::Query<DataAccessParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, SubscriberDataError> {
+    if get_subscriber_id_from_token(&pool, &parameters.token)
+        .await
+        .context("Failed to look up the data access token.")?
+        .is_none()
+    {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+    let token = encode_minimal(&parameters.token);
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Your data</title>
+				</head>
+				<body>
+					<p><a href="/subscriptions/data/export?token={token}">Download your data</a></p>
+					<h2>Erase your data</h2>
+					<p>You will stop receiving our newsletter and we will delete everything we know about you. This cannot be undone.</p>
+					<form action="/subscriptions/data/erase" method="post">
+						<input hidden type="text" name="token" value="{token}">
+						<button type="submit">Erase my data</button>
+					</form>
+				</body>
+				</html>
+				"#
+        )))
+}
+
+#[derive(Serialize)]
+pub struct SubscriberData {
+    subscription: SubscriptionRecord,
+    tokens: Vec<TokenRecord>,
+    lists: Vec<String>,
+    tags: Vec<String>,
+    deliveries: Vec<DeliveryRecord>,
+}
+
+#[derive(Serialize)]
+pub struct SubscriptionRecord {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+    digest_frequency: String,
+    last_digest_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct TokenRecord {
+    issued_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    // Set if the token confirms a change of address
+    new_email: Option<String>,
+}
+
+/// An issue that is still on its way to the subscriber, or that could not be
+/// delivered. Delivered issues leave no trace.
+#[derive(Serialize)]
+pub struct DeliveryRecord {
+    newsletter_issue_id: Uuid,
+    title: String,
+    status: String,
+    last_error: Option<String>,
+}
+
+/// Everything we hold about the subscriber, as a JSON download.
+#[tracing::instrument(name = "Export subscriber data", skip(parameters, pool))]
+pub async fn export_subscriber_data(
+    parameters: web::Query<DataAccessParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, SubscriberDataError> {
+    let subscriber_id = match get_subscriber_id_from_token(&pool, &parameters.token)
+        .await
+        .context("Failed to look up the data access token.")?
+    {
+        Some(subscriber_id) => subscriber_id,
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+    let data = get_subscriber_data(&pool, subscriber_id)
+        .await
+        .context("Failed to retrieve the data of the subscriber.")?;
+    Ok(HttpResponse::Ok()
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("subscriber-data.json".into())],
+        })
+        .json(data))
+}
+
+#[tracing::instrument(name = "Get subscriber data", skip(pool))]
+async fn get_subscriber_data(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<SubscriberData, sqlx::Error> {
+    let subscription = sqlx::query_as!(
+        SubscriptionRecord,
+        r#"
+        SELECT id, email, name, status, subscribed_at, digest_frequency, last_digest_at
+        FROM subscriptions
+        WHERE id = $1
+        "#,
+        subscriber_id,
+    )
+    .fetch_one(pool)
+    .await?;
+    let tokens = sqlx::query_as!(
+        TokenRecord,
+        r#"
+        SELECT t.issued_at, t.expires_at, r.new_email as "new_email?"
+        FROM subscription_tokens t
+        LEFT JOIN email_change_requests r USING (subscription_token)
+        WHERE t.subscriber_id = $1
+        ORDER BY t.issued_at
+        "#,
+        subscriber_id,
+    )
+    .fetch_all(pool)
+    .await?;
+    let lists = sqlx::query!(
+        r#"
+        SELECT l.name
+        FROM subscription_lists sl
+        JOIN lists l USING (list_id)
+        WHERE sl.subscriber_id = $1
+        ORDER BY l.name
+        "#,
+        subscriber_id,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| r.name)
+    .collect();
+    let tags = sqlx::query!(
+        r#"SELECT tag FROM subscriber_tags WHERE subscriber_id = $1 ORDER BY tag"#,
+        subscriber_id,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| r.tag)
+    .collect();
+    let deliveries = sqlx::query_as!(
+        DeliveryRecord,
+        r#"
+        SELECT
+            q.newsletter_issue_id as "newsletter_issue_id!",
+            i.title as "title!",
+            'pending' as "status!",
+            q.last_error
+        FROM issue_delivery_queue q
+        JOIN newsletter_issues i USING (newsletter_issue_id)
+        WHERE q.subscriber_email = $1
+        UNION ALL
+        SELECT
+            d.newsletter_issue_id,
+            i.title,
+            'failed',
+            d.last_error
+        FROM issue_delivery_dead_letters d
+        JOIN newsletter_issues i USING (newsletter_issue_id)
+        WHERE d.subscriber_email = $1
+        "#,
+        subscription.email,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(SubscriberData {
+        subscription,
+        tokens,
+        lists,
+        tags,
+        deliveries,
+    })
+}
+
+/// Delete the subscriber and every row that refers to them.
+#[tracing::instrument(name = "Erase subscriber data", skip(form, pool))]
+pub async fn erase_subscriber_data(
+    form: web::Form<DataAccessParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, SubscriberDataError> {
+    let subscriber_id = match get_subscriber_id_from_token(&pool, &form.token)
+        .await
+        .context("Failed to look up the data access token.")?
+    {
+        Some(subscriber_id) => subscriber_id,
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    delete_subscriber(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to erase the subscriber.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction.")?;
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Data erased</title>
+				</head>
+				<body>
+					<p>Your data has been erased. You will not receive any further issues.</p>
+				</body>
+				</html>
+				"#,
+    ))
+}
+
+#[tracing::instrument(name = "Delete a subscriber", skip(transaction))]
+async fn delete_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let email = sqlx::query!(
+        r#"SELECT email FROM subscriptions WHERE id = $1 FOR UPDATE"#,
+        subscriber_id,
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .email;
+    // Deliveries only know the address of the subscriber.
+    sqlx::query!(
+        r#"DELETE FROM issue_delivery_queue WHERE subscriber_email = $1"#,
+        email,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM issue_delivery_dead_letters WHERE subscriber_email = $1"#,
+        email,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    // Everything else goes away with the subscription.
+    sqlx::query!(r#"DELETE FROM subscriptions WHERE id = $1"#, subscriber_id)
+        .execute(&mut *transaction)
+        .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get subscriber from data access token", skip(pool, token))]
+async fn get_subscriber_id_from_token(
+    pool: &PgPool,
+    token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"
+        SELECT subscriber_id FROM data_access_tokens
+        WHERE data_access_token = $1 AND expires_at > now()
+        "#,
+        token,
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(r.map(|r| r.subscriber_id))
+}
+
+#[derive(thiserror::Error)]
+pub enum SubscriberDataError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl Debug for SubscriberDataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SubscriberDataError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscriberDataError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
@@ -0,0 +1,526 @@
+use std::fmt::{Debug, Write};
+
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use anyhow::Context;
+use htmlescape::encode_minimal;
+use serde::Deserialize;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::configuration::{ConfirmationOutboxSettings, SubscriptionSettings};
+use crate::confirmation_email_outbox::{
+    dispatch_confirmation_email_now, enqueue_confirmation_email,
+};
+use crate::domain::{DigestFrequency, SubscriberEmail, SubscriberName};
+use crate::email_client::{EmailClient, EmailError};
+use crate::lists::set_subscriber_lists;
+use crate::routes::{generate_subscription_token, store_token};
+use crate::startup::ApplicationBaseUrl;
+use crate::subscriber_links::{LinkPurpose, SubscriberLinks};
+use crate::utils::{error_chain_fmt, see_other};
+
+#[derive(Deserialize)]
+pub struct PreferencesParameters {
+    token: String,
+}
+
+struct Subscriber {
+    email: String,
+    name: String,
+    status: String,
+    digest_frequency: String,
+}
+
+/// The page linked to from the footer of every issue, where a subscriber can manage
+/// their subscription without an account: the signed token is their credential.
+#[tracing::instrument(
+    name = "Render the preferences page",
+    skip(parameters, pool, links, flash_messages)
+)]
+pub async fn preferences_form(
+    parameters: web::Query<PreferencesParameters>,
+    pool: web::Data<PgPool>,
+    links: web::Data<SubscriberLinks>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = links
+        .verify(LinkPurpose::Preferences, &parameters.token)
+        .map_err(PreferencesError::InvalidToken)?;
+    let subscriber = get_subscriber(&pool, subscriber_id)
+        .await
+        .context("Failed to retrieve the subscriber.")?;
+    let subscriber = match subscriber {
+        Some(subscriber) if subscriber.status == "confirmed" => subscriber,
+        _ => return Ok(not_subscribed_page()),
+    };
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", encode_minimal(m.content())).unwrap();
+    }
+    let mut lists_html = String::new();
+    for (name, is_member) in get_lists(&pool, subscriber_id)
+        .await
+        .context("Failed to retrieve the lists of the subscriber.")?
+    {
+        writeln!(
+            lists_html,
+            r#"<label><input type="checkbox" name="lists" value="{name}"{checked}> {name}</label><br>"#,
+            name = encode_minimal(&name),
+            checked = if is_member { " checked" } else { "" },
+        )
+        .unwrap();
+    }
+    let mut frequencies_html = String::new();
+    for frequency in DigestFrequency::ALL {
+        writeln!(
+            frequencies_html,
+            r#"<label><input type="radio" name="digest_frequency" value="{}"{}> {}</label><br>"#,
+            frequency.as_str(),
+            if frequency.as_str() == subscriber.digest_frequency {
+                " checked"
+            } else {
+                ""
+            },
+            frequency.description(),
+        )
+        .unwrap();
+    }
+    let token = encode_minimal(&parameters.token);
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Your preferences</title>
+				</head>
+				<body>
+					{msg_html}
+					<form action="/subscriptions/preferences" method="post">
+						<input hidden type="text" name="token" value="{token}">
+						<label>Name
+							<input type="text" name="name" value="{name}">
+						</label>
+						<h2>Lists</h2>
+						{lists_html}
+						<h2>How often</h2>
+						{frequencies_html}
+						<button type="submit">Save</button>
+					</form>
+					<h2>Email address</h2>
+					<form action="/subscriptions/preferences/email" method="post">
+						<input hidden type="text" name="token" value="{token}">
+						<label>We write to {email}. New address:
+							<input type="email" name="email">
+						</label>
+						<button type="submit">Change</button>
+					</form>
+					<h2>Unsubscribe</h2>
+					<form action="/subscriptions/unsubscribe" method="post">
+						<input hidden type="text" name="token" value="{unsubscribe_token}">
+						<button type="submit">Unsubscribe</button>
+					</form>
+					<p><a href="/subscriptions/data">Download or erase your data</a></p>
+				</body>
+				</html>
+				"#,
+            name = encode_minimal(&subscriber.name),
+            email = encode_minimal(&subscriber.email),
+            unsubscribe_token = links.sign(LinkPurpose::Unsubscribe, subscriber_id),
+        )))
+}
+
+fn not_subscribed_page() -> HttpResponse {
+    HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Your preferences</title>
+				</head>
+				<body>
+					<p>You are not subscribed to our newsletter.</p>
+				</body>
+				</html>
+				"#,
+    )
+}
+
+// `lists` is repeated, once per checked box: see `subscriptions::FormData`.
+#[derive(Deserialize)]
+#[serde(try_from = "Vec<(String, String)>")]
+pub struct PreferencesFormData {
+    token: String,
+    name: String,
+    lists: Vec<String>,
+    digest_frequency: String,
+}
+
+impl TryFrom<Vec<(String, String)>> for PreferencesFormData {
+    type Error = String;
+
+    fn try_from(pairs: Vec<(String, String)>) -> Result<Self, Self::Error> {
+        let (mut token, mut name, mut digest_frequency) = (None, None, None);
+        let mut lists = Vec::new();
+        for (key, value) in pairs {
+            match key.as_str() {
+                "token" => token = Some(value),
+                "name" => name = Some(value),
+                "lists" => lists.push(value),
+                "digest_frequency" => digest_frequency = Some(value),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            token: token.ok_or("missing field `token`")?,
+            name: name.ok_or("missing field `name`")?,
+            lists,
+            digest_frequency: digest_frequency.ok_or("missing field `digest_frequency`")?,
+        })
+    }
+}
+
+#[tracing::instrument(
+    name = "Update the preferences of a subscriber",
+    skip(form, pool, links)
+)]
+pub async fn update_preferences(
+    form: web::Form<PreferencesFormData>,
+    pool: web::Data<PgPool>,
+    links: web::Data<SubscriberLinks>,
+) -> Result<HttpResponse, PreferencesError> {
+    let PreferencesFormData {
+        token,
+        name,
+        lists,
+        digest_frequency,
+    } = form.0;
+    let subscriber_id = links
+        .verify(LinkPurpose::Preferences, &token)
+        .map_err(PreferencesError::InvalidToken)?;
+    let preferences_page = preferences_page(&token);
+    let (name, digest_frequency) = match (
+        SubscriberName::parse(name),
+        DigestFrequency::parse(&digest_frequency),
+    ) {
+        (Ok(name), Ok(digest_frequency)) => (name, digest_frequency),
+        (Err(e), _) | (_, Err(e)) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other(&preferences_page));
+        }
+    };
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    // Switching to digests starts the clock: the first one covers the issues
+    // published from now on.
+    let n_updated = sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET
+            name = $2,
+            digest_frequency = $3,
+            last_digest_at = CASE
+                WHEN $3 = 'every_issue' THEN NULL
+                ELSE COALESCE(last_digest_at, now())
+            END
+        WHERE id = $1 AND status = 'confirmed'
+        "#,
+        subscriber_id,
+        name.as_ref(),
+        digest_frequency.as_str(),
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to update the preferences of the subscriber.")?
+    .rows_affected();
+    if n_updated == 0 {
+        return Ok(not_subscribed_page());
+    }
+    set_subscriber_lists(&mut transaction, subscriber_id, &lists)
+        .await
+        .context("Failed to update the lists of the subscriber.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction.")?;
+    FlashMessage::info("Your preferences have been saved.").send();
+    Ok(see_other(&preferences_page))
+}
+
+#[derive(Deserialize)]
+pub struct EmailChangeFormData {
+    token: String,
+    email: String,
+}
+
+/// Mail a confirmation link to the new address: the address of the subscriber only
+/// changes once they follow it.
+///
+/// The page reads the same whether or not the new address is already subscribed, so
+/// that it cannot be used to find out who receives the newsletter: the owner of that
+/// address gets a notice instead of a confirmation link.
+#[tracing::instrument(
+    name = "Request a change of email address",
+    skip(form, pool, links, email_client, base_url, settings, outbox_settings)
+)]
+pub async fn change_email(
+    form: web::Form<EmailChangeFormData>,
+    pool: web::Data<PgPool>,
+    links: web::Data<SubscriberLinks>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<SubscriptionSettings>,
+    outbox_settings: web::Data<ConfirmationOutboxSettings>,
+) -> Result<HttpResponse, PreferencesError> {
+    let EmailChangeFormData { token, email } = form.0;
+    let subscriber_id = links
+        .verify(LinkPurpose::Preferences, &token)
+        .map_err(PreferencesError::InvalidToken)?;
+    let preferences_page = preferences_page(&token);
+    let new_email = match SubscriberEmail::parse(email.trim().to_string()) {
+        Ok(new_email) => new_email,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other(&preferences_page));
+        }
+    };
+    let subscriber = get_subscriber(&pool, subscriber_id)
+        .await
+        .context("Failed to retrieve the subscriber.")?;
+    let subscriber = match subscriber {
+        Some(subscriber) if subscriber.status == "confirmed" => subscriber,
+        _ => return Ok(not_subscribed_page()),
+    };
+    if subscriber.email == new_email.as_ref() {
+        FlashMessage::error("This is already your email address.").send();
+        return Ok(see_other(&preferences_page));
+    }
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    // Only the latest request can be confirmed.
+    delete_email_change_requests(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to invalidate the previous changes of email address.")?;
+    let is_taken = is_subscribed(&mut transaction, &new_email)
+        .await
+        .context("Failed to check whether the new address is already subscribed.")?;
+    let subscription_token = generate_subscription_token();
+    if !is_taken {
+        store_token(
+            &mut transaction,
+            subscriber_id,
+            &subscription_token,
+            settings.confirmation_token_ttl(),
+        )
+        .await
+        .context("Failed to store the token confirming the new address.")?;
+        store_email_change_request(&mut transaction, &subscription_token, new_email.as_ref())
+            .await
+            .context("Failed to store the change of email address.")?;
+        enqueue_confirmation_email(&mut transaction, &subscription_token)
+            .await
+            .context("Failed to write the confirmation email to the outbox.")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction.")?;
+    if is_taken {
+        // Nothing is waiting on the notice: it is not worth retrying.
+        if let Err(e) = send_address_taken_notice(&email_client, &new_email).await {
+            tracing::warn!(error.cause_chain = ?e, "Failed to send the address taken notice.");
+        }
+    } else {
+        // If the email provider is down the email stays in the outbox and the
+        // dispatcher retries it later.
+        let _ = dispatch_confirmation_email_now(
+            &pool,
+            &email_client,
+            &base_url.0,
+            &outbox_settings,
+            &subscription_token,
+        )
+        .await;
+    }
+    FlashMessage::info(format!(
+        "We have sent a confirmation link to {}. Your address will change once you follow it.",
+        new_email.as_ref()
+    ))
+    .send();
+    Ok(see_other(&preferences_page))
+}
+
+#[tracing::instrument(
+    name = "Send a confirmation email to a new address",
+    skip(email_client, base_url, subscription_token)
+)]
+pub async fn send_email_change_confirmation(
+    email_client: &EmailClient,
+    new_email: &SubscriberEmail,
+    base_url: &str,
+    subscription_token: &str,
+) -> Result<(), EmailError> {
+    let confirmation_link = format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        base_url, subscription_token,
+    );
+    let html_body = format!(
+        "You asked to receive our newsletter at this address.<br />\
+        Click <a href=\"{}\">here</a> to confirm it.<br />\
+        If you did not ask for this, you can ignore this email.",
+        confirmation_link
+    );
+    email_client
+        .send_email(new_email, "Confirm your new address", &html_body, None)
+        .await
+}
+
+#[tracing::instrument(
+    name = "Send a notice to an address that is already subscribed",
+    skip(email_client)
+)]
+async fn send_address_taken_notice(
+    email_client: &EmailClient,
+    new_email: &SubscriberEmail,
+) -> Result<(), anyhow::Error> {
+    let html_body = "Somebody asked to receive our newsletter at this address, \
+        which is already subscribed to it.<br />\
+        Nothing has changed: you can ignore this email.";
+    email_client
+        .send_email(new_email, "Your subscription", html_body, None)
+        .await?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Store a change of email address",
+    skip(transaction, subscription_token)
+)]
+pub async fn store_email_change_request(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscription_token: &str,
+    new_email: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO email_change_requests (subscription_token, new_email)
+        VALUES ($1, $2)
+        "#,
+        subscription_token,
+        new_email,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Check whether an address is subscribed", skip(transaction))]
+async fn is_subscribed(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<bool, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"SELECT EXISTS(SELECT 1 FROM subscriptions WHERE email = $1) as "is_subscribed!""#,
+        email.as_ref(),
+    )
+    .fetch_one(transaction)
+    .await?;
+    Ok(r.is_subscribed)
+}
+
+#[tracing::instrument(
+    name = "Delete the pending changes of email address",
+    skip(transaction)
+)]
+async fn delete_email_change_requests(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    // Requests go away with their token.
+    sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens t
+        USING email_change_requests r
+        WHERE r.subscription_token = t.subscription_token AND t.subscriber_id = $1
+        "#,
+        subscriber_id,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+fn preferences_page(token: &str) -> String {
+    format!(
+        "/subscriptions/preferences?token={}",
+        urlencoding::encode(token)
+    )
+}
+
+#[tracing::instrument(name = "Get subscriber", skip(pool))]
+async fn get_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<Subscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT email, name, status, digest_frequency
+        FROM subscriptions
+        WHERE id = $1
+        "#,
+        subscriber_id,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// Every list, and whether the subscriber belongs to it
+#[tracing::instrument(name = "Get lists of subscriber", skip(pool))]
+async fn get_lists(pool: &PgPool, subscriber_id: Uuid) -> Result<Vec<(String, bool)>, sqlx::Error> {
+    let lists = sqlx::query!(
+        r#"
+        SELECT l.name, sl.subscriber_id IS NOT NULL as "is_member!"
+        FROM lists l
+        LEFT JOIN subscription_lists sl ON sl.list_id = l.list_id AND sl.subscriber_id = $1
+        ORDER BY l.name
+        "#,
+        subscriber_id,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(lists.into_iter().map(|l| (l.name, l.is_member)).collect())
+}
+
+#[derive(thiserror::Error)]
+pub enum PreferencesError {
+    #[error("The preferences link is invalid.")]
+    InvalidToken(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl Debug for PreferencesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PreferencesError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PreferencesError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            PreferencesError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt::Debug;
+
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::subscriber_links::{LinkPurpose, SubscriberLinks};
+use crate::utils::error_chain_fmt;
+
+#[derive(Deserialize)]
+pub struct UnsubscribeParameters {
+    token: String,
+}
+
+// Following the link only renders a confirmation form: mail scanners and link
+// prefetchers issue GET requests, and they should not unsubscribe anybody.
+#[tracing::instrument(name = "Render the unsubscribe form", skip(parameters, links))]
+pub async fn unsubscribe_form(
+    parameters: web::Query<UnsubscribeParameters>,
+    links: web::Data<SubscriberLinks>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    links
+        .verify(LinkPurpose::Unsubscribe, &parameters.token)
+        .map_err(UnsubscribeError::InvalidToken)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Unsubscribe</title>
+				</head>
+				<body>
+					<p>Do you want to stop receiving our newsletter?</p>
+					<form action="/subscriptions/unsubscribe" method="post">
+						<input hidden type="text" name="token" value="{}">
+						<button type="submit">Unsubscribe</button>
+					</form>
+				</body>
+				</html>
+				"#,
+            htmlescape::encode_minimal(&parameters.token)
+        )))
+}
+
+#[tracing::instrument(name = "Unsubscribe a subscriber", skip(form, pool, links))]
+pub async fn unsubscribe(
+    form: web::Form<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+    links: web::Data<SubscriberLinks>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    let subscriber_id = links
+        .verify(LinkPurpose::Unsubscribe, &form.token)
+        .map_err(UnsubscribeError::InvalidToken)?;
+    mark_subscriber_as_unsubscribed(&pool, subscriber_id)
+        .await
+        .context("A database error has occurred while unsubscribing the subscriber")?;
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"
+				<!DOCTYPE html>
+				<html lang="en">
+				<head>
+					<meta http-equiv="content-type" content="text/html; charset=utf-8">
+					<title>Unsubscribed</title>
+				</head>
+				<body>
+					<p>You have been unsubscribed. You will not receive any further issues.</p>
+				</body>
+				</html>
+				"#,
+    ))
+}
+
+// RFC 8058 one-click unsubscribe: the mail client POSTs `List-Unsubscribe=One-Click`
+// to the URL advertised in the `List-Unsubscribe` header, without any user interaction
+// on our side.
+#[tracing::instrument(name = "One-click unsubscribe", skip(parameters, pool, links))]
+pub async fn unsubscribe_one_click(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+    links: web::Data<SubscriberLinks>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    let subscriber_id = links
+        .verify(LinkPurpose::Unsubscribe, &parameters.token)
+        .map_err(UnsubscribeError::InvalidToken)?;
+    mark_subscriber_as_unsubscribed(&pool, subscriber_id)
+        .await
+        .context("A database error has occurred while unsubscribing the subscriber")?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(name = "Mark a subscriber as unsubscribed", skip(subscriber_id, pool))]
+pub async fn mark_subscriber_as_unsubscribed(
+    pool: &PgPool,
+    subscriber_id: uuid::Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions SET status = 'unsubscribed' WHERE id = $1
+        "#,
+        subscriber_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("The unsubscribe link is invalid.")]
+    InvalidToken(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UnsubscribeError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            UnsubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
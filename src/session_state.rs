@@ -20,6 +20,11 @@ impl TypedSession {
     pub fn get_user_id(&self) -> Result<Option<Uuid>, serde_json::Error> {
         self.0.get(Self::USER_ID_KEY)
     }
+
+    // Removes the session state server-side and expires the session cookie.
+    pub fn log_out(self) {
+        self.0.purge()
+    }
 }
 
 impl FromRequest for TypedSession {
@@ -1,8 +1,10 @@
 use std::net::TcpListener;
+use std::sync::Arc;
 
 use actix_session::storage::RedisSessionStore;
 use actix_session::SessionMiddleware;
 use actix_web::cookie::Key;
+use actix_web::middleware::from_fn;
 use actix_web::{dev::Server, web, App, HttpServer};
 use actix_web_flash_messages::storage::CookieMessageStore;
 use actix_web_flash_messages::FlashMessagesFramework;
@@ -11,12 +13,23 @@ use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
 use tracing_actix_web::TracingLogger;
 
-use crate::configuration::{DatabaseSettings, Settings};
+use crate::authentication::reject_anonymous_users;
+use crate::configuration::{
+    ConfirmationOutboxSettings, DatabaseSettings, Settings, SubscriptionSettings,
+};
 use crate::email_client::EmailClient;
 use crate::routes::{
-    admin_dashboard, change_password, change_password_form, confirm, health_check, home, login,
-    login_form, publish_newsletter, subscribe,
+    admin_dashboard, admin_email_form, audience, change_admin_email, change_email, change_password,
+    change_password_form, confirm, create_draft, create_list, data_access_page, data_request_form,
+    edit_draft_form, erase_subscriber_data, export_subscriber_data, failed_deliveries,
+    health_check, home, import_form, import_subscribers, issue_web_version, log_out, login,
+    login_form, new_draft_form, newsletter_issues, preferences_form, preview_issue,
+    publish_newsletter, request_data_access, requeue_all_failed_deliveries,
+    requeue_failed_delivery, resend_confirmation, schedule_issue, send_test_copy, subscribe,
+    tag_subscriber, unschedule_issue, unsubscribe, unsubscribe_form, unsubscribe_one_click,
+    untag_subscriber, update_draft, update_preferences,
 };
+use crate::subscriber_links::SubscriberLinks;
 
 pub struct Application {
     port: u16,
@@ -26,27 +39,16 @@ pub struct Application {
 pub struct ApplicationBaseUrl(pub String);
 
 impl Application {
-    pub async fn build(configuration: Settings) -> Result<Self, anyhow::Error> {
+    /// `email_client` is shared with the background tasks, so that they all go through
+    /// the same rate limit and circuit breaker.
+    pub async fn build(
+        configuration: Settings,
+        email_client: Arc<EmailClient>,
+    ) -> Result<Self, anyhow::Error> {
         // Build postgres connection pool
         let db_connection_pool = Application::get_connection_pool(&configuration.database)
             .await
             .expect("Failed to connect to Postgres");
-        // Build an `EmailClient`
-        let sender_email = configuration
-            .email_client
-            .sender()
-            .expect("Invalid sender email address");
-        let timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration
-                .email_client
-                .authorization_token
-                .expose_secret()
-                .to_string(),
-            timeout,
-        );
         // Build `TcpListener`
         let address = format!(
             "{}:{}",
@@ -62,6 +64,8 @@ impl Application {
             configuration.application.base_url,
             HmacSecret(configuration.application.hmac_secret),
             configuration.redis_uri,
+            configuration.subscriptions,
+            configuration.confirmation_outbox,
         )
         .await?;
         // Save the bound port in the `Application` fields
@@ -81,18 +85,25 @@ impl Application {
         self.port
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn run(
         listener: TcpListener,
         db_connection_pool: PgPool,
-        email_client: EmailClient,
+        email_client: Arc<EmailClient>,
         base_url: String,
         hmac_secret: HmacSecret,
         redis_uri: Secret<String>,
+        subscription_settings: SubscriptionSettings,
+        confirmation_outbox_settings: ConfirmationOutboxSettings,
     ) -> Result<Server, anyhow::Error> {
         let db_connection_pool = web::Data::new(db_connection_pool);
-        let email_client = web::Data::new(email_client);
+        let email_client = web::Data::from(email_client);
+        let subscriber_links =
+            web::Data::new(SubscriberLinks::new(base_url.clone(), hmac_secret.clone()));
         let base_url = web::Data::new(ApplicationBaseUrl(base_url));
         let hmac_secret = web::Data::new(hmac_secret);
+        let subscription_settings = web::Data::new(subscription_settings);
+        let confirmation_outbox_settings = web::Data::new(confirmation_outbox_settings);
         let secret_key = Key::from(hmac_secret.0.expose_secret().as_bytes());
         let message_store = CookieMessageStore::builder(secret_key.clone()).build();
         let message_framework = FlashMessagesFramework::builder(message_store).build();
@@ -108,17 +119,116 @@ impl Application {
                 .route("/healthz", web::get().to(health_check))
                 .route("/subscriptions", web::post().to(subscribe))
                 .route("/subscriptions/confirm", web::get().to(confirm))
+                .route(
+                    "/subscriptions/confirm/resend",
+                    web::post().to(resend_confirmation),
+                )
+                .route("/subscriptions/data", web::get().to(data_request_form))
+                .route("/subscriptions/data", web::post().to(request_data_access))
+                .route(
+                    "/subscriptions/data/access",
+                    web::get().to(data_access_page),
+                )
+                .route(
+                    "/subscriptions/data/export",
+                    web::get().to(export_subscriber_data),
+                )
+                .route(
+                    "/subscriptions/data/erase",
+                    web::post().to(erase_subscriber_data),
+                )
+                .route(
+                    "/subscriptions/preferences",
+                    web::get().to(preferences_form),
+                )
+                .route(
+                    "/subscriptions/preferences",
+                    web::post().to(update_preferences),
+                )
+                .route(
+                    "/subscriptions/preferences/email",
+                    web::post().to(change_email),
+                )
+                .route(
+                    "/subscriptions/unsubscribe",
+                    web::get().to(unsubscribe_form),
+                )
+                .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
+                .route(
+                    "/subscriptions/unsubscribe/one-click",
+                    web::get().to(unsubscribe_form),
+                )
+                .route(
+                    "/subscriptions/unsubscribe/one-click",
+                    web::post().to(unsubscribe_one_click),
+                )
                 .route("/newsletters", web::post().to(publish_newsletter))
+                .route(
+                    "/issues/{newsletter_issue_id}",
+                    web::get().to(issue_web_version),
+                )
                 .route("/login", web::get().to(login_form))
                 .route("/login", web::post().to(login))
                 .route("/", web::get().to(home))
-                .route("/admin/dashboard", web::get().to(admin_dashboard))
-                .route("/admin/password", web::get().to(change_password_form))
-                .route("/admin/password", web::post().to(change_password))
+                .service(
+                    web::scope("/admin")
+                        .wrap(from_fn(reject_anonymous_users))
+                        .route("/dashboard", web::get().to(admin_dashboard))
+                        .route("/password", web::get().to(change_password_form))
+                        .route("/password", web::post().to(change_password))
+                        .route("/email", web::get().to(admin_email_form))
+                        .route("/email", web::post().to(change_admin_email))
+                        .route("/logout", web::post().to(log_out))
+                        .route("/newsletters", web::get().to(newsletter_issues))
+                        .route("/newsletters", web::post().to(create_draft))
+                        .route("/newsletters/new", web::get().to(new_draft_form))
+                        .route(
+                            "/newsletters/{newsletter_issue_id}/edit",
+                            web::get().to(edit_draft_form),
+                        )
+                        .route(
+                            "/newsletters/{newsletter_issue_id}/edit",
+                            web::post().to(update_draft),
+                        )
+                        .route(
+                            "/newsletters/{newsletter_issue_id}/preview",
+                            web::get().to(preview_issue),
+                        )
+                        .route(
+                            "/newsletters/{newsletter_issue_id}/test",
+                            web::post().to(send_test_copy),
+                        )
+                        .route(
+                            "/newsletters/{newsletter_issue_id}/schedule",
+                            web::post().to(schedule_issue),
+                        )
+                        .route(
+                            "/newsletters/{newsletter_issue_id}/unschedule",
+                            web::post().to(unschedule_issue),
+                        )
+                        .route("/audience", web::get().to(audience))
+                        .route("/audience/lists", web::post().to(create_list))
+                        .route("/audience/tags", web::post().to(tag_subscriber))
+                        .route("/audience/tags/remove", web::post().to(untag_subscriber))
+                        .route("/subscribers/import", web::get().to(import_form))
+                        .route("/subscribers/import", web::post().to(import_subscribers))
+                        .route("/deliveries/failed", web::get().to(failed_deliveries))
+                        .route(
+                            "/deliveries/failed/requeue",
+                            web::post().to(requeue_failed_delivery),
+                        )
+                        .route(
+                            "/deliveries/failed/requeue-all",
+                            web::post().to(requeue_all_failed_deliveries),
+                        ),
+                )
                 .app_data(db_connection_pool.clone())
                 .app_data(email_client.clone())
                 .app_data(base_url.clone())
                 .app_data(hmac_secret.clone())
+                .app_data(subscriber_links.clone())
+                .app_data(subscription_settings.clone())
+                .app_data(confirmation_outbox_settings.clone())
         })
         .listen(listener)?
         .run();
@@ -0,0 +1,81 @@
+use crate::configuration::{Settings, SubscriptionSettings};
+use crate::startup::Application;
+use chrono::Utc;
+use sqlx::PgPool;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct CleanupOutcome {
+    pub deleted_tokens: u64,
+    pub deleted_subscribers: u64,
+}
+
+/// Purges confirmation and data access tokens that expired more than the retention window
+/// ago, together with subscribers who never confirmed and have been pending for longer than that.
+#[tracing::instrument(skip_all, fields(deleted_tokens, deleted_subscribers), err)]
+pub async fn purge_stale_subscriptions(
+    pool: &PgPool,
+    settings: &SubscriptionSettings,
+) -> Result<CleanupOutcome, anyhow::Error> {
+    let cutoff = Utc::now() - settings.retention();
+    let mut transaction = pool.begin().await?;
+    // Stale tokens go first, so that the pending subscribers they belonged to are
+    // purged in the same run.
+    let deleted_confirmation_tokens = sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens WHERE expires_at < $1
+        "#,
+        cutoff,
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    let deleted_data_access_tokens = sqlx::query!(
+        r#"
+        DELETE FROM data_access_tokens WHERE expires_at < $1
+        "#,
+        cutoff,
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    let deleted_tokens = deleted_confirmation_tokens + deleted_data_access_tokens;
+    // A pending subscriber who asked for a new link recently still has a token and
+    // is left alone until that one goes stale too.
+    let deleted_subscribers = sqlx::query!(
+        r#"
+        DELETE FROM subscriptions s
+        WHERE s.status = 'pending_confirmation'
+            AND s.subscribed_at < $1
+            AND NOT EXISTS (
+                SELECT 1 FROM subscription_tokens t WHERE t.subscriber_id = s.id
+            )
+        "#,
+        cutoff,
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    transaction.commit().await?;
+    tracing::Span::current()
+        .record("deleted_tokens", deleted_tokens)
+        .record("deleted_subscribers", deleted_subscribers);
+    Ok(CleanupOutcome {
+        deleted_tokens,
+        deleted_subscribers,
+    })
+}
+
+async fn cleanup_loop(pool: PgPool, settings: SubscriptionSettings) -> Result<(), anyhow::Error> {
+    loop {
+        // Errors are already logged by the instrumentation: try again on the next tick.
+        let _ = purge_stale_subscriptions(&pool, &settings).await;
+        tokio::time::sleep(settings.cleanup_interval()).await;
+    }
+}
+
+// Only returns if Postgres cannot be reached on startup: the loop retries its own
+// failures. Raced against the API server in `main`.
+pub async fn run_cleanup_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
+    let connection_pool = Application::get_connection_pool(&configuration.database).await?;
+    cleanup_loop(connection_pool, configuration.subscriptions).await
+}
@@ -0,0 +1,75 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApp};
+
+async fn login(app: &TestApp) {
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    }))
+    .await;
+}
+
+async fn stored_email(app: &TestApp) -> Option<String> {
+    sqlx::query!(
+        "SELECT email FROM users WHERE username = $1",
+        app.test_user.username
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .email
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_change_your_email() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.post_admin_email("admin@example.com").await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+    assert_eq!(stored_email(&app).await, None);
+}
+
+#[tokio::test]
+async fn admins_can_set_and_remove_their_email() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+
+    // Act - Part 1 - Set it
+    let response = app.post_admin_email("admin@example.com").await;
+    assert_is_redirect_to(&response, "/admin/email");
+    let html_page = app.get_admin_email_html().await;
+    assert!(html_page.contains("<p><i>Your email address has been saved.</i></p>"));
+    assert!(html_page.contains(r#"value="admin@example.com""#));
+    assert_eq!(
+        stored_email(&app).await.as_deref(),
+        Some("admin@example.com")
+    );
+
+    // Act - Part 2 - Remove it
+    app.post_admin_email("").await;
+    let html_page = app.get_admin_email_html().await;
+    assert!(html_page.contains("<p><i>Your email address has been removed.</i></p>"));
+    assert_eq!(stored_email(&app).await, None);
+}
+
+#[tokio::test]
+async fn invalid_emails_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    app.post_admin_email("admin@example.com").await;
+
+    // Act
+    let response = app.post_admin_email("not-an-email").await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/admin/email");
+    assert_eq!(
+        stored_email(&app).await.as_deref(),
+        Some("admin@example.com")
+    );
+}
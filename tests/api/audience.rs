@@ -0,0 +1,260 @@
+use uuid::Uuid;
+use wiremock::matchers::any;
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{
+    assert_is_redirect_to, create_unconfirmed_subscriber_with_body, spawn_app, TestApp,
+};
+
+async fn login(app: &TestApp) {
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    }))
+    .await;
+}
+
+async fn create_confirmed_subscriber_with_body(app: &TestApp, body: &str) {
+    let confirmation_link = create_unconfirmed_subscriber_with_body(app, body.into()).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+// Publish an issue to `audience` and return the addresses it is queued for
+async fn publish_to(app: &TestApp, audience: serde_json::Value) -> Vec<String> {
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            },
+            "audience": audience,
+            "idempotency_key": Uuid::new_v4().to_string()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    let recipients = sqlx::query!(
+        r#"
+        SELECT q.subscriber_email
+        FROM issue_delivery_queue q
+        JOIN newsletter_issues i USING (newsletter_issue_id)
+        WHERE i.published_at = (SELECT MAX(published_at) FROM newsletter_issues)
+        ORDER BY q.subscriber_email
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    recipients.into_iter().map(|r| r.subscriber_email).collect()
+}
+
+#[tokio::test]
+async fn subscribers_can_pick_lists_when_subscribing() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    app.post_create_list("rust").await;
+    app.post_create_list("go").await;
+    app.post_create_list("zig").await;
+
+    // Act
+    create_unconfirmed_subscriber_with_body(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&lists=rust&lists=go".into(),
+    )
+    .await;
+
+    // Assert
+    let lists = sqlx::query!(
+        r#"
+        SELECT l.name
+        FROM subscription_lists sl JOIN lists l USING (list_id)
+        ORDER BY l.name
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    let lists: Vec<_> = lists.into_iter().map(|l| l.name).collect();
+    assert_eq!(lists, vec!["go", "rust"]);
+}
+
+#[tokio::test]
+async fn subscribing_to_an_unknown_list_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=rust".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn issues_only_go_to_their_audience() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    app.post_create_list("rust").await;
+    app.post_create_list("go").await;
+    create_confirmed_subscriber_with_body(&app, "name=a&email=a%40example.com&lists=rust").await;
+    create_confirmed_subscriber_with_body(&app, "name=b&email=b%40example.com&lists=go").await;
+    create_confirmed_subscriber_with_body(&app, "name=c&email=c%40example.com").await;
+    let response = app.post_tag_subscriber("b@example.com", "vip").await;
+    assert_is_redirect_to(&response, "/admin/audience");
+
+    // Act & Assert
+    assert_eq!(
+        publish_to(&app, serde_json::json!({})).await,
+        vec!["a@example.com", "b@example.com", "c@example.com"]
+    );
+    assert_eq!(
+        publish_to(&app, serde_json::json!({ "lists": ["rust"] })).await,
+        vec!["a@example.com"]
+    );
+    assert_eq!(
+        publish_to(
+            &app,
+            serde_json::json!({ "lists": ["rust"], "tags": ["vip"] })
+        )
+        .await,
+        vec!["a@example.com", "b@example.com"]
+    );
+    assert_eq!(
+        publish_to(&app, serde_json::json!({ "exclude_lists": ["rust"] })).await,
+        vec!["b@example.com", "c@example.com"]
+    );
+    assert_eq!(
+        publish_to(
+            &app,
+            serde_json::json!({ "lists": ["rust", "go"], "exclude_tags": ["vip"] })
+        )
+        .await,
+        vec!["a@example.com"]
+    );
+}
+
+#[tokio::test]
+async fn publishing_to_an_unknown_list_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            },
+            "audience": { "exclude_lists": ["rust"] },
+            "idempotency_key": Uuid::new_v4().to_string()
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn scheduled_issues_go_to_the_audience_picked_when_scheduling() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    app.post_create_list("rust").await;
+    create_confirmed_subscriber_with_body(&app, "name=a&email=a%40example.com&lists=rust").await;
+    create_confirmed_subscriber_with_body(&app, "name=c&email=c%40example.com").await;
+    app.post_new_draft(&serde_json::json!({
+        "title": "Draft title",
+        "html_content": "<p>Body</p>",
+        "text_content": "Body",
+    }))
+    .await;
+    let newsletter_issue_id = sqlx::query!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .newsletter_issue_id;
+
+    // Act
+    let response = app
+        .post_issue_form(
+            &format!("/admin/newsletters/{}/schedule", newsletter_issue_id),
+            &serde_json::json!({
+                "scheduled_for": "2999-01-01T10:00",
+                "lists": "rust",
+                "tags": "",
+                "exclude_lists": "",
+                "exclude_tags": "",
+            }),
+        )
+        .await;
+    assert_is_redirect_to(&response, "/admin/newsletters");
+    sqlx::query!("UPDATE newsletter_issues SET scheduled_for = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.publish_due_issues().await;
+
+    // Assert
+    let recipients = sqlx::query!("SELECT subscriber_email FROM issue_delivery_queue")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(recipients.len(), 1);
+    assert_eq!(recipients[0].subscriber_email, "a@example.com");
+}
+
+#[tokio::test]
+async fn admins_can_create_lists_and_tag_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    create_confirmed_subscriber_with_body(&app, "name=a&email=a%40example.com").await;
+
+    // Act - Part 1 - Create a list
+    let response = app.post_create_list("rust").await;
+    assert_is_redirect_to(&response, "/admin/audience");
+    let html_page = app.get_audience_html().await;
+    assert!(html_page.contains("The list rust has been created."));
+
+    // Act - Part 2 - Lists are unique
+    app.post_create_list("rust").await;
+    let html_page = app.get_audience_html().await;
+    assert!(html_page.contains("There is already a list named rust."));
+
+    // Act - Part 3 - Tag a subscriber
+    app.post_tag_subscriber("a@example.com", "vip").await;
+    let html_page = app.get_audience_html().await;
+    assert!(html_page.contains("a@example.com has been tagged vip."));
+    assert!(html_page.contains("<tr><td>vip</td><td>1</td></tr>"));
+
+    // Act - Part 4 - Unknown subscribers cannot be tagged
+    app.post_tag_subscriber("nobody@example.com", "vip").await;
+    let html_page = app.get_audience_html().await;
+    assert!(html_page.contains("nobody@example.com is not subscribed"));
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_manage_lists() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.post_create_list("rust").await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+}
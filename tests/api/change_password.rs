@@ -53,15 +53,13 @@ async fn new_password_fields_must_match() {
             "new_password_check": &another_new_password,
         }))
         .await;
-    // FIXME
-    // assert_is_redirect_to(&response, "/admin/password");
+    assert_is_redirect_to(&response, "/admin/password");
     // Act - Part 3 - Follow the redirect
-    // FIXME
-    // let html_page = app.get_change_password_html().await;
-    // assert!(html_page.contains(
-    //     "<p><i>You entered two different new passwords - \
-    //      the field values must match.</i></p>"
-    // ));
+    let html_page = app.get_change_password_html().await;
+    assert!(html_page.contains(
+        "<p><i>You entered two different new passwords - \
+         the field values must match.</i></p>"
+    ));
 }
 
 #[tokio::test]
@@ -88,20 +86,24 @@ async fn current_password_must_be_valid() {
         .await;
 
     // Assert
-    // FIXME
-    // assert_is_redirect_to(&response, "/admin/password");
+    assert_is_redirect_to(&response, "/admin/password");
 
     // Act - Part 3 - Follow the redirect
     let html_page = app.get_change_password_html().await;
-    // FIXME
-    // assert!(html_page.contains("<p><i>The current password is incorrect.</i></p>"));
+    assert!(html_page.contains("<p><i>The current password is incorrect.</i></p>"));
 }
 
 #[tokio::test]
 async fn new_password_must_be_greater_than_12_characters_but_less_than_128_characters() {
     // Arrange
     let app = spawn_app().await;
-    // Act - Part 1 - Try to change the password
+    // Act - Part 1 - Login
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    }))
+    .await;
+    // Act - Part 2 - Try to change the password
     let response = app
         .post_change_password(&serde_json::json!({
             "current_password": &app.test_user.password,
@@ -110,10 +112,45 @@ async fn new_password_must_be_greater_than_12_characters_but_less_than_128_chara
         }))
         .await;
     // Assert
-    // FIXME
-    // assert_is_redirect_to(&response, "/admin/password");
-    // Act - Part 2 - Follow the redirect
+    assert_is_redirect_to(&response, "/admin/password");
+    // Act - Part 3 - Follow the redirect
     let html_page = app.get_change_password_html().await;
-    // FIXME
-    // assert!(html_page.contains("<p><i>The password you entered is too short or too long.</i></p>"));
+    assert!(html_page.contains("<p><i>The password you entered is too short or too long.</i></p>"));
+}
+
+#[tokio::test]
+async fn changing_password_works() {
+    // Arrange
+    let app = spawn_app().await;
+    let new_password = Uuid::new_v4().to_string();
+
+    // Act - Part 1 - Login
+    let login_body = serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    });
+    let response = app.post_login(&login_body).await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+
+    // Act - Part 2 - Change password
+    let response = app
+        .post_change_password(&serde_json::json!({
+            "current_password": &app.test_user.password,
+            "new_password": &new_password,
+            "new_password_check": &new_password,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/password");
+
+    // Act - Part 3 - Follow the redirect
+    let html_page = app.get_change_password_html().await;
+    assert!(html_page.contains("<p><i>Your password has been changed.</i></p>"));
+
+    // Act - Part 4 - Login using the new password
+    let login_body = serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &new_password
+    });
+    let response = app.post_login(&login_body).await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
 }
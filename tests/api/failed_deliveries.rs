@@ -0,0 +1,242 @@
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{
+    accept_batch, assert_is_redirect_to, batch_response, create_confirmed_subscriber,
+    create_unconfirmed_subscriber_with_email, spawn_app, TestApp,
+};
+
+async fn publish_newsletter(app: &TestApp) {
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            },
+            "idempotency_key": Uuid::new_v4().to_string()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+}
+
+async fn count_dead_letters(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM issue_delivery_dead_letters"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+#[tokio::test]
+async fn transient_failures_are_retried_later() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - The task is still queued, with a retry scheduled in the future
+    let task = sqlx::query!(
+        r#"SELECT n_retries, last_error, execute_after > now() as "in_the_future!"
+        FROM issue_delivery_queue"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(task.n_retries, 1);
+    assert!(task.last_error.is_some());
+    assert!(task.in_the_future);
+    assert_eq!(count_dead_letters(&app).await, 0);
+}
+
+#[tokio::test]
+async fn deliveries_are_dead_lettered_after_the_maximum_number_of_attempts() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.issue_delivery.max_attempts = 2;
+    app.issue_delivery.initial_backoff_milliseconds = 0;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let queued = sqlx::query!("SELECT subscriber_email FROM issue_delivery_queue")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(queued.is_empty());
+    let dead_letter = sqlx::query!("SELECT n_attempts FROM issue_delivery_dead_letters")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(dead_letter.n_attempts, 2);
+}
+
+#[tokio::test]
+async fn rate_limited_deliveries_are_retried_without_counting_an_attempt() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.issue_delivery.max_attempts = 1;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "60"))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - Rescheduled for when the provider told us to come back
+    let task = sqlx::query!(
+        r#"SELECT n_retries, execute_after > now() + interval '30 seconds' as "after_retry_after!"
+        FROM issue_delivery_queue"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(task.n_retries, 0);
+    assert!(task.after_retry_after);
+    assert_eq!(count_dead_letters(&app).await, 0);
+}
+
+#[tokio::test]
+async fn permanent_failures_are_dead_lettered_straight_away() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(count_dead_letters(&app).await, 1);
+}
+
+#[tokio::test]
+async fn recipients_rejected_within_a_batch_are_dead_lettered_on_their_own() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let confirmation_links =
+        create_unconfirmed_subscriber_with_email(&app, "le_guin%40gmail.com").await;
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(|request: &wiremock::Request| batch_response(request, &["le_guin@gmail.com"]))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let dead_letter =
+        sqlx::query!("SELECT subscriber_email, last_error FROM issue_delivery_dead_letters")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(dead_letter.subscriber_email, "le_guin@gmail.com");
+    assert!(dead_letter.last_error.contains("Inactive recipient."));
+    let queued = sqlx::query!("SELECT subscriber_email FROM issue_delivery_queue")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(queued.is_empty());
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_failed_deliveries() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.get_failed_deliveries().await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn failed_deliveries_can_be_requeued_from_the_admin_area() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+    app.dispatch_all_pending_emails().await;
+    let issue_id = sqlx::query!("SELECT newsletter_issue_id FROM issue_delivery_dead_letters")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .newsletter_issue_id;
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    }))
+    .await;
+
+    // Act - Part 1 - Inspect the failed deliveries
+    let html_page = app.get_failed_deliveries_html().await;
+    assert!(html_page.contains("ursula_le_guin@gmail.com"));
+
+    // Act - Part 2 - Requeue the failed delivery
+    let response = app
+        .post_requeue_failed_delivery(&serde_json::json!({
+            "newsletter_issue_id": issue_id,
+            "subscriber_email": "ursula_le_guin@gmail.com",
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/deliveries/failed");
+    let html_page = app.get_failed_deliveries_html().await;
+    assert!(html_page.contains("<p><i>The delivery has been requeued.</i></p>"));
+
+    // Assert
+    assert_eq!(count_dead_letters(&app).await, 0);
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.dispatch_all_pending_emails().await;
+}
@@ -1,14 +1,69 @@
-use crate::helpers::spawn_app;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use z2p::configuration::get_configuration;
+
+use crate::helpers::{create_confirmed_subscriber, spawn_app};
 
 #[tokio::test]
 async fn healtz_works() {
     let app = spawn_app().await;
     let client = reqwest::Client::new();
     let response = client
-        .get(&format!("{}/healthz", &app.address))
+        .get(format!("{}/healthz", &app.address))
         .send()
         .await
         .expect("Failed to execute request");
     assert_eq!(response.status(), reqwest::StatusCode::OK);
-    assert_eq!(Some(2), response.content_length());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "ok");
+    assert_eq!(body["email_circuit_breaker"], "closed");
+}
+
+#[tokio::test]
+async fn healthz_reports_failures_of_the_background_tasks() {
+    // Arrange
+    let app = spawn_app().await;
+    let failure_threshold = get_configuration()
+        .unwrap()
+        .email_client
+        .circuit_breaker
+        .failure_threshold;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&app.email_server)
+        .await;
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            },
+            "idempotency_key": uuid::Uuid::new_v4().to_string()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    // Act - Make the delivery worker fail until the circuit opens
+    for _ in 0..failure_threshold {
+        sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+            .execute(&app.db_pool)
+            .await
+            .unwrap();
+        app.dispatch_all_pending_emails().await;
+    }
+
+    // Assert
+    let body: serde_json::Value = app
+        .http_client
+        .get(format!("{}/healthz", &app.address))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body["email_circuit_breaker"], "open");
 }
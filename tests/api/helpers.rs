@@ -1,11 +1,23 @@
+use std::sync::Arc;
+
 use argon2::password_hash::SaltString;
 use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
 use once_cell::sync::Lazy;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
-use wiremock::MockServer;
-use z2p::configuration::{get_configuration, DatabaseSettings};
-use z2p::startup::Application;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use z2p::configuration::{
+    get_configuration, ConfirmationOutboxSettings, DatabaseSettings, IssueDeliverySettings,
+    SubscriptionSettings,
+};
+use z2p::confirmation_email_outbox::try_dispatch_confirmation_email;
+use z2p::email_client::EmailClient;
+use z2p::issue_delivery_worker::{try_execute_task, ExecutionOutcome};
+use z2p::issue_digest::try_send_digest;
+use z2p::issue_scheduler::try_publish_due_issue;
+use z2p::startup::{Application, HmacSecret};
+use z2p::subscriber_links::SubscriberLinks;
 use z2p::telemetry::{get_subscriber, initialize_subscriber};
 
 /// Ensure tracing stack is only initialized once using `once_cell`
@@ -41,7 +53,10 @@ pub async fn spawn_app() -> TestApp {
     // create and migrate database
     configure_database(&configuration.database).await;
     // launch application as a background task
-    let application = Application::build(configuration.clone())
+    // The in-process background tasks share the application's client, as they do in
+    // `main`
+    let email_client = Arc::new(configuration.email_client.clone().client());
+    let application = Application::build(configuration.clone(), email_client.clone())
         .await
         .expect("Failed to build application");
     let application_port = application.port();
@@ -49,7 +64,7 @@ pub async fn spawn_app() -> TestApp {
     let db_pool = Application::get_connection_pool(&configuration.database)
         .await
         .expect("Failed to connect to database");
-    let _ = tokio::spawn(application.run_server_until_stopped());
+    tokio::spawn(application.run_server_until_stopped());
     let client = reqwest::Client::builder()
         .redirect(reqwest::redirect::Policy::none())
         .cookie_store(true)
@@ -62,6 +77,15 @@ pub async fn spawn_app() -> TestApp {
         email_server,
         port: application_port,
         test_user: TestUser::generate(),
+        email_client,
+        issue_delivery: configuration.issue_delivery,
+        subscriptions: configuration.subscriptions,
+        confirmation_outbox: configuration.confirmation_outbox,
+        base_url: configuration.application.base_url.clone(),
+        subscriber_links: SubscriberLinks::new(
+            configuration.application.base_url,
+            HmacSecret(configuration.application.hmac_secret),
+        ),
     };
     test_app.test_user.store(&test_app.db_pool).await;
     test_app
@@ -74,12 +98,93 @@ pub struct TestApp {
     pub db_pool: PgPool,
     pub email_server: MockServer,
     pub test_user: TestUser,
+    pub email_client: Arc<EmailClient>,
+    pub issue_delivery: IssueDeliverySettings,
+    pub subscriptions: SubscriptionSettings,
+    pub confirmation_outbox: ConfirmationOutboxSettings,
+    pub base_url: String,
+    pub subscriber_links: SubscriberLinks,
 }
 
 impl TestApp {
+    // Run the delivery worker in-process until the queue is empty
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue = try_execute_task(
+                &self.db_pool,
+                &self.email_client,
+                &self.issue_delivery,
+                &self.subscriber_links,
+            )
+            .await
+            .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    // Run the confirmation email dispatcher in-process until no email in the outbox
+    // is due
+    pub async fn dispatch_confirmation_outbox(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue = try_dispatch_confirmation_email(
+                &self.db_pool,
+                &self.email_client,
+                &self.base_url,
+                &self.confirmation_outbox,
+            )
+            .await
+            .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    // Run the digest sender in-process until no digest is due
+    pub async fn send_due_digests(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue = try_send_digest(
+                &self.db_pool,
+                &self.email_client,
+                &self.issue_delivery,
+                &self.subscriber_links,
+            )
+            .await
+            .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    // Run the issue scheduler in-process until no scheduled issue is due
+    pub async fn publish_due_issues(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_publish_due_issue(&self.db_pool).await.unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    // All the messages sent to the email API through `/email/batch` so far
+    pub async fn sent_batch_messages(&self) -> Vec<serde_json::Value> {
+        self.email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|request| request.url.path() == "/email/batch")
+            .flat_map(batch_messages)
+            .collect()
+    }
+
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         self.http_client
-            .post(&format!("{}/subscriptions", self.address))
+            .post(format!("{}/subscriptions", self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
@@ -87,6 +192,15 @@ impl TestApp {
             .expect("Failed to execute the request")
     }
 
+    pub async fn post_resend_confirmation(&self, subscription_token: &str) -> reqwest::Response {
+        self.http_client
+            .post(format!("{}/subscriptions/confirm/resend", &self.address))
+            .form(&serde_json::json!({ "subscription_token": subscription_token }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     // Extract the confirmation links embedded in the request to the email API
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
@@ -104,14 +218,113 @@ impl TestApp {
             confirmation_link.set_port(Some(self.port)).unwrap();
             confirmation_link
         };
-        let html = get_link(&body["HtmlBody"].as_str().unwrap());
-        let plain_text = get_link(&body["TextBody"].as_str().unwrap());
+        let html = get_link(body["HtmlBody"].as_str().unwrap());
+        let plain_text = get_link(body["TextBody"].as_str().unwrap());
         ConfirmationLinks { html, plain_text }
     }
 
+    // Extract the unsubscribe links embedded in a newsletter message sent to the email API
+    pub fn get_unsubscribe_links(&self, message: &serde_json::Value) -> ConfirmationLinks {
+        let get_link = |s: &str| {
+            let links: Vec<_> = linkify::LinkFinder::new()
+                .links(s)
+                .filter(|l| l.as_str().contains("/subscriptions/unsubscribe"))
+                .collect();
+            assert_eq!(links.len(), 1);
+            let mut unsubscribe_link = reqwest::Url::parse(links[0].as_str()).unwrap();
+            assert_eq!(unsubscribe_link.host_str().unwrap(), "127.0.0.1");
+            unsubscribe_link.set_port(Some(self.port)).unwrap();
+            unsubscribe_link
+        };
+        let html = get_link(message["HtmlBody"].as_str().unwrap());
+        let plain_text = get_link(message["TextBody"].as_str().unwrap());
+        ConfirmationLinks { html, plain_text }
+    }
+
+    pub async fn get_preferences(&self, token: &str) -> reqwest::Response {
+        self.http_client
+            .get(format!("{}/subscriptions/preferences", &self.address))
+            .query(&[("token", token)])
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_preferences_html(&self, token: &str) -> String {
+        self.get_preferences(token).await.text().await.unwrap()
+    }
+
+    pub async fn post_preferences<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.http_client
+            .post(format!("{}/subscriptions/preferences", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_change_email(&self, token: &str, email: &str) -> reqwest::Response {
+        self.http_client
+            .post(format!("{}/subscriptions/preferences/email", &self.address))
+            .form(&serde_json::json!({ "token": token, "email": email }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_data_request(&self, email: &str) -> reqwest::Response {
+        self.http_client
+            .post(format!("{}/subscriptions/data", &self.address))
+            .form(&serde_json::json!({ "email": email }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_data_request_html(&self) -> String {
+        self.http_client
+            .get(format!("{}/subscriptions/data", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_data_export(&self, token: &str) -> reqwest::Response {
+        self.http_client
+            .get(format!("{}/subscriptions/data/export", &self.address))
+            .query(&[("token", token)])
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_data_erasure(&self, token: &str) -> reqwest::Response {
+        self.http_client
+            .post(format!("{}/subscriptions/data/erase", &self.address))
+            .form(&serde_json::json!({ "token": token }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_unsubscribe(&self, token: &str) -> reqwest::Response {
+        self.http_client
+            .post(format!("{}/subscriptions/unsubscribe", &self.address))
+            .form(&serde_json::json!({ "token": token }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
         self.http_client
-            .post(&format!("{}/newsletters", &self.address))
+            .post(format!("{}/newsletters", &self.address))
             .basic_auth(&self.test_user.username, Some(&self.test_user.password))
             .json(&body)
             .send()
@@ -124,7 +337,7 @@ impl TestApp {
         Body: serde::Serialize,
     {
         self.http_client
-            .post(&format!("{}/login", &self.address))
+            .post(format!("{}/login", &self.address))
             // This `reqwest`` method makes sure that the body is URL-encoded
             // and the 'Content-Type' header is set accordingly
             .form(body)
@@ -135,7 +348,7 @@ impl TestApp {
 
     pub async fn get_login_html(&self) -> String {
         self.http_client
-            .get(&format!("{}/login", &self.address))
+            .get(format!("{}/login", &self.address))
             .send()
             .await
             .expect("Failed to execute request.")
@@ -146,7 +359,7 @@ impl TestApp {
 
     pub async fn get_admin_dashboard(&self) -> reqwest::Response {
         self.http_client
-            .get(&format!("{}/admin/dashboard", &self.address))
+            .get(format!("{}/admin/dashboard", &self.address))
             .send()
             .await
             .expect("Failed to execute request.")
@@ -156,9 +369,168 @@ impl TestApp {
         self.get_admin_dashboard().await.text().await.unwrap()
     }
 
+    pub async fn post_logout(&self) -> reqwest::Response {
+        self.http_client
+            .post(format!("{}/admin/logout", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_failed_deliveries(&self) -> reqwest::Response {
+        self.http_client
+            .get(format!("{}/admin/deliveries/failed", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_failed_deliveries_html(&self) -> String {
+        self.get_failed_deliveries().await.text().await.unwrap()
+    }
+
+    pub async fn post_requeue_failed_delivery<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.http_client
+            .post(format!("{}/admin/deliveries/failed/requeue", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_newsletter_issues(&self) -> reqwest::Response {
+        self.http_client
+            .get(format!("{}/admin/newsletters", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_newsletter_issues_html(&self) -> String {
+        self.get_newsletter_issues().await.text().await.unwrap()
+    }
+
+    pub async fn post_new_draft<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.http_client
+            .post(format!("{}/admin/newsletters", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    // The pages of a single issue live under `/admin/newsletters/{id}/`
+    pub async fn get_issue_page(&self, location: &str) -> reqwest::Response {
+        self.http_client
+            .get(format!("{}{}", &self.address, location))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_issue_form<Body>(&self, location: &str, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.http_client
+            .post(format!("{}{}", &self.address, location))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_audience_html(&self) -> String {
+        self.http_client
+            .get(format!("{}/admin/audience", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_create_list(&self, name: &str) -> reqwest::Response {
+        self.http_client
+            .post(format!("{}/admin/audience/lists", &self.address))
+            .form(&serde_json::json!({ "name": name }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_tag_subscriber(&self, email: &str, tag: &str) -> reqwest::Response {
+        self.http_client
+            .post(format!("{}/admin/audience/tags", &self.address))
+            .form(&serde_json::json!({ "email": email, "tag": tag }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_import_subscribers_html(&self) -> String {
+        self.http_client
+            .get(format!("{}/admin/subscribers/import", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    // Upload `csv` as a file, after the other form fields
+    pub async fn post_import_subscribers(
+        &self,
+        fields: &[(&str, &str)],
+        csv: &str,
+    ) -> reqwest::Response {
+        let mut parts = fields.to_vec();
+        parts.push(("file", csv));
+        self.post_import_form(&parts).await
+    }
+
+    // The parts are sent in order, the one named `file` as the uploaded CSV.
+    pub async fn post_import_form(&self, parts: &[(&str, &str)]) -> reqwest::Response {
+        let boundary = "------------------------z2pimportboundary";
+        let mut body = String::new();
+        for (name, value) in parts {
+            if *name == "file" {
+                body.push_str(&format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"subscribers.csv\"\r\n\
+                    Content-Type: text/csv\r\n\r\n{}\r\n",
+                    boundary, value
+                ));
+            } else {
+                body.push_str(&format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    boundary, name, value
+                ));
+            }
+        }
+        body.push_str(&format!("--{}--\r\n", boundary));
+        self.http_client
+            .post(format!("{}/admin/subscribers/import", &self.address))
+            .header(
+                "Content-Type",
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     pub async fn get_change_password(&self) -> reqwest::Response {
         self.http_client
-            .get(&format!("{}/admin/password", &self.address))
+            .get(format!("{}/admin/password", &self.address))
             .send()
             .await
             .expect("Failed to execute request.")
@@ -173,12 +545,32 @@ impl TestApp {
         Body: serde::Serialize,
     {
         self.http_client
-            .post(&format!("{}/admin/password", &self.address))
+            .post(format!("{}/admin/password", &self.address))
             .form(body)
             .send()
             .await
             .expect("Failed to execute request.")
     }
+
+    pub async fn get_admin_email_html(&self) -> String {
+        self.http_client
+            .get(format!("{}/admin/email", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_admin_email(&self, email: &str) -> reqwest::Response {
+        self.http_client
+            .post(format!("{}/admin/email", &self.address))
+            .form(&serde_json::json!({ "email": email }))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
 }
 
 // Confirmation links embedded in the request to the email API
@@ -251,3 +643,76 @@ pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
     assert_eq!(response.status().as_u16(), 303);
     assert_eq!(response.headers().get("Location").unwrap(), location);
 }
+
+// Use pubic API of the application under test to creat an unconfirmed subscriber
+/// The messages carried by a request to Postmark's `/email/batch` endpoint.
+pub fn batch_messages(request: &wiremock::Request) -> Vec<serde_json::Value> {
+    serde_json::from_slice(&request.body).unwrap()
+}
+
+/// Answer a request to Postmark's `/email/batch` endpoint, accepting every message
+/// but the ones sent to `rejected`.
+pub fn batch_response(request: &wiremock::Request, rejected: &[&str]) -> ResponseTemplate {
+    let results: Vec<_> = batch_messages(request)
+        .iter()
+        .map(|message| {
+            if rejected.iter().any(|r| message["To"] == *r) {
+                serde_json::json!({"ErrorCode": 406, "Message": "Inactive recipient."})
+            } else {
+                serde_json::json!({"ErrorCode": 0, "Message": "OK"})
+            }
+        })
+        .collect();
+    ResponseTemplate::new(200).set_body_json(results)
+}
+
+/// Accept every message sent to Postmark's `/email/batch` endpoint.
+pub fn accept_batch(request: &wiremock::Request) -> ResponseTemplate {
+    batch_response(request, &[])
+}
+
+pub async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
+    create_unconfirmed_subscriber_with_email(app, "ursula_le_guin%40gmail.com").await
+}
+
+pub async fn create_unconfirmed_subscriber_with_email(
+    app: &TestApp,
+    url_encoded_email: &str,
+) -> ConfirmationLinks {
+    let body = format!("name=le%20guin&email={}", url_encoded_email);
+    create_unconfirmed_subscriber_with_body(app, body).await
+}
+
+pub async fn create_unconfirmed_subscriber_with_body(
+    app: &TestApp,
+    body: String,
+) -> ConfirmationLinks {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_confirmation_links(email_request)
+}
+
+pub async fn create_confirmed_subscriber(app: &TestApp) {
+    let confirmation_link = create_unconfirmed_subscriber(app).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
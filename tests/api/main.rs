@@ -1,8 +1,16 @@
 mod admin_dashboard;
+mod admin_email;
+mod audience;
 mod change_password;
+mod failed_deliveries;
 mod health_check;
 mod helpers;
 mod login;
+mod newsletter_drafts;
 mod newsletters;
+mod subscribers_import;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_data;
+mod subscriptions_preferences;
+mod subscriptions_unsubscribe;
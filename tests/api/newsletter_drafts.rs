@@ -0,0 +1,496 @@
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{
+    accept_batch, assert_is_redirect_to, create_confirmed_subscriber, spawn_app, TestApp,
+};
+
+async fn login(app: &TestApp) {
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    }))
+    .await;
+}
+
+// Save a draft through the admin form and return its id
+async fn create_draft(app: &TestApp) -> Uuid {
+    let response = app
+        .post_new_draft(&serde_json::json!({
+            "title": "Draft title",
+            "html_content": "<p>Hi {{ name }}!</p>",
+            "text_content": "Hi {{ name }}!",
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 303);
+    sqlx::query!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .newsletter_issue_id
+}
+
+async fn issue_status(app: &TestApp, newsletter_issue_id: Uuid) -> String {
+    sqlx::query!(
+        "SELECT status FROM newsletter_issues WHERE newsletter_issue_id = $1",
+        newsletter_issue_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .status
+}
+
+async fn count_delivery_tasks(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_manage_newsletter_issues() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let list = app.get_newsletter_issues().await;
+    let create = app
+        .post_new_draft(&serde_json::json!({
+            "title": "Draft title",
+            "html_content": "<p>Body</p>",
+            "text_content": "Body",
+        }))
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&list, "/login");
+    assert_is_redirect_to(&create, "/login");
+    let n_issues = sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(n_issues, 0);
+}
+
+#[tokio::test]
+async fn drafts_are_not_delivered() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    login(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let newsletter_issue_id = create_draft(&app).await;
+    app.publish_due_issues().await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(issue_status(&app, newsletter_issue_id).await, "draft");
+    assert_eq!(count_delivery_tasks(&app).await, 0);
+    let html_page = app.get_newsletter_issues_html().await;
+    assert!(html_page.contains("Draft title"));
+}
+
+#[tokio::test]
+async fn drafts_have_no_web_version() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    let newsletter_issue_id = create_draft(&app).await;
+
+    // Act
+    let response = app
+        .http_client
+        .get(format!("{}/issues/{}", app.address, newsletter_issue_id))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn drafts_can_be_edited_and_previewed() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    let newsletter_issue_id = create_draft(&app).await;
+    let edit_page = format!("/admin/newsletters/{}/edit", newsletter_issue_id);
+
+    // Act - Part 1 - Edit the draft, leaving the plain-text body empty
+    let response = app
+        .post_issue_form(
+            &edit_page,
+            &serde_json::json!({
+                "title": "Edited title",
+                "html_content": "<p>Hello {{ name }}, <a href=\"{{ issue_url }}\">read online</a></p>",
+                "text_content": "",
+            }),
+        )
+        .await;
+    assert_is_redirect_to(&response, &edit_page);
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = app.get_issue_page(&edit_page).await.text().await.unwrap();
+    assert!(html_page.contains("The draft has been saved."));
+    assert!(html_page.contains("Edited title"));
+
+    // Act - Part 3 - Preview it
+    let preview = app
+        .get_issue_page(&format!(
+            "/admin/newsletters/{}/preview",
+            newsletter_issue_id
+        ))
+        .await;
+    assert_eq!(preview.status().as_u16(), 200);
+    let html_page = preview.text().await.unwrap();
+
+    // Assert - Merge fields are filled in for the logged-in admin
+    assert!(html_page.contains(&format!("Hello {}", app.test_user.username)));
+    assert!(html_page.contains(&format!("/issues/{}", newsletter_issue_id)));
+    assert!(!html_page.contains("{{ name }}"));
+    // The plain-text body was generated from the HTML one
+    let text_content = sqlx::query!(
+        "SELECT text_content FROM newsletter_issues WHERE newsletter_issue_id = $1",
+        newsletter_issue_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .text_content;
+    assert!(text_content.contains("Hello {{ name }}"));
+}
+
+#[tokio::test]
+async fn generated_plain_text_bodies_follow_html_edits() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    let newsletter_issue_id = create_draft(&app).await;
+    let edit_page = format!("/admin/newsletters/{}/edit", newsletter_issue_id);
+    let draft = |html_content: &str| {
+        serde_json::json!({
+            "title": "Draft title",
+            "html_content": html_content,
+            "text_content": "",
+        })
+    };
+
+    // Act - Part 1 - Leave the plain-text body to be generated
+    app.post_issue_form(&edit_page, &draft("<p>First version</p>"))
+        .await;
+    let html_page = app.get_issue_page(&edit_page).await.text().await.unwrap();
+    // The generated body is not shown back, so it is not saved as written by hand
+    assert!(html_page.contains(r#"<textarea name="text_content" rows="20" cols="80"></textarea>"#));
+
+    // Act - Part 2 - Edit the HTML body only
+    app.post_issue_form(&edit_page, &draft("<p>Second version</p>"))
+        .await;
+
+    // Assert
+    let text_content = sqlx::query!(
+        "SELECT text_content FROM newsletter_issues WHERE newsletter_issue_id = $1",
+        newsletter_issue_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .text_content;
+    assert!(text_content.contains("Second version"));
+    assert!(!text_content.contains("First version"));
+}
+
+#[tokio::test]
+async fn drafts_with_invalid_merge_fields_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    let newsletter_issue_id = create_draft(&app).await;
+    let edit_page = format!("/admin/newsletters/{}/edit", newsletter_issue_id);
+
+    // Act
+    let response = app
+        .post_issue_form(
+            &edit_page,
+            &serde_json::json!({
+                "title": "Edited title",
+                "html_content": "<p>Hello {{ email }}</p>",
+                "text_content": "Hello",
+            }),
+        )
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, &edit_page);
+    let html_page = app.get_issue_page(&edit_page).await.text().await.unwrap();
+    assert!(html_page.contains("is not a known merge field"));
+    assert!(html_page.contains("Draft title"));
+}
+
+#[tokio::test]
+async fn issues_cannot_be_scheduled_in_the_past() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    let newsletter_issue_id = create_draft(&app).await;
+
+    // Act
+    let response = app
+        .post_issue_form(
+            &format!("/admin/newsletters/{}/schedule", newsletter_issue_id),
+            &serde_json::json!({ "scheduled_for": "2020-01-01T10:00" }),
+        )
+        .await;
+
+    // Assert
+    assert_is_redirect_to(
+        &response,
+        &format!("/admin/newsletters/{}/edit", newsletter_issue_id),
+    );
+    assert_eq!(issue_status(&app, newsletter_issue_id).await, "draft");
+}
+
+#[tokio::test]
+async fn scheduled_issues_are_delivered_once_they_are_due() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    login(&app).await;
+    let newsletter_issue_id = create_draft(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Schedule the draft
+    let response = app
+        .post_issue_form(
+            &format!("/admin/newsletters/{}/schedule", newsletter_issue_id),
+            &serde_json::json!({ "scheduled_for": "2999-01-01T10:00" }),
+        )
+        .await;
+    assert_is_redirect_to(&response, "/admin/newsletters");
+    assert_eq!(issue_status(&app, newsletter_issue_id).await, "scheduled");
+    // Scheduled issues cannot be edited anymore
+    let response = app
+        .get_issue_page(&format!("/admin/newsletters/{}/edit", newsletter_issue_id))
+        .await;
+    assert_is_redirect_to(&response, "/admin/newsletters");
+
+    // Act - Part 2 - Nothing happens before it is due
+    app.publish_due_issues().await;
+    assert_eq!(count_delivery_tasks(&app).await, 0);
+
+    // Act - Part 3 - Let time pass
+    sqlx::query!(
+        "UPDATE newsletter_issues SET scheduled_for = now() - interval '1 minute'
+        WHERE newsletter_issue_id = $1",
+        newsletter_issue_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    app.publish_due_issues().await;
+    assert_eq!(issue_status(&app, newsletter_issue_id).await, "sending");
+    assert_eq!(count_delivery_tasks(&app).await, 1);
+
+    // Act - Part 4 - Deliver it
+    app.dispatch_all_pending_emails().await;
+    app.publish_due_issues().await;
+
+    // Assert
+    assert_eq!(issue_status(&app, newsletter_issue_id).await, "sent");
+    let messages = app.sent_batch_messages().await;
+    assert_eq!(messages[0]["Subject"], "Draft title");
+    assert!(messages[0]["TextBody"]
+        .as_str()
+        .unwrap()
+        .starts_with("Hi le guin!"));
+}
+
+#[tokio::test]
+async fn scheduled_issues_can_go_back_to_draft() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    let newsletter_issue_id = create_draft(&app).await;
+    app.post_issue_form(
+        &format!("/admin/newsletters/{}/schedule", newsletter_issue_id),
+        &serde_json::json!({ "scheduled_for": "2999-01-01T10:00:00Z" }),
+    )
+    .await;
+
+    // Act
+    let response = app
+        .post_issue_form(
+            &format!("/admin/newsletters/{}/unschedule", newsletter_issue_id),
+            &serde_json::json!({}),
+        )
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/admin/newsletters");
+    assert_eq!(issue_status(&app, newsletter_issue_id).await, "draft");
+    let html_page = app.get_newsletter_issues_html().await;
+    assert!(html_page.contains("The issue is a draft again."));
+}
+
+#[tokio::test]
+async fn published_issues_are_marked_as_sent_once_delivered() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(accept_batch)
+        .mount(&app.email_server)
+        .await;
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            },
+            "idempotency_key": Uuid::new_v4().to_string()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    let newsletter_issue_id = sqlx::query!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .newsletter_issue_id;
+    assert_eq!(issue_status(&app, newsletter_issue_id).await, "sending");
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+    app.publish_due_issues().await;
+
+    // Assert
+    assert_eq!(issue_status(&app, newsletter_issue_id).await, "sent");
+}
+
+#[tokio::test]
+async fn test_copies_go_to_the_admin_only() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    login(&app).await;
+    let newsletter_issue_id = create_draft(&app).await;
+    app.post_admin_email("admin@example.com").await;
+    let preview_page = format!("/admin/newsletters/{}/preview", newsletter_issue_id);
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - The address defaults to the admin's one
+    let html_page = app
+        .get_issue_page(&preview_page)
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(html_page.contains(r#"value="admin@example.com""#));
+    let response = app
+        .post_issue_form(
+            &format!("/admin/newsletters/{}/test", newsletter_issue_id),
+            &serde_json::json!({ "email": "" }),
+        )
+        .await;
+    assert_is_redirect_to(&response, &preview_page);
+    let html_page = app
+        .get_issue_page(&preview_page)
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(html_page.contains("A test copy has been sent to admin@example.com."));
+
+    // Act - Part 2 - Any other address can be used instead
+    app.post_issue_form(
+        &format!("/admin/newsletters/{}/test", newsletter_issue_id),
+        &serde_json::json!({ "email": "reviewer@example.com" }),
+    )
+    .await;
+
+    // Assert
+    let requests = app.email_server.received_requests().await.unwrap();
+    let bodies: Vec<serde_json::Value> = requests
+        .iter()
+        .filter(|r| r.url.path() == "/email")
+        .map(|r| serde_json::from_slice(&r.body).unwrap())
+        .collect();
+    assert_eq!(bodies[bodies.len() - 2]["To"], "admin@example.com");
+    assert_eq!(bodies[bodies.len() - 1]["To"], "reviewer@example.com");
+    for body in &bodies[bodies.len() - 2..] {
+        assert_eq!(body["Subject"], "[TEST] Draft title");
+        assert!(body["TextBody"]
+            .as_str()
+            .unwrap()
+            .starts_with(&format!("Hi {}!", app.test_user.username)));
+    }
+    // Subscribers are left alone
+    assert_eq!(issue_status(&app, newsletter_issue_id).await, "draft");
+    assert_eq!(count_delivery_tasks(&app).await, 0);
+}
+
+#[tokio::test]
+async fn test_copies_need_a_valid_address() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    let newsletter_issue_id = create_draft(&app).await;
+    let preview_page = format!("/admin/newsletters/{}/preview", newsletter_issue_id);
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    for (email, error_message) in [
+        ("", "Enter the address the test copy should be sent to."),
+        (
+            "not-an-email",
+            "not-an-email is not a valid subscriber email",
+        ),
+        // What was typed in is escaped when it is shown back
+        (
+            "<b>nope</b>",
+            "<p><i>&lt;b&gt;nope&lt;/b&gt; is not a valid subscriber email",
+        ),
+    ] {
+        // Act
+        let response = app
+            .post_issue_form(
+                &format!("/admin/newsletters/{}/test", newsletter_issue_id),
+                &serde_json::json!({ "email": email }),
+            )
+            .await;
+
+        // Assert
+        assert_is_redirect_to(&response, &preview_page);
+        let html_page = app
+            .get_issue_page(&preview_page)
+            .await
+            .text()
+            .await
+            .unwrap();
+        assert!(html_page.contains(error_message));
+    }
+}
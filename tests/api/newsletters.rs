@@ -1,4 +1,7 @@
-use crate::helpers::{spawn_app, ConfirmationLinks, TestApp};
+use crate::helpers::{
+    accept_batch, create_confirmed_subscriber, create_unconfirmed_subscriber,
+    create_unconfirmed_subscriber_with_email, spawn_app,
+};
 use wiremock::matchers::{any, method, path};
 use wiremock::{Mock, ResponseTemplate};
 
@@ -18,14 +21,42 @@ async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
         "content": {
             "text": "Newsletter body as plain text",
             "html": "<p>Newsletter body as plain HTML</p>"
-        }
+        },
+        "idempotency_key": uuid::Uuid::new_v4().to_string()
     });
     let response = app.post_newsletters(newsletter_request_body).await;
-    assert_eq!(response.status().as_u16(), 200)
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop
 }
 
 #[tokio::test]
 async fn newsletters_are_delivered_to_confirmed_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    // Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as plain HTML</p>"
+        },
+        "idempotency_key": uuid::Uuid::new_v4().to_string()
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop
+}
+
+#[tokio::test]
+async fn newsletters_can_be_published_without_an_idempotency_key() {
     // Arrange
     let app = spawn_app().await;
     create_confirmed_subscriber(&app).await;
@@ -43,7 +74,9 @@ async fn newsletters_are_delivered_to_confirmed_subscribers() {
         }
     });
     let response = app.post_newsletters(newsletter_request_body).await;
-    assert_eq!(response.status().as_u16(), 200)
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop
 }
 
 #[tokio::test]
@@ -56,14 +89,51 @@ async fn newsletters_returns_400_for_invalid_data() {
                 "content": {
                     "text": "Newsletter body as plain text",
                     "html": "<p>Newsletter body as HTML</p>",
-                }
+                },
+                "idempotency_key": uuid::Uuid::new_v4().to_string()
             }),
             "missing title",
         ),
         (
-            serde_json::json!({"title": "Newsletter!"}),
+            serde_json::json!({
+                "title": "Newsletter!",
+                "idempotency_key": uuid::Uuid::new_v4().to_string()
+            }),
             "missing content",
         ),
+        (
+            serde_json::json!({
+                "title": "Newsletter!",
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>",
+                },
+                "idempotency_key": ""
+            }),
+            "empty idempotency key",
+        ),
+        (
+            serde_json::json!({
+                "title": "Newsletter!",
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Hi {{ email }}</p>",
+                },
+                "idempotency_key": uuid::Uuid::new_v4().to_string()
+            }),
+            "unknown merge field",
+        ),
+        (
+            serde_json::json!({
+                "title": "Newsletter!",
+                "content": {
+                    "text": "Hi {{ name",
+                    "html": "<p>Newsletter body as HTML</p>",
+                },
+                "idempotency_key": uuid::Uuid::new_v4().to_string()
+            }),
+            "unclosed merge field",
+        ),
     ];
 
     for (invalid_body, error_message) in test_cases {
@@ -84,13 +154,14 @@ async fn requests_missing_authorization_are_rejected() {
     let app = spawn_app().await;
 
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .json(&serde_json::json!({
             "title": "Newsletter title",
             "content": {
                 "text": "Newsletter body as plain text",
                 "html": "<p>Newsletter body as HTML</p>",
-            }
+            },
+            "idempotency_key": uuid::Uuid::new_v4().to_string()
         }))
         .send()
         .await
@@ -112,14 +183,15 @@ async fn non_existing_user_is_rejected() {
     let username = uuid::Uuid::new_v4().to_string();
     let password = uuid::Uuid::new_v4().to_string();
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .basic_auth(username, Some(password))
         .json(&serde_json::json!({
             "title": "Newsletter title",
             "content": {
                 "text": "Newsletter body as plain text",
                 "html": "<p>Newsletter body as HTML</p>",
-            }
+            },
+            "idempotency_key": uuid::Uuid::new_v4().to_string()
         }))
         .send()
         .await
@@ -141,14 +213,15 @@ async fn invalid_password_is_rejected() {
     let password = uuid::Uuid::new_v4().to_string();
     assert_ne!(app.test_user.password, password);
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .basic_auth(username, Some(password))
         .json(&serde_json::json!({
             "title": "Newsletter title",
             "content": {
                 "text": "Newsletter body as plain text",
                 "html": "<p>Newsletter body as HTML</p>",
-            }
+            },
+            "idempotency_key": uuid::Uuid::new_v4().to_string()
         }))
         .send()
         .await
@@ -161,35 +234,280 @@ async fn invalid_password_is_rejected() {
     );
 }
 
-// Use pubic API of the application under test to creat an unconfirmed subscriber
-async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
-    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
-    let _mock_guard = Mock::given(path("/email"))
+#[tokio::test]
+async fn newsletter_creation_is_idempotent() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Publish a newsletter
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>"
+        },
+        // We expect the idempotency key as part of the
+        // request body
+        "idempotency_key": uuid::Uuid::new_v4().to_string()
+    });
+    let response = app.post_newsletters(newsletter_request_body.clone()).await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    // Act - Part 2 - Submit the same newsletter again
+    let response = app.post_newsletters(newsletter_request_body).await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    app.dispatch_all_pending_emails().await;
+    // Assert - The newsletter email went out **once**
+    assert_eq!(app.sent_batch_messages().await.len(), 1);
+}
+
+#[tokio::test]
+async fn concurrent_newsletter_submission_is_handled_gracefully() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Submit two newsletter forms concurrently
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>"
+        },
+        "idempotency_key": uuid::Uuid::new_v4().to_string()
+    });
+    let response1 = app.post_newsletters(newsletter_request_body.clone());
+    let response2 = app.post_newsletters(newsletter_request_body);
+    let (response1, response2) = tokio::join!(response1, response2);
+
+    assert_eq!(response1.status(), response2.status());
+    assert_eq!(
+        response1.text().await.unwrap(),
+        response2.text().await.unwrap()
+    );
+    app.dispatch_all_pending_emails().await;
+    // Assert - The newsletter email went out **once**
+    assert_eq!(app.sent_batch_messages().await.len(), 1);
+}
+
+#[tokio::test]
+async fn publishing_enqueues_one_delivery_task_per_confirmed_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    create_unconfirmed_subscriber_with_email(&app, "le_guin%40gmail.com").await;
+    Mock::given(path("/email/batch"))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
-        .named("Create unconfirmed subscriber")
+        .respond_with(accept_batch)
         .expect(1)
-        .mount_as_scoped(&app.email_server)
+        .mount(&app.email_server)
         .await;
-    app.post_subscriptions(body.into())
+
+    // Act - Part 1 - Publish a newsletter
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            },
+            "idempotency_key": uuid::Uuid::new_v4().to_string()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    // Assert - Nothing has been sent yet, the worker owns delivery
+    let queued = sqlx::query!("SELECT subscriber_email FROM issue_delivery_queue")
+        .fetch_all(&app.db_pool)
         .await
-        .error_for_status()
         .unwrap();
-    let email_request = &app
-        .email_server
-        .received_requests()
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].subscriber_email, "ursula_le_guin@gmail.com");
+
+    // Act - Part 2 - Drain the queue
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let queued = sqlx::query!("SELECT subscriber_email FROM issue_delivery_queue")
+        .fetch_all(&app.db_pool)
         .await
-        .unwrap()
-        .pop()
         .unwrap();
-    app.get_confirmation_links(&email_request)
+    assert!(queued.is_empty());
 }
 
-async fn create_confirmed_subscriber(app: &TestApp) {
-    let confirmation_link = create_unconfirmed_subscriber(app).await;
-    reqwest::get(confirmation_link.html)
+#[tokio::test]
+async fn merge_fields_are_filled_in_for_each_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Hi {{ name }}!\nRead it online: {{ issue_url }}\nLeave: {{unsubscribe_url}}",
+                "html": "<p>Hi {{ name }}!</p><a href=\"{{ issue_url }}\">Read it online</a>"
+            },
+            "idempotency_key": uuid::Uuid::new_v4().to_string()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let message = &app.sent_batch_messages().await[0];
+    let html_body = message["HtmlBody"].as_str().unwrap();
+    let text_body = message["TextBody"].as_str().unwrap();
+    assert!(html_body.starts_with("<p>Hi le guin!</p>"));
+    assert!(text_body.starts_with("Hi le guin!"));
+    // The text body places the unsubscribe link itself: no footer is added
+    assert!(!text_body.contains("Unsubscribe:"));
+    assert!(text_body.contains("/subscriptions/unsubscribe?token="));
+    // The html body does not: it gets the usual footer
+    assert!(html_body.contains(">Unsubscribe</a>"));
+
+    let issue = sqlx::query!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    let issue_path = format!("/issues/{}", issue.newsletter_issue_id);
+    assert!(text_body.contains(&issue_path));
+    let web_version = app
+        .http_client
+        .get(format!("{}{}", app.address, issue_path))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(web_version.status().as_u16(), 200);
+    assert!(web_version
+        .text()
         .await
         .unwrap()
-        .error_for_status()
+        .contains("<p>Hi reader!</p>"));
+}
+
+#[tokio::test]
+async fn the_web_version_of_an_unknown_issue_is_a_404() {
+    // Arrange
+    let app = spawn_app().await;
+    // Act
+    let response = app
+        .http_client
+        .get(format!("{}/issues/{}", app.address, uuid::Uuid::new_v4()))
+        .send()
+        .await
+        .unwrap();
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn issues_written_in_markdown_are_sent_as_html_and_plain_text() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "markdown": "Hi {{ name }}!\n\n* Read [the docs](https://example.com/docs)\n* Stay safe<script>alert(1)</script>"
+            },
+            "idempotency_key": uuid::Uuid::new_v4().to_string()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let message = &app.sent_batch_messages().await[0];
+    let html_body = message["HtmlBody"].as_str().unwrap();
+    let text_body = message["TextBody"].as_str().unwrap();
+    assert!(html_body.starts_with("<p>Hi le guin!</p>"));
+    assert!(html_body.contains(r#"<a href="https://example.com/docs""#));
+    assert!(!html_body.contains("<script>"));
+    assert!(text_body
+        .starts_with("Hi le guin!\n\n- Read the docs (https://example.com/docs)\n- Stay safe"));
+}
+
+#[tokio::test]
+async fn markdown_issues_with_unknown_merge_fields_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "markdown": "Hi {{ email }}" },
+            "idempotency_key": uuid::Uuid::new_v4().to_string()
+        }))
+        .await;
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn a_plain_text_body_is_derived_from_the_html_if_omitted() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "html": "<p>Hi {{ name }}!</p><ul><li>Read <a href=\"{{ issue_url }}\">online</a></li></ul>"
+            },
+            "idempotency_key": uuid::Uuid::new_v4().to_string()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let message = &app.sent_batch_messages().await[0];
+    let text_body = message["TextBody"].as_str().unwrap();
+    let issue = sqlx::query!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
         .unwrap();
+    assert!(text_body.starts_with(&format!(
+        "Hi le guin!\n\n* Read [online][1]\n\n[1]: {}/issues/{}",
+        app.base_url, issue.newsletter_issue_id
+    )));
 }
@@ -0,0 +1,248 @@
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{assert_is_redirect_to, create_confirmed_subscriber, spawn_app, TestApp};
+
+async fn login(app: &TestApp) {
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    }))
+    .await;
+}
+
+async fn count_subscribers(app: &TestApp, status: &str) -> i64 {
+    sqlx::query!(
+        r#"SELECT COUNT(*) as "count!" FROM subscriptions WHERE status = $1"#,
+        status
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .count
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_import_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_import_subscribers(&[], "email,name\nursula@example.com,Ursula\n")
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+    assert_eq!(count_subscribers(&app, "confirmed").await, 0);
+}
+
+#[tokio::test]
+async fn invalid_rows_are_reported_and_the_others_imported() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    create_confirmed_subscriber(&app).await;
+    let csv = "Name,Email,Source\n\
+        Ursula,ursula@example.com,blog\n\
+        Nobody,not-an-email,blog\n\
+        ,octavia@example.com,blog\n\
+        \"Le Guin, Ursula\",ursula_le_guin@gmail.com,blog\n\
+        Ursula again,ursula@example.com,blog\n\
+        Octavia,octavia.butler@example.com\n";
+
+    // Act
+    let response = app
+        .post_import_subscribers(&[("status", "confirmed")], csv)
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("2 subscribers have been imported."));
+    assert!(html_page.contains("4 rows skipped"));
+    assert!(html_page
+        .contains("<tr><td>3</td><td>not-an-email is not a valid subscriber email</td></tr>"));
+    assert!(html_page.contains("<tr><td>4</td><td> is not a valid subscriber name.</td></tr>"));
+    assert!(html_page
+        .contains("<tr><td>5</td><td>ursula_le_guin@gmail.com is already subscribed.</td></tr>"));
+    assert!(html_page
+        .contains("<tr><td>6</td><td>ursula@example.com appears earlier in the file.</td></tr>"));
+    let imported = sqlx::query!(
+        "SELECT name FROM subscriptions WHERE email = 'ursula@example.com' AND status = 'confirmed'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(imported.name, "Ursula");
+    // The existing subscriber is left alone
+    let existing =
+        sqlx::query!("SELECT name FROM subscriptions WHERE email = 'ursula_le_guin@gmail.com'")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(existing.name, "le guin");
+}
+
+#[tokio::test]
+async fn subscribers_imported_with_double_opt_in_get_a_confirmation_email() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_import_subscribers(
+            &[("status", "double_opt_in")],
+            "email,name\nursula@example.com,Ursula\noctavia@example.com,Octavia\n",
+        )
+        .await;
+    app.dispatch_confirmation_outbox().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(count_subscribers(&app, "pending_confirmation").await, 2);
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    assert_eq!(count_subscribers(&app, "confirmed").await, 1);
+}
+
+#[tokio::test]
+async fn a_dry_run_saves_nothing() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_import_subscribers(
+            &[("status", "double_opt_in"), ("dry_run", "on")],
+            "email,name\nursula@example.com,Ursula\nursula@example.com,Ursula\n",
+        )
+        .await;
+    app.dispatch_confirmation_outbox().await;
+
+    // Assert
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("1 subscribers would be imported. Nothing has been saved."));
+    assert!(html_page.contains("ursula@example.com appears earlier in the file."));
+    assert_eq!(count_subscribers(&app, "pending_confirmation").await, 0);
+}
+
+#[tokio::test]
+async fn options_sent_after_the_file_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    let csv = "email,name\nursula@example.com,Ursula\n";
+    let test_cases = vec![
+        (
+            vec![("status", "confirmed"), ("file", csv), ("dry_run", "on")],
+            "The options must come before the file.",
+        ),
+        (
+            vec![("file", csv), ("status", "confirmed"), ("dry_run", "on")],
+            "Pick how imported subscribers should be confirmed.",
+        ),
+    ];
+
+    for (parts, error_message) in test_cases {
+        // Act
+        let response = app.post_import_form(&parts).await;
+
+        // Assert
+        assert_is_redirect_to(&response, "/admin/subscribers/import");
+        let html_page = app.get_import_subscribers_html().await;
+        assert!(html_page.contains(error_message));
+        assert_eq!(count_subscribers(&app, "confirmed").await, 0);
+    }
+}
+
+#[tokio::test]
+async fn large_files_are_imported_in_batches() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    let mut csv = String::from("email,name\n");
+    for i in 0..1234 {
+        csv.push_str(&format!("subscriber{}@example.com,Subscriber {}\n", i, i));
+    }
+
+    // Act
+    let response = app
+        .post_import_subscribers(&[("status", "confirmed")], &csv)
+        .await;
+
+    // Assert
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("1234 subscribers have been imported."));
+    assert_eq!(count_subscribers(&app, "confirmed").await, 1234);
+}
+
+#[tokio::test]
+async fn only_the_first_skipped_rows_are_listed() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+    let mut csv = String::from("email,name\n");
+    for i in 0..2500 {
+        csv.push_str(&format!("not-an-email-{},Subscriber {}\n", i, i));
+    }
+
+    // Act
+    let response = app
+        .post_import_subscribers(&[("status", "confirmed")], &csv)
+        .await;
+
+    // Assert
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("2500 rows skipped, the first 1000 are listed"));
+    assert!(html_page.contains("<tr><td>2</td>"));
+    assert!(html_page.contains("<tr><td>1001</td>"));
+    assert!(!html_page.contains("<tr><td>1002</td>"));
+}
+
+#[tokio::test]
+async fn a_file_without_email_and_name_columns_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    login(&app).await;
+
+    let test_cases = [
+        (
+            "address,full name\nursula@example.com,Ursula\n",
+            "The first row of the file must name an email and a name column.",
+        ),
+        ("", "The file is empty."),
+    ];
+
+    for (csv, error_message) in test_cases {
+        // Act - Part 1 - Upload the file
+        let response = app
+            .post_import_subscribers(&[("status", "confirmed")], csv)
+            .await;
+        assert_is_redirect_to(&response, "/admin/subscribers/import");
+
+        // Act - Part 2 - Follow the redirect
+        let html_page = app.get_import_subscribers_html().await;
+        assert!(html_page.contains(error_message));
+    }
+    assert_eq!(count_subscribers(&app, "confirmed").await, 0);
+}
@@ -1,6 +1,10 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app, TestApp,
+};
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
+use z2p::configuration::get_configuration;
+use z2p::subscriber_links::LinkPurpose;
 
 #[tokio::test]
 async fn subscribe_returns_200_for_valid_form_data() {
@@ -36,6 +40,32 @@ async fn subscribe_persists_the_new_subscriber() {
     assert_eq!(saved.status, "pending_confirmation");
 }
 
+#[tokio::test]
+async fn concurrent_first_submissions_of_an_address_both_succeed() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    // Act
+    let (response1, response2) = tokio::join!(
+        app.post_subscriptions(body.into()),
+        app.post_subscriptions(body.into())
+    );
+    // Assert
+    assert_eq!(response1.status().as_u16(), 200);
+    assert_eq!(response2.status().as_u16(), 200);
+    let n_subscribers = sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(n_subscribers, 1);
+}
+
 #[tokio::test]
 async fn subscribe_sends_a_confirmation_email_for_valid_data() {
     // Arrange
@@ -52,7 +82,7 @@ async fn subscribe_sends_a_confirmation_email_for_valid_data() {
     // Assert
     // Get intercepted request
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
     // Mock asserts on drop
 }
@@ -105,7 +135,7 @@ async fn subscribe_fails_if_there_is_a_fatal_database_errror() {
     let app = spawn_app().await;
     let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
     // Sabotage the database
-    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;",)
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token CASCADE;",)
         .execute(&app.db_pool)
         .await
         .unwrap();
@@ -114,3 +144,325 @@ async fn subscribe_fails_if_there_is_a_fatal_database_errror() {
     // Assert
     assert_eq!(response.status().as_u16(), 500);
 }
+
+#[tokio::test]
+async fn subscribing_again_while_pending_resends_the_same_confirmation_link() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let first_links = create_unconfirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let second_links = app.get_confirmation_links(&email_request);
+    assert_eq!(first_links.html, second_links.html);
+    let saved = sqlx::query!("SELECT COUNT(*) AS \"n!\" FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count saved subscriptions.");
+    assert_eq!(saved.n, 1);
+}
+
+#[tokio::test]
+async fn subscribing_again_when_confirmed_returns_200_without_sending_an_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+    // Mock asserts on drop
+}
+
+#[tokio::test]
+async fn subscribing_again_after_unsubscribing_restarts_double_opt_in() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    let token = app
+        .subscriber_links
+        .sign(LinkPurpose::Unsubscribe, subscriber.id);
+    app.post_unsubscribe(&token)
+        .await
+        .error_for_status()
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    // Act
+    let body = "name=Ursula&email=ursula_le_guin%40gmail.com";
+    let response = app.post_subscriptions(body.into()).await;
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT name, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.name, "Ursula");
+    assert_eq!(saved.status, "pending_confirmation");
+    // The new confirmation link brings the subscriber back onto the list.
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+struct OutboxEntry {
+    n_retries: i16,
+}
+
+async fn confirmation_outbox(app: &TestApp) -> Vec<OutboxEntry> {
+    sqlx::query_as!(
+        OutboxEntry,
+        "SELECT n_retries FROM confirmation_email_outbox"
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+}
+
+// Make every email in the outbox due right away, instead of waiting for its backoff
+async fn fast_forward_confirmation_outbox(app: &TestApp) {
+    sqlx::query!("UPDATE confirmation_email_outbox SET execute_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn subscribe_succeeds_and_keeps_the_email_in_the_outbox_if_the_email_provider_is_down() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+    let outbox = confirmation_outbox(&app).await;
+    assert_eq!(outbox.len(), 1);
+    assert_eq!(outbox[0].n_retries, 1);
+}
+
+#[tokio::test]
+async fn a_sent_confirmation_email_leaves_the_outbox() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    // Act
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_confirmation_outbox().await;
+    // Assert
+    assert!(confirmation_outbox(&app).await.is_empty());
+    // Mock asserts on drop
+}
+
+#[tokio::test]
+async fn the_dispatcher_sends_the_confirmation_email_once_the_email_provider_is_back() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    // Act
+    fast_forward_confirmation_outbox(&app).await;
+    app.dispatch_confirmation_outbox().await;
+    // Assert
+    assert!(confirmation_outbox(&app).await.is_empty());
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn the_dispatcher_does_not_retry_before_the_backoff_has_elapsed() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+    // Act
+    app.dispatch_confirmation_outbox().await;
+    // Assert
+    assert_eq!(confirmation_outbox(&app).await[0].n_retries, 1);
+    // Mock asserts on drop
+}
+
+#[tokio::test]
+async fn a_confirmation_email_is_dropped_once_it_runs_out_of_attempts() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+    sqlx::query!(
+        "UPDATE confirmation_email_outbox SET n_retries = $1",
+        app.confirmation_outbox.max_attempts - 1
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    // Act
+    fast_forward_confirmation_outbox(&app).await;
+    app.dispatch_confirmation_outbox().await;
+    // Assert
+    assert!(confirmation_outbox(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn a_confirmation_email_rejected_by_the_email_provider_is_not_retried() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(confirmation_outbox(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn subscribe_stops_calling_the_email_provider_once_the_circuit_opens() {
+    // Arrange
+    let app = spawn_app().await;
+    let failure_threshold = get_configuration()
+        .unwrap()
+        .email_client
+        .circuit_breaker
+        .failure_threshold;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(u64::from(failure_threshold))
+        .mount(&app.email_server)
+        .await;
+    // Act
+    for i in 0..failure_threshold + 2 {
+        let body = format!("name=le%20guin&email=ursula_{}%40gmail.com", i);
+        let response = app.post_subscriptions(body).await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    // Assert - The emails held back by the open circuit did not use up an attempt
+    let outbox = confirmation_outbox(&app).await;
+    assert_eq!(outbox.len(), failure_threshold as usize + 2);
+    assert_eq!(outbox.iter().filter(|e| e.n_retries == 0).count(), 2);
+    let health: serde_json::Value = app
+        .http_client
+        .get(format!("{}/healthz", &app.address))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(health["email_circuit_breaker"], "open");
+    // Mock asserts on drop
+}
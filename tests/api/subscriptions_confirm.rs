@@ -1,7 +1,12 @@
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
 
-use crate::helpers::spawn_app;
+use crate::helpers::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber,
+    create_unconfirmed_subscriber_with_email, spawn_app, TestApp,
+};
+use z2p::subscriber_links::LinkPurpose;
+use z2p::subscription_cleanup::{purge_stale_subscriptions, CleanupOutcome};
 
 #[tokio::test]
 async fn confirmations_without_token_are_rejected_with_a_404() {
@@ -30,7 +35,7 @@ async fn the_link_returned_by_subscribe_returns_200_if_called() {
     // Assert
     // Get intercepted request
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
     // Act
     let response = reqwest::get(confirmation_links.html).await.unwrap();
     // Assert
@@ -49,7 +54,7 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
         .await;
     app.post_subscriptions(body.into()).await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
     // Act
     reqwest::get(confirmation_links.html)
         .await
@@ -65,3 +70,226 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
     assert_eq!(saved.name, "le guin");
     assert_eq!(saved.status, "confirmed");
 }
+
+fn subscription_token(link: &reqwest::Url) -> String {
+    link.query_pairs()
+        .find(|(k, _)| k == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned()
+}
+
+async fn expire_confirmation_tokens(app: &TestApp) {
+    sqlx::query!("UPDATE subscription_tokens SET expires_at = now() - interval '1 hour'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn confirmation_links_can_only_be_used_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = create_unconfirmed_subscriber(&app).await;
+    reqwest::get(confirmation_links.html.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    // Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn expired_confirmation_links_offer_to_send_a_new_one() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = create_unconfirmed_subscriber(&app).await;
+    expire_confirmation_tokens(&app).await;
+    // Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    // Assert
+    assert_eq!(response.status().as_u16(), 410);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("This confirmation link has expired."));
+    assert!(html_page.contains(r#"action="/subscriptions/confirm/resend""#));
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn a_resent_confirmation_link_confirms_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let expired_links = create_unconfirmed_subscriber(&app).await;
+    expire_confirmation_tokens(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    // Act - Part 1 - Ask for a new link
+    let response = app
+        .post_resend_confirmation(&subscription_token(&expired_links.html))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    // Act - Part 2 - Follow it
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    assert_ne!(confirmation_links.html, expired_links.html);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    // Assert
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+    // The expired link is gone for good.
+    let response = reqwest::get(expired_links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn an_expired_change_of_address_can_be_confirmed_with_a_new_link() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    let token = app
+        .subscriber_links
+        .sign(LinkPurpose::Preferences, subscriber.id);
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_change_email(&token, "ursula@example.com").await;
+    let expired_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let expired_links = app.get_confirmation_links(&expired_request);
+    expire_confirmation_tokens(&app).await;
+
+    // Act - Part 1 - Ask for a new link
+    let response = app
+        .post_resend_confirmation(&subscription_token(&expired_links.html))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    // Act - Part 2 - Follow it
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], "ursula@example.com");
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    assert_ne!(confirmation_links.html, expired_links.html);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let saved = sqlx::query!("SELECT email, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula@example.com");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn resending_a_confirmation_link_requires_a_known_token() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    // Act
+    let response = app.post_resend_confirmation("not-a-real-token").await;
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn cleanup_purges_stale_tokens_and_unconfirmed_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    create_unconfirmed_subscriber_with_email(&app, "stale%40example.com").await;
+    create_unconfirmed_subscriber_with_email(&app, "recent%40example.com").await;
+    // Everybody but the recent subscriber is well past the retention window.
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions SET subscribed_at = now() - interval '1 year'
+        WHERE email != 'recent@example.com'
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        UPDATE subscription_tokens SET expires_at = now() - interval '1 year'
+        WHERE subscriber_id IN (
+            SELECT id FROM subscriptions WHERE email = 'stale@example.com'
+        )
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    // Act
+    let outcome = purge_stale_subscriptions(&app.db_pool, &app.subscriptions)
+        .await
+        .unwrap();
+    // Assert
+    assert_eq!(
+        outcome,
+        CleanupOutcome {
+            deleted_tokens: 1,
+            deleted_subscribers: 1,
+        }
+    );
+    let remaining: Vec<_> = sqlx::query!("SELECT email FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.email)
+        .collect();
+    assert_eq!(
+        remaining,
+        vec!["recent@example.com", "ursula_le_guin@gmail.com"]
+    );
+}
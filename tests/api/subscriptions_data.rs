@@ -0,0 +1,196 @@
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{
+    assert_is_redirect_to, create_confirmed_subscriber, create_unconfirmed_subscriber_with_body,
+    spawn_app, TestApp,
+};
+
+// Ask for a data access link and return the token it carries
+async fn request_data_access_token(app: &TestApp, email: &str) -> String {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_data_request(email).await;
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let access_link = app.get_confirmation_links(&email_request).html;
+    assert_eq!(access_link.path(), "/subscriptions/data/access");
+    access_link
+        .query_pairs()
+        .find(|(key, _)| key == "token")
+        .unwrap()
+        .1
+        .into_owned()
+}
+
+#[tokio::test]
+async fn requesting_data_access_does_not_tell_whether_an_address_is_subscribed() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    for email in ["ursula_le_guin@gmail.com", "nobody@example.com"] {
+        // Act
+        let response = app.post_data_request(email).await;
+
+        // Assert
+        assert_is_redirect_to(&response, "/subscriptions/data");
+        let html_page = app.get_data_request_html().await;
+        assert!(html_page.contains(&format!(
+            "If {} is subscribed to our newsletter, we have sent it a link to access your data.",
+            email
+        )));
+    }
+    // Mock verifies on Drop that only the subscribed address got an email
+}
+
+#[tokio::test]
+async fn the_data_access_page_needs_a_valid_token() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = request_data_access_token(&app, "ursula_le_guin@gmail.com").await;
+    sqlx::query!("UPDATE data_access_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    for token in ["not-a-token", token.as_str()] {
+        // Act
+        let export = app.get_data_export(token).await;
+        let erasure = app.post_data_erasure(token).await;
+
+        // Assert
+        assert_eq!(export.status().as_u16(), 401);
+        assert_eq!(erasure.status().as_u16(), 401);
+    }
+}
+
+#[tokio::test]
+async fn subscribers_can_export_their_data() {
+    // Arrange
+    let app = spawn_app().await;
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    }))
+    .await;
+    app.post_create_list("rust").await;
+    create_unconfirmed_subscriber_with_body(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&lists=rust".into(),
+    )
+    .await;
+    let token = request_data_access_token(&app, "ursula_le_guin@gmail.com").await;
+
+    // Act
+    let response = app.get_data_export(&token).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers()["Content-Disposition"]
+        .to_str()
+        .unwrap()
+        .starts_with("attachment"));
+    let data: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(data["subscription"]["email"], "ursula_le_guin@gmail.com");
+    assert_eq!(data["subscription"]["name"], "le guin");
+    assert_eq!(data["subscription"]["status"], "pending_confirmation");
+    assert_eq!(data["lists"], serde_json::json!(["rust"]));
+    assert_eq!(data["tokens"].as_array().unwrap().len(), 1);
+    assert_eq!(data["deliveries"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn erasing_a_subscriber_deletes_every_row_about_them() {
+    // Arrange
+    let app = spawn_app().await;
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    }))
+    .await;
+    create_confirmed_subscriber(&app).await;
+    app.post_tag_subscriber("ursula_le_guin@gmail.com", "vip")
+        .await;
+    // An issue waiting to be delivered
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            },
+            "idempotency_key": Uuid::new_v4().to_string()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    let token = request_data_access_token(&app, "ursula_le_guin@gmail.com").await;
+    let data: serde_json::Value = app.get_data_export(&token).await.json().await.unwrap();
+    assert_eq!(data["tags"], serde_json::json!(["vip"]));
+    assert_eq!(data["deliveries"][0]["title"], "Newsletter title");
+    assert_eq!(data["deliveries"][0]["status"], "pending");
+
+    // Act
+    let response = app.post_data_erasure(&token).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let n_rows = sqlx::query!(
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM subscriptions) +
+            (SELECT COUNT(*) FROM subscription_tokens) +
+            (SELECT COUNT(*) FROM subscriber_tags) +
+            (SELECT COUNT(*) FROM issue_delivery_queue) +
+            (SELECT COUNT(*) FROM data_access_tokens) as "count!"
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .count;
+    assert_eq!(n_rows, 0);
+    // The link cannot be used again
+    assert_eq!(app.get_data_export(&token).await.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn subscribers_with_pending_tokens_can_be_erased() {
+    // Arrange
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber_with_body(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com".into(),
+    )
+    .await;
+    let token = request_data_access_token(&app, "ursula_le_guin@gmail.com").await;
+
+    // Act
+    let response = app.post_data_erasure(&token).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let n_tokens = sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM subscription_tokens"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(n_tokens, 0);
+}
@@ -0,0 +1,560 @@
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use z2p::subscriber_links::LinkPurpose;
+
+use crate::helpers::{
+    accept_batch, assert_is_redirect_to, create_confirmed_subscriber,
+    create_unconfirmed_subscriber_with_email, spawn_app, TestApp,
+};
+
+async fn preferences_token(app: &TestApp) -> String {
+    let subscriber =
+        sqlx::query!("SELECT id FROM subscriptions WHERE email = 'ursula_le_guin@gmail.com'")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    app.subscriber_links
+        .sign(LinkPurpose::Preferences, subscriber.id)
+}
+
+fn last_email(requests: &[wiremock::Request]) -> serde_json::Value {
+    serde_json::from_slice(&requests.last().unwrap().body).unwrap()
+}
+
+async fn publish_newsletter(app: &TestApp, title: &str) {
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": title,
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            },
+            "idempotency_key": Uuid::new_v4().to_string()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+}
+
+#[tokio::test]
+async fn every_issue_links_to_the_preferences_page() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    publish_newsletter(&app, "Newsletter title").await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let message = &app.sent_batch_messages().await[0];
+    let token = preferences_token(&app).await;
+    for body in ["HtmlBody", "TextBody"] {
+        assert!(message[body]
+            .as_str()
+            .unwrap()
+            .contains(&format!("/subscriptions/preferences?token={}", token)));
+    }
+    let html_page = app.get_preferences_html(&token).await;
+    assert!(html_page.contains("ursula_le_guin@gmail.com"));
+    assert!(html_page.contains(r#"value="le guin""#));
+}
+
+#[tokio::test]
+async fn the_preferences_page_needs_a_valid_token() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    let unsubscribe_token = app
+        .subscriber_links
+        .sign(LinkPurpose::Unsubscribe, subscriber.id);
+
+    for token in ["not-a-token", unsubscribe_token.as_str()] {
+        // Act
+        let response = app.get_preferences(token).await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 401);
+    }
+}
+
+#[tokio::test]
+async fn subscribers_can_update_their_preferences() {
+    // Arrange
+    let app = spawn_app().await;
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    }))
+    .await;
+    app.post_create_list("rust").await;
+    app.post_create_list("go").await;
+    create_confirmed_subscriber(&app).await;
+    let token = preferences_token(&app).await;
+
+    // Act
+    let response = app
+        .post_preferences(&[
+            ("token", token.as_str()),
+            ("name", "Ursula"),
+            ("lists", "rust"),
+            ("lists", "go"),
+            ("digest_frequency", "weekly"),
+        ])
+        .await;
+
+    // Assert
+    assert_is_redirect_to(
+        &response,
+        &format!("/subscriptions/preferences?token={}", token),
+    );
+    let html_page = app.get_preferences_html(&token).await;
+    assert!(html_page.contains("Your preferences have been saved."));
+    assert!(html_page.contains(r#"value="Ursula""#));
+    assert!(html_page.contains(r#"value="rust" checked"#));
+    assert!(html_page.contains(r#"value="weekly" checked"#));
+    let saved = sqlx::query!(
+        r#"SELECT name, digest_frequency, last_digest_at IS NOT NULL as "has_last_digest!"
+        FROM subscriptions"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(saved.name, "Ursula");
+    assert_eq!(saved.digest_frequency, "weekly");
+    assert!(saved.has_last_digest);
+}
+
+#[tokio::test]
+async fn invalid_preferences_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = preferences_token(&app).await;
+    let test_cases = [
+        ("", "every_issue", "is not a valid subscriber name."),
+        ("Ursula", "daily", "daily is not a valid digest frequency."),
+    ];
+
+    for (name, digest_frequency, error_message) in test_cases {
+        // Act
+        app.post_preferences(&[
+            ("token", token.as_str()),
+            ("name", name),
+            ("digest_frequency", digest_frequency),
+        ])
+        .await;
+
+        // Assert
+        let html_page = app.get_preferences_html(&token).await;
+        assert!(html_page.contains(error_message));
+        assert!(html_page.contains(r#"value="le guin""#));
+    }
+}
+
+#[tokio::test]
+async fn a_new_address_is_only_used_once_confirmed() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = preferences_token(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Ask for the change
+    let response = app.post_change_email(&token, "ursula@example.com").await;
+    assert_is_redirect_to(
+        &response,
+        &format!("/subscriptions/preferences?token={}", token),
+    );
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+
+    // Act - Part 2 - Follow the link sent to the new address
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], "ursula@example.com");
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula@example.com");
+    assert_eq!(saved.status, "confirmed");
+    // The previous address is told about the change
+    let notice = last_email(&app.email_server.received_requests().await.unwrap());
+    assert_eq!(notice["To"], "ursula_le_guin@gmail.com");
+    assert!(notice["HtmlBody"]
+        .as_str()
+        .unwrap()
+        .contains("ursula@example.com"));
+}
+
+#[tokio::test]
+async fn a_new_address_is_confirmed_through_the_outbox_if_the_email_provider_is_down() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = preferences_token(&app).await;
+    {
+        let _mock_guard = Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount_as_scoped(&app.email_server)
+            .await;
+
+        // Act - Part 1 - Ask for the change while the provider is down
+        app.post_change_email(&token, "ursula@example.com").await;
+    }
+    let html_page = app.get_preferences_html(&token).await;
+    assert!(html_page.contains("We have sent a confirmation link to ursula@example.com."));
+
+    // Act - Part 2 - The dispatcher retries once the provider is back
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    sqlx::query!("UPDATE confirmation_email_outbox SET execute_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_confirmation_outbox().await;
+
+    // Assert
+    let confirmation = last_email(&app.email_server.received_requests().await.unwrap());
+    assert_eq!(confirmation["To"], "ursula@example.com");
+    assert_eq!(confirmation["Subject"], "Confirm your new address");
+}
+
+#[tokio::test]
+async fn an_address_that_is_already_subscribed_cannot_be_taken_over() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    create_unconfirmed_subscriber_with_email(&app, "ursula%40example.com").await;
+    let token = preferences_token(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_change_email(&token, "ursula@example.com").await;
+
+    // Assert - The page does not tell whether the address is subscribed
+    assert_is_redirect_to(
+        &response,
+        &format!("/subscriptions/preferences?token={}", token),
+    );
+    let html_page = app.get_preferences_html(&token).await;
+    assert!(html_page.contains("We have sent a confirmation link to ursula@example.com."));
+    // The owner of the address gets a notice, with nothing to confirm
+    let notice = last_email(&app.email_server.received_requests().await.unwrap());
+    assert_eq!(notice["To"], "ursula@example.com");
+    assert!(!notice["HtmlBody"]
+        .as_str()
+        .unwrap()
+        .contains("/subscriptions/confirm"));
+    let n_requests = sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM email_change_requests"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(n_requests, 0);
+}
+
+#[tokio::test]
+async fn an_address_subscribed_before_the_change_is_confirmed_cannot_be_taken_over() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = preferences_token(&app).await;
+    let confirmation_links = {
+        let _mock_guard = Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount_as_scoped(&app.email_server)
+            .await;
+        app.post_change_email(&token, "ursula@example.com").await;
+        let email_request = app
+            .email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        app.get_confirmation_links(&email_request)
+    };
+    create_unconfirmed_subscriber_with_email(&app, "ursula%40example.com").await;
+
+    // Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 409);
+    let saved =
+        sqlx::query!("SELECT email FROM subscriptions WHERE email = 'ursula_le_guin@gmail.com'")
+            .fetch_optional(&app.db_pool)
+            .await
+            .unwrap();
+    assert!(saved.is_some());
+}
+
+#[tokio::test]
+async fn only_the_latest_change_of_address_can_be_confirmed() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = preferences_token(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_change_email(&token, "ursula@example.com").await;
+    let first_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.post_change_email(&token, "le_guin@example.com").await;
+    let second_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+
+    // Act
+    let first_response = reqwest::get(app.get_confirmation_links(&first_request).html)
+        .await
+        .unwrap();
+    let second_response = reqwest::get(app.get_confirmation_links(&second_request).html)
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 401);
+    assert_eq!(second_response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "le_guin@example.com");
+}
+
+#[tokio::test]
+async fn subscribers_can_switch_to_digests() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = preferences_token(&app).await;
+    app.post_preferences(&[
+        ("token", token.as_str()),
+        ("name", "le guin"),
+        ("digest_frequency", "weekly"),
+    ])
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Issues are not delivered one by one
+    publish_newsletter(&app, "First issue").await;
+    publish_newsletter(&app, "Second issue").await;
+    let n_tasks = sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(n_tasks, 0);
+
+    // Act - Part 2 - Nothing is sent before a week has passed
+    app.send_due_digests().await;
+    assert!(app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .all(
+            |r| !serde_json::from_slice::<serde_json::Value>(&r.body).unwrap()["Subject"]
+                .as_str()
+                .unwrap_or_default()
+                .contains("digest")
+        ));
+
+    // Act - Part 3 - A week later
+    sqlx::query!("UPDATE subscriptions SET last_digest_at = last_digest_at - interval '8 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE newsletter_issues SET published_at = published_at - interval '1 day'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.send_due_digests().await;
+    // Nothing new to send right after a digest
+    app.send_due_digests().await;
+
+    // Assert
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "Your weekly digest");
+    let text_body = body["TextBody"].as_str().unwrap();
+    assert!(text_body.find("First issue").unwrap() < text_body.find("Second issue").unwrap());
+    assert!(text_body.contains("/subscriptions/unsubscribe?token="));
+    assert!(text_body.contains("/subscriptions/preferences?token="));
+}
+
+#[tokio::test]
+async fn digests_that_fail_are_retried_later() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = preferences_token(&app).await;
+    app.post_preferences(&[
+        ("token", token.as_str()),
+        ("name", "le guin"),
+        ("digest_frequency", "weekly"),
+    ])
+    .await;
+    publish_newsletter(&app, "First issue").await;
+    sqlx::query!("UPDATE subscriptions SET last_digest_at = last_digest_at - interval '8 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE newsletter_issues SET published_at = published_at - interval '1 day'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.send_due_digests().await;
+
+    // Assert - The digest is not tried again straight away
+    let saved = sqlx::query!(
+        r#"SELECT digest_n_retries, digest_execute_after > now() as "backing_off!"
+        FROM subscriptions"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(saved.digest_n_retries, 1);
+    assert!(saved.backing_off);
+    // Mock asserts on drop
+}
+
+#[tokio::test]
+async fn rate_limited_digests_are_retried_without_counting_an_attempt() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.issue_delivery.max_attempts = 1;
+    create_confirmed_subscriber(&app).await;
+    let token = preferences_token(&app).await;
+    app.post_preferences(&[
+        ("token", token.as_str()),
+        ("name", "le guin"),
+        ("digest_frequency", "weekly"),
+    ])
+    .await;
+    publish_newsletter(&app, "First issue").await;
+    sqlx::query!("UPDATE subscriptions SET last_digest_at = last_digest_at - interval '8 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE newsletter_issues SET published_at = published_at - interval '1 day'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "60"))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.send_due_digests().await;
+
+    // Assert - The digest is still due, once the provider lets us send again
+    let saved = sqlx::query!(
+        r#"SELECT
+            digest_n_retries,
+            digest_execute_after > now() + interval '30 seconds' as "after_retry_after!",
+            last_digest_at < now() - interval '7 days' as "still_due!"
+        FROM subscriptions"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(saved.digest_n_retries, 0);
+    assert!(saved.after_retry_after);
+    assert!(saved.still_due);
+    // Mock asserts on drop
+}
+
+#[tokio::test]
+async fn unsubscribed_readers_have_no_preferences_to_manage() {
+    // Arrange
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber_with_email(&app, "ursula_le_guin%40gmail.com").await;
+    let token = preferences_token(&app).await;
+
+    // Act
+    let html_page = app.get_preferences_html(&token).await;
+
+    // Assert
+    assert!(html_page.contains("You are not subscribed to our newsletter."));
+}
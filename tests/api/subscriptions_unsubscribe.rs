@@ -0,0 +1,233 @@
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+
+use crate::helpers::{
+    accept_batch, batch_messages, create_confirmed_subscriber, spawn_app, ConfirmationLinks,
+    TestApp,
+};
+
+// Publish an issue to the confirmed subscriber and return the message sent to the email API
+async fn receive_newsletter_message(app: &TestApp) -> serde_json::Value {
+    let _mock_guard = Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            },
+            "idempotency_key": Uuid::new_v4().to_string()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    batch_messages(&email_request).pop().unwrap()
+}
+
+// Publish an issue to the confirmed subscriber and return the links it carried
+async fn receive_newsletter(app: &TestApp) -> ConfirmationLinks {
+    let message = receive_newsletter_message(app).await;
+    app.get_unsubscribe_links(&message)
+}
+
+// Extract the value of a custom header from a message sent to the email API
+fn email_header(message: &serde_json::Value, name: &str) -> String {
+    message["Headers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|h| h["Name"] == name)
+        .map(|h| h["Value"].as_str().unwrap().to_owned())
+        .unwrap()
+}
+
+fn token(link: &reqwest::Url) -> String {
+    link.query_pairs()
+        .find(|(k, _)| k == "token")
+        .map(|(_, v)| v.into_owned())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn newsletters_carry_a_personalised_unsubscribe_link() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let links = receive_newsletter(&app).await;
+
+    // Assert
+    assert_eq!(links.html, links.plain_text);
+    let response = reqwest::get(links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.text().await.unwrap().contains("Unsubscribe"));
+}
+
+#[tokio::test]
+async fn following_the_unsubscribe_link_does_not_unsubscribe_on_its_own() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let links = receive_newsletter(&app).await;
+
+    // Act
+    reqwest::get(links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn submitting_the_unsubscribe_form_unsubscribes_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let links = receive_newsletter(&app).await;
+
+    // Act
+    let response = app.post_unsubscribe(&token(&links.html)).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+}
+
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_unsubscribed_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let links = receive_newsletter(&app).await;
+    app.post_unsubscribe(&token(&links.html))
+        .await
+        .error_for_status()
+        .unwrap();
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(accept_batch)
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            },
+            "idempotency_key": Uuid::new_v4().to_string()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that we haven't sent the newsletter email
+}
+
+#[tokio::test]
+async fn invalid_unsubscribe_tokens_are_rejected_with_a_401() {
+    // Arrange
+    let app = spawn_app().await;
+    let forged_token = format!("{}.{}", Uuid::new_v4(), "00".repeat(32));
+
+    // Act
+    let get_response = reqwest::get(format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        app.address, forged_token
+    ))
+    .await
+    .unwrap();
+    let post_response = app.post_unsubscribe(&forged_token).await;
+
+    // Assert
+    assert_eq!(get_response.status().as_u16(), 401);
+    assert_eq!(post_response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn newsletters_carry_one_click_list_unsubscribe_headers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let message = receive_newsletter_message(&app).await;
+
+    // Assert
+    assert_eq!(
+        email_header(&message, "List-Unsubscribe-Post"),
+        "List-Unsubscribe=One-Click"
+    );
+    let list_unsubscribe = email_header(&message, "List-Unsubscribe");
+    let targets: Vec<_> = list_unsubscribe
+        .split(", ")
+        .map(|t| t.trim_start_matches('<').trim_end_matches('>'))
+        .collect();
+    assert_eq!(targets.len(), 2);
+    assert_eq!(
+        targets[0],
+        "mailto:unsubscribe@gmail.com?subject=unsubscribe"
+    );
+    assert!(targets[1].starts_with("http://127.0.0.1/subscriptions/unsubscribe/one-click?token="));
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_unsubscribes_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let message = receive_newsletter_message(&app).await;
+    let list_unsubscribe = email_header(&message, "List-Unsubscribe");
+    let one_click_url = list_unsubscribe
+        .split(", ")
+        .nth(1)
+        .unwrap()
+        .trim_start_matches('<')
+        .trim_end_matches('>');
+    let mut one_click_url = reqwest::Url::parse(one_click_url).unwrap();
+    one_click_url.set_port(Some(app.port)).unwrap();
+
+    // Act - What a mail client does when the user clicks its unsubscribe button
+    let response = reqwest::Client::new()
+        .post(one_click_url)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+}